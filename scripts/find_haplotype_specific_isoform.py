@@ -17,6 +17,8 @@ def read_haplotype_file(haplotype_file):
     read_haplotype = {}
     with open(haplotype_file, "r") as f:
         for line in f:
+            if line.startswith("#"):
+                continue
             line = line.strip().split("\t")
             read_haplotype[line[0]] = line[1]
     return read_haplotype
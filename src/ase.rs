@@ -0,0 +1,413 @@
+use std::collections::{HashMap, VecDeque};
+
+use mathru::special::gamma::Gamma;
+use rust_lapper::Interval;
+
+use crate::AseModel;
+use crate::exon::Exon;
+use crate::phase_link::PhaseSetLink;
+use crate::snpfrags::SNPFrag;
+
+#[derive(Debug, Clone, Default)]
+pub struct AseUnit {
+    pub gene_id: String,
+    // "." without annotation, the unit is the whole phase set
+    pub chromosome: String,
+    pub phase_set: u32,
+    pub num_snps: u32,
+    // phased heterozygous SNPs of the gene in the phase set
+    pub hap1_reads: u32,
+    pub hap2_reads: u32,
+    // reads assigned to each haplotype, used for the test
+    pub hap1_allele_cnt: u32,
+    pub hap2_allele_cnt: u32,
+    // haplotype expression summed over the phased SNPs
+    pub hap1_dominant_snps: u32,
+    pub hap2_dominant_snps: u32,
+    // phased SNPs where the other haplotype has at most the monoallelic fraction of the haplotype expression
+    pub pvalue: f64,
+    pub qvalue: f64,
+}
+
+impl AseUnit {
+    pub fn ratio(&self) -> f64 {
+        // hap1 / hap2 read ratio, with a pseudocount of 0.5
+        return (self.hap1_reads as f64 + 0.5) / (self.hap2_reads as f64 + 0.5);
+    }
+}
+
+fn ln_gamma(x: f64) -> f64 {
+    return Gamma::ln_gamma(x);
+}
+
+pub(crate) fn ln_choose(n: u32, k: u32) -> f64 {
+    return ln_gamma(n as f64 + 1.0) - ln_gamma(k as f64 + 1.0) - ln_gamma((n - k) as f64 + 1.0);
+}
+
+pub fn binomial_test(k: u32, n: u32) -> f64 {
+    // two-sided exact binomial test of k successes in n trials against p = 0.5,
+    // summing the probabilities of all outcomes no more likely than k
+    if n == 0 {
+        return 1.0;
+    }
+    let log_half = 0.5_f64.ln() * n as f64;
+    let log_pk = ln_choose(n, k) + log_half;
+    let mut p = 0.0;
+    for i in 0..=n {
+        let log_pi = ln_choose(n, i) + log_half;
+        if log_pi <= log_pk + 1e-7 {
+            p += log_pi.exp();
+        }
+    }
+    return p.min(1.0);
+}
+
+const MIN_DISPERSION: f64 = 1e-6;
+
+fn ln_beta(x: f64, y: f64) -> f64 {
+    return ln_gamma(x) + ln_gamma(y) - ln_gamma(x + y);
+}
+
+fn beta_binomial_ln_pmf(k: u32, n: u32, a: f64) -> f64 {
+    // beta-binomial with mean 0.5, alpha = beta = a
+    return ln_choose(n, k) + ln_beta(k as f64 + a, (n - k) as f64 + a) - ln_beta(a, a);
+}
+
+fn dispersion_shape(rho: f64) -> f64 {
+    // alpha = beta of the beta-binomial with mean 0.5 and overdispersion rho = 1 / (alpha + beta + 1),
+    // rho is bounded below by MIN_DISPERSION to keep alpha finite
+    let rho = rho.max(MIN_DISPERSION);
+    return (1.0 - rho) / (2.0 * rho);
+}
+
+pub fn beta_binomial_test(k: u32, n: u32, rho: f64) -> f64 {
+    // two-sided test of k successes in n trials against the beta-binomial with mean 0.5 and overdispersion rho
+    if n == 0 {
+        return 1.0;
+    }
+    let a = dispersion_shape(rho);
+    let log_pk = beta_binomial_ln_pmf(k, n, a);
+    let mut p = 0.0;
+    for i in 0..=n {
+        let log_pi = beta_binomial_ln_pmf(i, n, a);
+        if log_pi <= log_pk + 1e-7 {
+            p += log_pi.exp();
+        }
+    }
+    return p.min(1.0);
+}
+
+pub fn estimate_dispersion(snp_counts: &Vec<[u32; 2]>) -> f64 {
+    // maximum likelihood overdispersion of the haplotype counts of the phased SNPs, assuming most SNPs are not imbalanced.
+    // Golden-section search on log10(rho) in [MIN_DISPERSION, 0.5], MIN_DISPERSION (nearly binomial) without SNPs
+    if snp_counts.len() == 0 {
+        return MIN_DISPERSION;
+    }
+    let loglik = |log_rho: f64| -> f64 {
+        let a = dispersion_shape(10.0_f64.powf(log_rho));
+        return snp_counts.iter().map(|c| beta_binomial_ln_pmf(c[0], c[0] + c[1], a)).sum();
+    };
+    let golden = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let (mut lo, mut hi) = (MIN_DISPERSION.log10(), 0.5_f64.log10());
+    let mut x1 = hi - golden * (hi - lo);
+    let mut x2 = lo + golden * (hi - lo);
+    let (mut f1, mut f2) = (loglik(x1), loglik(x2));
+    for _ in 0..50 {
+        if f1 > f2 {
+            hi = x2;
+            x2 = x1;
+            f2 = f1;
+            x1 = hi - golden * (hi - lo);
+            f1 = loglik(x1);
+        } else {
+            lo = x1;
+            x1 = x2;
+            f1 = f2;
+            x2 = lo + golden * (hi - lo);
+            f2 = loglik(x2);
+        }
+    }
+    return 10.0_f64.powf((lo + hi) / 2.0);
+}
+
+pub fn benjamini_hochberg(pvalues: &Vec<f64>) -> Vec<f64> {
+    let n = pvalues.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|a, b| pvalues[*b].partial_cmp(&pvalues[*a]).unwrap());
+    let mut qvalues = vec![1.0; n];
+    let mut min_q: f64 = 1.0;
+    for (rank, i) in order.iter().enumerate() {
+        // rank 0 is the largest p-value, whose BH rank is n
+        let q = pvalues[*i] * n as f64 / (n - rank) as f64;
+        min_q = min_q.min(q);
+        qvalues[*i] = min_q;
+    }
+    return qvalues;
+}
+
+fn genes_of(gene_exons: &HashMap<String, Vec<Interval<usize, u8>>>, start: i64, end: i64) -> Vec<String> {
+    // genes whose exons overlap [start, end), 0-based. Without annotation every position belongs to gene "."
+    if gene_exons.len() == 0 {
+        return vec![".".to_string()];
+    }
+    let mut genes: Vec<String> = Vec::new();
+    for (gene_id, exons) in gene_exons.iter() {
+        // exon intervals are 1-based, stop exclusive
+        if exons.iter().any(|iv| (iv.start as i64) < end + 1 && start + 1 < iv.stop as i64) {
+            genes.push(gene_id.clone());
+        }
+    }
+    return genes;
+}
+
+pub(crate) fn gene_of_read(gene_exons: &HashMap<String, Vec<Interval<usize, u8>>>, exons: &Vec<Exon>) -> String {
+    // gene whose exons overlap the exons of the read, reads overlapping the exons of several genes are ambiguous (".")
+    let mut genes: Vec<String> = Vec::new();
+    for e in exons.iter() {
+        for g in genes_of(gene_exons, e.start, e.end).into_iter() {
+            if !genes.contains(&g) {
+                genes.push(g);
+            }
+        }
+    }
+    return if genes.len() == 1 { genes[0].clone() } else { ".".to_string() };
+}
+
+impl SNPFrag {
+    pub fn collect_ase_units(
+        &self,
+        gene_exons: &HashMap<String, Vec<Interval<usize, u8>>>,
+        read_phase_sets: &HashMap<String, u32>,
+        max_monoallelic_frac: f32,
+    ) -> Vec<AseUnit> {
+        // haplotype expression and haplotagged reads of each gene and phase set of the region
+        let mut units: HashMap<(String, u32), AseUnit> = HashMap::new();
+        for snp in self.candidate_snps.iter() {
+            if snp.variant_type != 1 || snp.phase_set == 0 {
+                continue;
+            }
+            for gene_id in genes_of(gene_exons, snp.pos, snp.pos + 1).into_iter() {
+                let unit = units.entry((gene_id.clone(), snp.phase_set)).or_insert(AseUnit {
+                    gene_id: gene_id,
+                    chromosome: self.region.chr.clone(),
+                    phase_set: snp.phase_set,
+                    ..Default::default()
+                });
+                unit.num_snps += 1;
+                let hap1 = snp.haplotype_expression[0] + snp.haplotype_expression[1];
+                let hap2 = snp.haplotype_expression[2] + snp.haplotype_expression[3];
+                unit.hap1_allele_cnt += hap1;
+                unit.hap2_allele_cnt += hap2;
+                if hap1 + hap2 > 0 {
+                    if hap2 as f32 <= max_monoallelic_frac * (hap1 + hap2) as f32 {
+                        unit.hap1_dominant_snps += 1;
+                    } else if hap1 as f32 <= max_monoallelic_frac * (hap1 + hap2) as f32 {
+                        unit.hap2_dominant_snps += 1;
+                    }
+                }
+            }
+        }
+        for frag in self.fragments.iter() {
+            if frag.assignment != 1 && frag.assignment != 2 {
+                continue;
+            }
+            let ps = match read_phase_sets.get(&frag.read_id) {
+                Some(v) => *v,
+                None => continue,
+            };
+            let mut genes: Vec<String> = Vec::new();
+            for e in frag.exons.iter() {
+                for gene_id in genes_of(gene_exons, e.start, e.end).into_iter() {
+                    if !genes.contains(&gene_id) {
+                        genes.push(gene_id);
+                    }
+                }
+            }
+            for gene_id in genes.into_iter() {
+                if let Some(unit) = units.get_mut(&(gene_id, ps)) {
+                    if frag.assignment == 1 {
+                        unit.hap1_reads += 1;
+                    } else {
+                        unit.hap2_reads += 1;
+                    }
+                }
+            }
+        }
+        return units.into_values().collect();
+    }
+
+    pub fn collect_ase_snp_counts(&self) -> Vec<[u32; 2]> {
+        // hap1 and hap2 expression of the phased heterozygous SNPs covered by at least 10 haplotagged reads, for estimating the dispersion
+        let mut snp_counts: Vec<[u32; 2]> = Vec::new();
+        for snp in self.candidate_snps.iter() {
+            if snp.variant_type != 1 || snp.phase_set == 0 {
+                continue;
+            }
+            let hap1 = snp.haplotype_expression[0] + snp.haplotype_expression[1];
+            let hap2 = snp.haplotype_expression[2] + snp.haplotype_expression[3];
+            if hap1 + hap2 >= 10 {
+                snp_counts.push([hap1, hap2]);
+            }
+        }
+        return snp_counts;
+    }
+}
+
+pub fn relabel_ase_units(ase_units: &mut VecDeque<AseUnit>, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    for unit in ase_units.iter_mut() {
+        if let Some(link) = phase_set_links.get(&(unit.chromosome.clone(), unit.phase_set)) {
+            unit.phase_set = link.phase_set;
+            if link.flip {
+                std::mem::swap(&mut unit.hap1_reads, &mut unit.hap2_reads);
+                std::mem::swap(&mut unit.hap1_allele_cnt, &mut unit.hap2_allele_cnt);
+                std::mem::swap(&mut unit.hap1_dominant_snps, &mut unit.hap2_dominant_snps);
+            }
+        }
+    }
+}
+
+pub fn test_ase_units(ase_units: &VecDeque<AseUnit>, ase_model: &AseModel, dispersion: f64) -> Vec<AseUnit> {
+    // merge the units of the same gene and phase set collected by different regions, then test every unit for
+    // allelic imbalance of the haplotagged reads and correct for multiple testing.
+    // The beta-binomial model uses the dispersion estimated from the SNPs of the sample
+    let mut merged: Vec<AseUnit> = Vec::new();
+    let mut merged_idx: HashMap<(String, String, u32), usize> = HashMap::new();
+    for unit in ase_units.iter() {
+        let key = (unit.gene_id.clone(), unit.chromosome.clone(), unit.phase_set);
+        match merged_idx.get(&key) {
+            Some(i) => {
+                let m = &mut merged[*i];
+                m.num_snps += unit.num_snps;
+                m.hap1_reads += unit.hap1_reads;
+                m.hap2_reads += unit.hap2_reads;
+                m.hap1_allele_cnt += unit.hap1_allele_cnt;
+                m.hap2_allele_cnt += unit.hap2_allele_cnt;
+                m.hap1_dominant_snps += unit.hap1_dominant_snps;
+                m.hap2_dominant_snps += unit.hap2_dominant_snps;
+            }
+            None => {
+                merged_idx.insert(key, merged.len());
+                merged.push(unit.clone());
+            }
+        }
+    }
+    for unit in merged.iter_mut() {
+        unit.pvalue = match ase_model {
+            AseModel::binomial => binomial_test(unit.hap1_reads, unit.hap1_reads + unit.hap2_reads),
+            AseModel::betabinomial => beta_binomial_test(unit.hap1_reads, unit.hap1_reads + unit.hap2_reads, dispersion),
+        };
+    }
+    let qvalues = benjamini_hochberg(&merged.iter().map(|u| u.pvalue).collect());
+    for (unit, q) in merged.iter_mut().zip(qvalues.into_iter()) {
+        unit.qvalue = q;
+    }
+    return merged;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn binomial_test_known_values() {
+        assert!((binomial_test(3, 10) - 0.34375).abs() < 1e-9);
+        assert!((binomial_test(0, 5) - 0.0625).abs() < 1e-9);
+        assert!((binomial_test(1, 8) - 0.0703125).abs() < 1e-9);
+        assert!((binomial_test(5, 10) - 1.0).abs() < 1e-9);
+        assert_eq!(binomial_test(0, 0), 1.0);
+        assert!((binomial_test(7, 10) - binomial_test(3, 10)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn benjamini_hochberg_known_values() {
+        let qvalues = benjamini_hochberg(&vec![0.01, 0.04, 0.03, 0.5]);
+        let expected = [0.04, 0.04 * 4.0 / 3.0, 0.04 * 4.0 / 3.0, 0.5];
+        for (q, e) in qvalues.iter().zip(expected.iter()) {
+            assert!((q - e).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn benjamini_hochberg_is_monotone_and_capped() {
+        let pvalues = vec![0.9, 0.001, 0.2, 0.02, 0.02, 0.6, 0.99, 0.05];
+        let qvalues = benjamini_hochberg(&pvalues);
+        assert_eq!(qvalues.len(), pvalues.len());
+        for i in 0..pvalues.len() {
+            assert!(qvalues[i] >= pvalues[i] && qvalues[i] <= 1.0);
+            for j in 0..pvalues.len() {
+                // order of the p-values is preserved
+                if pvalues[i] < pvalues[j] {
+                    assert!(qvalues[i] <= qvalues[j]);
+                }
+            }
+        }
+        assert!(benjamini_hochberg(&vec![0.8, 0.9, 1.0]).iter().all(|q| *q <= 1.0));
+        assert_eq!(benjamini_hochberg(&Vec::new()).len(), 0);
+    }
+
+    #[test]
+    fn beta_binomial_test_near_zero_dispersion_is_binomial() {
+        for (k, n) in [(3, 10), (0, 5), (12, 30), (50, 100)] {
+            let p_binom = binomial_test(k, n);
+            let p_beta = beta_binomial_test(k, n, MIN_DISPERSION);
+            assert!((p_binom - p_beta).abs() < 1e-3, "k={} n={}: {} vs {}", k, n, p_binom, p_beta);
+        }
+        assert!((beta_binomial_test(3, 10, 0.0) - binomial_test(3, 10)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn beta_binomial_test_is_symmetric() {
+        for rho in [0.01, 0.1, 0.3] {
+            let p1 = beta_binomial_test(3, 20, rho);
+            let p2 = beta_binomial_test(17, 20, rho);
+            assert!((p1 - p2).abs() < 1e-9);
+            assert!(p1 > 0.0 && p1 <= 1.0);
+        }
+        // overdispersion makes the same imbalance less significant
+        assert!(beta_binomial_test(3, 20, 0.1) > binomial_test(3, 20));
+    }
+
+    #[test]
+    fn estimate_dispersion_without_snps() {
+        let rho = estimate_dispersion(&Vec::new());
+        assert_eq!(rho, MIN_DISPERSION);
+        let p = beta_binomial_test(5, 10, rho);
+        assert!(p.is_finite() && (p - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn estimate_dispersion_recovers_simulated_rho() {
+        // beta-binomial counts drawn with a Polya urn started with alpha = beta = a balls
+        let mut rng = StdRng::seed_from_u64(7);
+        for rho in [0.05, 0.2] {
+            let a = dispersion_shape(rho);
+            let mut snp_counts: Vec<[u32; 2]> = Vec::new();
+            for _ in 0..3000 {
+                let (mut w1, mut w2) = (a, a);
+                let mut c = [0, 0];
+                for _ in 0..40 {
+                    if rng.gen::<f64>() < w1 / (w1 + w2) {
+                        c[0] += 1;
+                        w1 += 1.0;
+                    } else {
+                        c[1] += 1;
+                        w2 += 1.0;
+                    }
+                }
+                snp_counts.push(c);
+            }
+            let estimated = estimate_dispersion(&snp_counts);
+            assert!((estimated - rho).abs() < 0.2 * rho, "rho={} estimated={}", rho, estimated);
+        }
+        // binomial counts have (nearly) no overdispersion
+        let snp_counts: Vec<[u32; 2]> = (0..3000)
+            .map(|_| {
+                let k = (0..40).filter(|_| rng.gen::<bool>()).count() as u32;
+                [k, 40 - k]
+            })
+            .collect();
+        assert!(estimate_dispersion(&snp_counts) < 0.01);
+    }
+}
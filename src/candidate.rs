@@ -5,7 +5,7 @@ use rust_lapper::{Interval, Lapper};
 use crate::Platform;
 use crate::snp::CandidateSNP;
 use crate::snpfrags::SNPFrag;
-use crate::util::Profile;
+use crate::util::{Profile, QualSummary};
 
 fn cmp_f64(a: &f64, b: &f64) -> Ordering {
     if a < b {
@@ -81,26 +81,20 @@ impl SNPFrag {
             }
             // filtering average distance to read end is significant different for allele1 and allele2
             // filtering average base quality is significant different for allele1 and allele2
-            let mut allele1_dists: Vec<i64> = Vec::new();
-            let mut allele2_dists: Vec<i64> = Vec::new();
-            let mut allele1_quals: Vec<u8> = Vec::new();
-            let mut allele2_quals: Vec<u8> = Vec::new();
+            let mut allele1_quals = QualSummary::default();
+            let mut allele2_quals = QualSummary::default();
             match allele1 {
                 'a' | 'A' => {
-                    allele1_dists = bf.distance_to_end.a.clone();
-                    allele1_quals = bf.baseq.a.clone();
+                    allele1_quals = bf.baseq.a;
                 }
                 'c' | 'C' => {
-                    allele1_dists = bf.distance_to_end.c.clone();
-                    allele1_quals = bf.baseq.c.clone();
+                    allele1_quals = bf.baseq.c;
                 }
                 'g' | 'G' => {
-                    allele1_dists = bf.distance_to_end.g.clone();
-                    allele1_quals = bf.baseq.g.clone();
+                    allele1_quals = bf.baseq.g;
                 }
                 't' | 'T' => {
-                    allele1_dists = bf.distance_to_end.t.clone();
-                    allele1_quals = bf.baseq.t.clone();
+                    allele1_quals = bf.baseq.t;
                 }
                 _ => {
                     println!("Error: unknown allele: {}", allele1);
@@ -108,20 +102,16 @@ impl SNPFrag {
             }
             match allele2 {
                 'a' | 'A' => {
-                    allele2_dists = bf.distance_to_end.a.clone();
-                    allele2_quals = bf.baseq.a.clone();
+                    allele2_quals = bf.baseq.a;
                 }
                 'c' | 'C' => {
-                    allele2_dists = bf.distance_to_end.c.clone();
-                    allele2_quals = bf.baseq.c.clone();
+                    allele2_quals = bf.baseq.c;
                 }
                 'g' | 'G' => {
-                    allele2_dists = bf.distance_to_end.g.clone();
-                    allele2_quals = bf.baseq.g.clone();
+                    allele2_quals = bf.baseq.g;
                 }
                 't' | 'T' => {
-                    allele2_dists = bf.distance_to_end.t.clone();
-                    allele2_quals = bf.baseq.t.clone();
+                    allele2_quals = bf.baseq.t;
                 }
                 _ => {
                     println!("Error: unknown allele: {}", allele2);
@@ -129,24 +119,12 @@ impl SNPFrag {
             }
             // filter if all alt alleles have low base quality
             if allele1 != bf.ref_base {
-                let mut allele1_bq_pass_cnt = 0;
-                for bq in allele1_quals.iter() {
-                    if *bq >= min_baseq {
-                        allele1_bq_pass_cnt += 1;
-                    }
-                }
-                if allele1_cnt > 0 && allele1_bq_pass_cnt < 2 {
+                if allele1_cnt > 0 && allele1_quals.pass_cnt < 2 {
                     position += 1;
                     continue;
                 }
             } else if allele2 != bf.ref_base {
-                let mut allele2_bq_pass_cnt = 0;
-                for bq in allele2_quals.iter() {
-                    if *bq >= min_baseq {
-                        allele2_bq_pass_cnt += 1;
-                    }
-                }
-                if allele2_cnt > 0 && allele2_bq_pass_cnt < 2 {
+                if allele2_cnt > 0 && allele2_quals.pass_cnt < 2 {
                     position += 1;
                     continue;
                 }
@@ -504,24 +482,20 @@ impl SNPFrag {
                 continue;
             }
 
-            for bq in identical_baseqs.iter() {
-                let bq = if *bq < 40 { *bq } else { 40 };
-                let error_rate = 0.1_f64.powf((bq as f64) / 10.0);
-                loglikelihood[0] += error_rate.log10();
-                loglikelihood[2] += (1.0 - error_rate).log10();
-            }
+            loglikelihood[0] += identical_baseqs.log_error;
+            loglikelihood[2] += identical_baseqs.log_correct;
 
-            for bq_vec in different_baseqs.iter() {
-                for bq in bq_vec.iter() {
-                    let bq = if *bq < 40 { *bq } else { 40 };
-                    let error_rate = 0.1_f64.powf((bq as f64) / 10.0);
-                    loglikelihood[0] += (1.0 - error_rate).log10();
-                    loglikelihood[2] += error_rate.log10();
-                }
+            for bq_sum in different_baseqs.iter() {
+                loglikelihood[0] += bq_sum.log_correct;
+                loglikelihood[2] += bq_sum.log_error;
             }
 
             let num_reads = bf.a + bf.c + bf.g + bf.t;
             loglikelihood[1] -= (num_reads as f64) * 2.0_f64.log10(); // example: logL(0) = -1, logL(1) = -6, logL(2) = -26
+            if self.ploidy == 1 {
+                // hemizygous, no heterozygous genotype
+                loglikelihood[1] = f64::NEG_INFINITY;
+            }
 
             // PL: phred-scaled likelihood
             // https://gatk.broadinstitute.org/hc/en-us/articles/360035890451-Calculation-of-PL-and-GQ-by-HaplotypeCaller-and-GenotypeGVCFs
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use crate::snpfrags::SNPFrag;
+
+struct DpRead {
+    frag_idx: usize,
+    entries: Vec<(usize, i32, f64)>,
+    // column, allele (1: ref, -1: alt), weight of a mismatch
+    first_col: usize,
+    last_col: usize,
+}
+
+fn mismatch_weight(prob: f64) -> f64 {
+    // log-likelihood ratio of a correct base versus an error, the cost of a mismatch in weighted MEC
+    let e = prob.max(1e-6).min(0.5);
+    return ((1.0 - e) / e).log10();
+}
+
+fn column_costs(num_reads: usize, elems: &Vec<(usize, i32, f64)>) -> (Vec<f64>, Vec<f64>) {
+    // weighted MEC cost of each bipartition of the active reads, for delta = 1 and delta = -1.
+    // bit b of a bipartition is set when the b-th active read is assigned to haplotype 1 (sigma = 1)
+    let mut base_pos = 0.0;
+    let mut base_neg = 0.0;
+    let mut flip_pos = vec![0.0; num_reads];
+    let mut flip_neg = vec![0.0; num_reads];
+    for (b, p, w) in elems.iter() {
+        // all reads start with sigma = -1, mismatch when sigma * delta != p
+        if -1 != *p {
+            base_pos += w;
+            flip_pos[*b] -= w;
+        } else {
+            flip_pos[*b] += w;
+        }
+        if 1 != *p {
+            base_neg += w;
+            flip_neg[*b] -= w;
+        } else {
+            flip_neg[*b] += w;
+        }
+    }
+    let n = 1_usize << num_reads;
+    let mut cost_pos = vec![0.0; n];
+    let mut cost_neg = vec![0.0; n];
+    cost_pos[0] = base_pos;
+    cost_neg[0] = base_neg;
+    for s in 1..n {
+        let b = s.trailing_zeros() as usize;
+        cost_pos[s] = cost_pos[s & (s - 1)] + flip_pos[b];
+        cost_neg[s] = cost_neg[s & (s - 1)] + flip_neg[b];
+    }
+    return (cost_pos, cost_neg);
+}
+
+fn project_state(state: usize, bits: &Vec<usize>) -> usize {
+    // bits[b] is the position of the b-th read in the shared read list, usize::MAX if not shared
+    let mut s = 0;
+    for b in 0..bits.len() {
+        if bits[b] != usize::MAX && (state >> b) & 1 == 1 {
+            s |= 1 << bits[b];
+        }
+    }
+    return s;
+}
+
+impl SNPFrag {
+    fn select_dp_reads(&self, col_of: &HashMap<usize, usize>, num_cols: usize, max_dp_coverage: usize) -> Vec<DpRead> {
+        // keep the most informative reads while the number of reads spanning each column stays below max_dp_coverage
+        let mut reads: Vec<DpRead> = Vec::new();
+        for k in 0..self.fragments.len() {
+            if self.fragments[k].haplotag == 0 {
+                continue;
+            }
+            let mut entries: Vec<(usize, i32, f64)> = Vec::new();
+            for fe in self.fragments[k].list.iter() {
+                if fe.phase_site == false || !col_of.contains_key(&fe.snp_idx) {
+                    continue;
+                }
+                entries.push((col_of[&fe.snp_idx], fe.p, mismatch_weight(fe.prob)));
+            }
+            if entries.len() == 0 {
+                continue;
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            let first_col = entries.first().unwrap().0;
+            let last_col = entries.last().unwrap().0;
+            reads.push(DpRead { frag_idx: k, entries: entries, first_col: first_col, last_col: last_col });
+        }
+        reads.sort_by(|a, b| b.entries.len().cmp(&a.entries.len()).then(a.frag_idx.cmp(&b.frag_idx)));
+        let mut coverage: Vec<usize> = vec![0; num_cols];
+        let mut selected: Vec<DpRead> = Vec::new();
+        for rd in reads.into_iter() {
+            if (rd.first_col..=rd.last_col).any(|j| coverage[j] >= max_dp_coverage) {
+                continue;
+            }
+            for j in rd.first_col..=rd.last_col {
+                coverage[j] += 1;
+            }
+            selected.push(rd);
+        }
+        selected.sort_by(|a, b| a.first_col.cmp(&b.first_col).then(a.frag_idx.cmp(&b.frag_idx)));
+        return selected;
+    }
+
+    pub fn phase_dp(&mut self, max_dp_coverage: usize) {
+        // Phase the heterozygous SNPs by dynamic programming over the SNP columns (weighted MEC, read-set bounded like WhatsHap).
+        // Each column keeps the cost of every bipartition of the reads spanning it, so the haplotypes are globally optimal
+        // for the selected reads and the result is deterministic. Reads are then assigned to the haplotypes and refined by cross_optimize.
+        let mut cols: Vec<usize> = self.high_frac_het_snps.clone();
+        cols.sort();
+        if cols.len() == 0 {
+            return;
+        }
+        let mut col_of: HashMap<usize, usize> = HashMap::new();
+        for (j, i) in cols.iter().enumerate() {
+            col_of.insert(*i, j);
+        }
+        let reads = self.select_dp_reads(&col_of, cols.len(), max_dp_coverage);
+
+        // active reads and alleles of each column
+        let mut active: Vec<Vec<usize>> = vec![Vec::new(); cols.len()];
+        let mut col_elems: Vec<Vec<(usize, i32, f64)>> = vec![Vec::new(); cols.len()];
+        for (r, rd) in reads.iter().enumerate() {
+            for j in rd.first_col..=rd.last_col {
+                active[j].push(r);
+            }
+            for (j, p, w) in rd.entries.iter() {
+                col_elems[*j].push((active[*j].len() - 1, *p, *w));
+            }
+        }
+
+        // forward pass, backtrace[j][s] is the best state of column j-1 given the shared reads in state s
+        let mut backtrace: Vec<Vec<usize>> = Vec::new();
+        let mut shared_bits: Vec<Vec<usize>> = Vec::new();
+        let mut prev_cost: Vec<f64> = vec![0.0];
+        let mut prev_active: Vec<usize> = Vec::new();
+        for j in 0..cols.len() {
+            let (cost_pos, cost_neg) = column_costs(active[j].len(), &col_elems[j]);
+            let mut cur_bits: Vec<usize> = vec![usize::MAX; active[j].len()];
+            let mut prev_bits: Vec<usize> = vec![usize::MAX; prev_active.len()];
+            let mut num_shared = 0;
+            for (b, r) in active[j].iter().enumerate() {
+                if let Some(pb) = prev_active.iter().position(|x| x == r) {
+                    cur_bits[b] = num_shared;
+                    prev_bits[pb] = num_shared;
+                    num_shared += 1;
+                }
+            }
+            let mut proj_min: Vec<f64> = vec![f64::INFINITY; 1 << num_shared];
+            let mut proj_arg: Vec<usize> = vec![0; 1 << num_shared];
+            for s in 0..prev_cost.len() {
+                let ps = project_state(s, &prev_bits);
+                if prev_cost[s] < proj_min[ps] {
+                    proj_min[ps] = prev_cost[s];
+                    proj_arg[ps] = s;
+                }
+            }
+            let mut cur_cost: Vec<f64> = vec![0.0; cost_pos.len()];
+            for s in 0..cur_cost.len() {
+                cur_cost[s] = cost_pos[s].min(cost_neg[s]) + proj_min[project_state(s, &cur_bits)];
+            }
+            backtrace.push(proj_arg);
+            shared_bits.push(cur_bits);
+            prev_cost = cur_cost;
+            prev_active = active[j].clone();
+        }
+
+        // backward pass
+        let mut state = 0;
+        for s in 1..prev_cost.len() {
+            if prev_cost[s] < prev_cost[state] {
+                state = s;
+            }
+        }
+        for j in (0..cols.len()).rev() {
+            let (cost_pos, cost_neg) = column_costs(active[j].len(), &col_elems[j]);
+            self.candidate_snps[cols[j]].haplotype = if cost_neg[state] < cost_pos[state] { -1 } else { 1 };
+            state = backtrace[j][project_state(state, &shared_bits[j])];
+        }
+
+        // assign every read to the haplotype it fits best
+        for k in 0..self.fragments.len() {
+            if self.fragments[k].haplotag == 0 {
+                continue;
+            }
+            let mut delta: Vec<i32> = Vec::new();
+            let mut ps: Vec<i32> = Vec::new();
+            let mut probs: Vec<f64> = Vec::new();
+            for fe in self.fragments[k].list.iter() {
+                if fe.phase_site == false {
+                    continue;
+                }
+                ps.push(fe.p);
+                probs.push(fe.prob);
+                delta.push(self.candidate_snps[fe.snp_idx].haplotype);
+            }
+            let q = SNPFrag::cal_sigma_delta_log(1, &delta, &ps, &probs);
+            let qn = SNPFrag::cal_sigma_delta_log(-1, &delta, &ps, &probs);
+            self.fragments[k].haplotag = if q < qn { -1 } else { 1 };
+        }
+        self.cross_optimize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snp::{CandidateSNP, FragElem, Fragment};
+
+    fn build_snpfrag(num_snps: usize, reads: &Vec<Vec<(usize, i32)>>) -> SNPFrag {
+        // reads are lists of (snp index, allele), every base has the same error rate
+        let mut snpfrag = SNPFrag::default();
+        for i in 0..num_snps {
+            let mut snp = CandidateSNP::default();
+            snp.pos = 100 * i as i64;
+            snp.haplotype = 1;
+            snpfrag.candidate_snps.push(snp);
+            snpfrag.high_frac_het_snps.push(i);
+        }
+        for (k, rd) in reads.iter().enumerate() {
+            let mut frag = Fragment::default();
+            frag.fragment_idx = k;
+            frag.read_id = format!("read{}", k);
+            frag.haplotag = 1;
+            for (i, p) in rd.iter() {
+                frag.list.push(FragElem { snp_idx: *i, pos: 100 * *i as i64, p: *p, prob: 0.05, phase_site: true, ..Default::default() });
+            }
+            snpfrag.fragments.push(frag);
+        }
+        return snpfrag;
+    }
+
+    fn mec(haplotype: &Vec<i32>, reads: &Vec<Vec<(usize, i32)>>) -> usize {
+        // minimum error correction, each read is assigned to the haplotype it conflicts with least
+        let mut cost = 0;
+        for rd in reads.iter() {
+            let mismatch = rd.iter().filter(|(i, p)| haplotype[*i] != *p).count();
+            cost += mismatch.min(rd.len() - mismatch);
+        }
+        return cost;
+    }
+
+    fn haplotype_of(snpfrag: &SNPFrag) -> Vec<i32> {
+        // haplotype with the first SNP on haplotype 1, to compare phasings up to a global flip
+        let flip = snpfrag.candidate_snps[0].haplotype;
+        return snpfrag.candidate_snps.iter().map(|snp| snp.haplotype * flip).collect();
+    }
+
+    fn truth_reads() -> Vec<Vec<(usize, i32)>> {
+        // true haplotype is [1, -1, -1, 1, 1], the last read carries a sequencing error at SNP 3
+        let hap = vec![1, -1, -1, 1, 1];
+        let spans: Vec<(usize, usize, i32)> = vec![(0, 2, 1), (1, 3, 1), (2, 4, 1), (0, 4, 1), (0, 1, -1), (1, 4, -1), (3, 4, -1), (0, 2, -1), (2, 4, 1)];
+        let mut reads: Vec<Vec<(usize, i32)>> = spans.iter().map(|(s, e, sigma)| (*s..=*e).map(|i| (i, hap[i] * sigma)).collect()).collect();
+        reads.last_mut().unwrap()[1].1 *= -1;
+        return reads;
+    }
+
+    #[test]
+    fn column_costs_match_brute_force() {
+        let elems: Vec<(usize, i32, f64)> = vec![(0, 1, 1.0), (1, -1, 2.0), (2, 1, 0.5), (3, -1, 3.0)];
+        let (cost_pos, cost_neg) = column_costs(4, &elems);
+        for s in 0..16 {
+            for (delta, costs) in [(1, &cost_pos), (-1, &cost_neg)] {
+                let mut expected = 0.0;
+                for (b, p, w) in elems.iter() {
+                    let sigma = if (s >> b) & 1 == 1 { 1 } else { -1 };
+                    if sigma * delta != *p {
+                        expected += w;
+                    }
+                }
+                assert!((costs[s] - expected).abs() < 1e-9, "state {} delta {}: {} != {}", s, delta, costs[s], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn phase_dp_finds_mec_optimal_phasing() {
+        let reads = truth_reads();
+        let mut snpfrag = build_snpfrag(5, &reads);
+        snpfrag.phase_dp(20);
+        let haplotype = haplotype_of(&snpfrag);
+        assert_eq!(haplotype, vec![1, -1, -1, 1, 1]);
+
+        // brute force over all haplotypes
+        let mut best = usize::MAX;
+        for h in 0..(1 << 5) {
+            let hap: Vec<i32> = (0..5).map(|i| if (h >> i) & 1 == 1 { -1 } else { 1 }).collect();
+            best = best.min(mec(&hap, &reads));
+        }
+        assert_eq!(mec(&haplotype, &reads), best);
+        for frag in snpfrag.fragments.iter() {
+            assert_ne!(frag.haplotag, 0);
+        }
+    }
+
+    #[test]
+    fn phase_dp_matches_existing_optimizer() {
+        let reads = truth_reads();
+        let mut dp = build_snpfrag(5, &reads);
+        dp.phase_dp(20);
+        let mut enumerated = build_snpfrag(5, &reads);
+        enumerated.phase(10, 0.0, 10);
+        assert_eq!(haplotype_of(&dp), haplotype_of(&enumerated));
+    }
+
+    #[test]
+    fn select_dp_reads_drops_reads_deterministically_at_cap() {
+        // read 3 spans columns 0-2, reads 1 and 2 span columns 0-1, read 0 covers column 0 only
+        let reads: Vec<Vec<(usize, i32)>> = vec![vec![(0, 1)], vec![(0, 1), (1, 1)], vec![(0, -1), (1, -1)], vec![(0, 1), (1, 1), (2, 1)]];
+        let snpfrag = build_snpfrag(3, &reads);
+        let col_of: HashMap<usize, usize> = (0..3).map(|i| (i, i)).collect();
+        let selected: Vec<usize> = snpfrag.select_dp_reads(&col_of, 3, 2).iter().map(|rd| rd.frag_idx).collect();
+        // the longest reads are kept first and ties are broken by fragment index
+        assert_eq!(selected, vec![1, 3]);
+        let all: Vec<usize> = snpfrag.select_dp_reads(&col_of, 3, 20).iter().map(|rd| rd.frag_idx).collect();
+        assert_eq!(all, vec![0, 1, 2, 3]);
+
+        // phasing at the cap only uses the kept reads and is repeatable
+        let mut capped = build_snpfrag(5, &truth_reads());
+        capped.phase_dp(2);
+        let mut again = build_snpfrag(5, &truth_reads());
+        again.phase_dp(2);
+        assert_eq!(haplotype_of(&capped), haplotype_of(&again));
+    }
+}
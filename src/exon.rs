@@ -1,4 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+
+use fishers_exact::fishers_exact;
+
+use crate::phase_link::PhaseSetLink;
+use crate::snpfrags::SNPFrag;
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct Exon {
@@ -99,4 +104,203 @@ pub fn exon_cluster(
         }
     }
     return clusters;
-}
\ No newline at end of file
+}
+#[derive(Debug, Clone, Default)]
+pub struct HaplotypeExon {
+    pub exon: Exon,
+    pub phase_set: u32,
+    // hap1 and hap2 refer to the haplotypes of this phase set
+    pub hap1_reads: u32,
+    pub hap2_reads: u32,
+    // reads of each haplotype containing the exon
+    pub hap1_total: u32,
+    pub hap2_total: u32,
+    // haplotagged reads of each haplotype in the phase set
+    pub pvalue: f64,
+    pub qvalue: f64,
+}
+
+impl HaplotypeExon {
+    pub fn usage(&self) -> (f64, f64) {
+        // fraction of the reads of each haplotype containing the exon
+        let hap1_usage = if self.hap1_total > 0 { self.hap1_reads as f64 / self.hap1_total as f64 } else { 0.0 };
+        let hap2_usage = if self.hap2_total > 0 { self.hap2_reads as f64 / self.hap2_total as f64 } else { 0.0 };
+        return (hap1_usage, hap2_usage);
+    }
+}
+
+pub fn haplotype_exons(hap1_exons: Vec<Exon>, hap2_exons: Vec<Exon>, hap1_total: u32, hap2_total: u32, phase_set: u32, min_sup: u32) -> Vec<HaplotypeExon> {
+    // Consensus exons of the haplotagged reads of one phase set with their usage on each haplotype. The reads containing the exon
+    // are compared with the other haplotagged reads of the phase set between haplotypes (Fisher's exact test), so the usage is
+    // normalised by the read count of each haplotype.
+    let hap1_start = hap1_exons.iter().map(|e| e.start).min().unwrap_or(0);
+    let hap1_end = hap1_exons.iter().map(|e| e.end).max().unwrap_or(0);
+    let hap2_start = hap2_exons.iter().map(|e| e.start).min().unwrap_or(0);
+    let hap2_end = hap2_exons.iter().map(|e| e.end).max().unwrap_or(0);
+    let hap1_consensus_exons = exon_cluster(hap1_exons, hap1_start, hap1_end, 0);
+    let hap2_consensus_exons = exon_cluster(hap2_exons, hap2_start, hap2_end, 0);
+    let mut combined_consensus_exons: HashMap<Exon, (Vec<Exon>, Vec<Exon>)> = HashMap::new();
+    for (e, v) in hap1_consensus_exons.iter() {
+        combined_consensus_exons.entry(e.clone()).or_insert((Vec::new(), Vec::new())).0.extend(v.iter().cloned());
+    }
+    for (e, v) in hap2_consensus_exons.iter() {
+        combined_consensus_exons.entry(e.clone()).or_insert((Vec::new(), Vec::new())).1.extend(v.iter().cloned());
+    }
+
+    // single exon reads rarely share exact boundaries, they are represented by the best supported exon within 20bp
+    let overlapped = |ex: &Exon, e: &Exon| ex.state == 3 && (ex.start - e.start).abs() <= 20 && (ex.end - e.end).abs() <= 20;
+    let mut single_exons: Vec<(&Exon, usize)> = combined_consensus_exons.iter().filter(|(e, _)| e.state == 3).map(|(e, v)| (e, v.0.len() + v.1.len())).collect();
+    single_exons.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.start.cmp(&b.0.start)).then(a.0.end.cmp(&b.0.end)));
+    let mut single_exon_representatives: Vec<Exon> = Vec::new();
+    for (e, _) in single_exons.iter() {
+        if !single_exon_representatives.iter().any(|r| overlapped(r, e)) {
+            single_exon_representatives.push((*e).clone());
+        }
+    }
+
+    let mut haplotype_exons: Vec<HaplotypeExon> = Vec::new();
+    for (e, members) in combined_consensus_exons.iter() {
+        let mut ec = e.clone();
+        let (mut hap1_reads, mut hap2_reads) = (members.0.len() as u32, members.1.len() as u32);
+        let all_members: Vec<&Exon> = members.0.iter().chain(members.1.iter()).collect();
+        if e.state == 0 {
+            // start of the start exon is the mean of its reads
+            ec.start = all_members.iter().map(|ex| ex.start).sum::<i64>() / all_members.len() as i64;
+        } else if e.state == 2 {
+            // end of the end exon is the mean of its reads
+            ec.end = all_members.iter().map(|ex| ex.end).sum::<i64>() / all_members.len() as i64;
+        } else if e.state == 3 {
+            if !single_exon_representatives.contains(e) {
+                continue;
+            }
+            hap1_reads = hap1_consensus_exons.iter().filter(|(ex, _)| overlapped(ex, e)).map(|(_, v)| v.len() as u32).sum();
+            hap2_reads = hap2_consensus_exons.iter().filter(|(ex, _)| overlapped(ex, e)).map(|(_, v)| v.len() as u32).sum();
+        }
+        if hap1_reads + hap2_reads < min_sup {
+            continue;
+        }
+        let table = [hap1_reads, hap2_reads, hap1_total.saturating_sub(hap1_reads), hap2_total.saturating_sub(hap2_reads)];
+        haplotype_exons.push(HaplotypeExon {
+            exon: ec,
+            phase_set: phase_set,
+            hap1_reads: hap1_reads,
+            hap2_reads: hap2_reads,
+            hap1_total: hap1_total,
+            hap2_total: hap2_total,
+            pvalue: fishers_exact(&table).unwrap().two_tail_pvalue.min(1.0),
+            qvalue: 1.0,
+        });
+    }
+    return haplotype_exons;
+}
+
+impl SNPFrag {
+    pub fn collect_haplotype_exons(&self, read_phase_sets: &HashMap<String, u32>, min_haplotype_reads: u32, min_sup: u32) -> Vec<HaplotypeExon> {
+        // exons of the haplotagged reads of each phase set of the region, hap1 and hap2 are only comparable within a phase set.
+        // Phase sets with fewer than min_haplotype_reads reads on either haplotype are skipped.
+        let mut phase_set_exons: HashMap<u32, (Vec<Exon>, Vec<Exon>, u32, u32)> = HashMap::new(); // hap1 exons, hap2 exons, hap1 reads, hap2 reads
+        for frag in self.fragments.iter() {
+            if frag.assignment != 1 && frag.assignment != 2 {
+                continue;
+            }
+            let ps = match read_phase_sets.get(&frag.read_id) {
+                Some(v) => *v,
+                None => continue,
+            };
+            let entry = phase_set_exons.entry(ps).or_insert((Vec::new(), Vec::new(), 0, 0));
+            if frag.assignment == 1 {
+                entry.0.extend(frag.exons.iter().cloned());
+                entry.2 += 1;
+            } else {
+                entry.1.extend(frag.exons.iter().cloned());
+                entry.3 += 1;
+            }
+        }
+        let mut phase_sets: Vec<u32> = phase_set_exons.keys().cloned().collect();
+        phase_sets.sort();
+        let mut exons: Vec<HaplotypeExon> = Vec::new();
+        for ps in phase_sets.iter() {
+            let (hap1_exons, hap2_exons, hap1_total, hap2_total) = phase_set_exons.remove(ps).unwrap();
+            if hap1_total < min_haplotype_reads || hap2_total < min_haplotype_reads {
+                continue;
+            }
+            exons.extend(haplotype_exons(hap1_exons, hap2_exons, hap1_total, hap2_total, *ps, min_sup));
+        }
+        return exons;
+    }
+}
+
+pub fn relabel_haplotype_exons(haplotype_exons: &mut VecDeque<HaplotypeExon>, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    for he in haplotype_exons.iter_mut() {
+        if let Some(link) = phase_set_links.get(&(he.exon.chr.clone(), he.phase_set)) {
+            he.phase_set = link.phase_set;
+            if link.flip {
+                std::mem::swap(&mut he.hap1_reads, &mut he.hap2_reads);
+                std::mem::swap(&mut he.hap1_total, &mut he.hap2_total);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snp::Fragment;
+
+    fn exon(start: i64, end: i64) -> Exon {
+        return Exon { chr: "chr1".to_string(), start: start, end: end, state: 1 };
+    }
+
+    fn fragment(read_id: &str, assignment: i32, exons: Vec<Exon>) -> Fragment {
+        return Fragment { read_id: read_id.to_string(), assignment: assignment, haplotag: if assignment == 1 { 1 } else { -1 }, exons: exons, ..Default::default() };
+    }
+
+    fn two_phase_sets() -> (SNPFrag, HashMap<String, u32>) {
+        // phase set 101: hap1 reads include exon 1000-1100, hap2 reads skip it.
+        // phase set 5001: 3 hap1 and 5 hap2 reads, all include exon 6000-6100.
+        let mut snpfrag = SNPFrag::default();
+        let mut read_phase_sets: HashMap<String, u32> = HashMap::new();
+        for i in 0..4 {
+            snpfrag.fragments.push(fragment(&format!("a{}", i), 1, vec![exon(500, 600), exon(1000, 1100)]));
+            snpfrag.fragments.push(fragment(&format!("b{}", i), 2, vec![exon(500, 600)]));
+            read_phase_sets.insert(format!("a{}", i), 101);
+            read_phase_sets.insert(format!("b{}", i), 101);
+        }
+        for i in 0..8 {
+            snpfrag.fragments.push(fragment(&format!("c{}", i), if i < 3 { 1 } else { 2 }, vec![exon(6000, 6100)]));
+            read_phase_sets.insert(format!("c{}", i), 5001);
+        }
+        snpfrag.fragments.push(fragment("unphased", 1, vec![exon(1000, 1100)]));
+        return (snpfrag, read_phase_sets);
+    }
+
+    #[test]
+    fn haplotype_exons_are_counted_per_phase_set() {
+        let (snpfrag, read_phase_sets) = two_phase_sets();
+        let mut exons = snpfrag.collect_haplotype_exons(&read_phase_sets, 1, 1);
+        exons.sort_by(|a, b| a.exon.start.cmp(&b.exon.start));
+        let summary: Vec<(i64, u32, u32, u32, u32, u32)> = exons.iter().map(|e| (e.exon.start, e.phase_set, e.hap1_reads, e.hap2_reads, e.hap1_total, e.hap2_total)).collect();
+        assert_eq!(summary, vec![(500, 101, 4, 4, 4, 4), (1000, 101, 4, 0, 4, 4), (6000, 5001, 3, 5, 3, 5)]);
+        // the exon used by one haplotype only is significant, the shared ones are not
+        assert!(exons[1].pvalue < 0.05);
+        assert_eq!(exons[0].pvalue, 1.0);
+        assert_eq!(exons[2].pvalue, 1.0);
+
+        // phase set 5001 has too few hap1 reads
+        let exons = snpfrag.collect_haplotype_exons(&read_phase_sets, 4, 1);
+        assert!(exons.iter().all(|e| e.phase_set == 101));
+    }
+
+    #[test]
+    fn relabel_haplotype_exons_follows_the_linked_phase_set() {
+        let (snpfrag, read_phase_sets) = two_phase_sets();
+        let mut exons: VecDeque<HaplotypeExon> = snpfrag.collect_haplotype_exons(&read_phase_sets, 1, 1).into();
+        let mut links: HashMap<(String, u32), PhaseSetLink> = HashMap::new();
+        links.insert(("chr1".to_string(), 5001), PhaseSetLink { phase_set: 101, flip: true });
+        relabel_haplotype_exons(&mut exons, &links);
+        let linked = exons.iter().find(|e| e.exon.start == 6000).unwrap();
+        assert_eq!((linked.phase_set, linked.hap1_reads, linked.hap2_reads, linked.hap1_total, linked.hap2_total), (101, 5, 3, 5, 3));
+        let kept = exons.iter().find(|e| e.exon.start == 1000).unwrap();
+        assert_eq!((kept.phase_set, kept.hap1_reads, kept.hap2_reads), (101, 4, 0));
+    }
+}
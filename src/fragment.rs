@@ -1,24 +1,19 @@
 use bio::bio_types::strand::ReqStrand::Forward;
-use rust_htslib::{bam, bam::Read, bam::record::Record};
+use rust_htslib::bam::record::Record;
 
 use crate::exon::Exon;
-use crate::snp::{FragElem, Fragment};
+use crate::indel::{left_align_deletion, left_align_insertion};
+use crate::snp::{FragElem, Fragment, IndelElem};
 use crate::snpfrags::SNPFrag;
 use crate::util::Region;
 
 impl SNPFrag {
-    pub fn get_fragments(&mut self, bam_path: &str, region: &Region) {
-        let mut bam_reader: bam::IndexedReader = bam::IndexedReader::from_path(bam_path).unwrap();
-        bam_reader.fetch((region.chr.as_str(), region.start, region.end)).unwrap();
-        let mut record = Record::new();
+    pub fn get_fragments(&mut self, records: &Vec<Record>, region: &Region, ref_seq: &Vec<u8>) {
         if self.candidate_snps.len() == 0 {
             return;
         }
         // assert!(self.min_linkers >= 0, "Error: min_linkers <= 0");
-        while let Some(result) = bam_reader.read(&mut record) {
-            if result.is_err() {
-                panic!("BAM parsing failed...");
-            }
+        for record in records.iter() {
             // TODO: filtering unmapped, secondary, supplementary reads?
             if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
                 continue;
@@ -56,6 +51,9 @@ impl SNPFrag {
             fragment.read_id = qname.clone();
             fragment.fragment_idx = self.fragments.len();
 
+            let mut read_indels: Vec<(usize, Vec<u8>, u32)> = Vec::new(); // left-aligned anchor, inserted sequence, deletion length
+            let mut read_alleles: Vec<(usize, i32)> = Vec::new(); // snp_idx and allele of all covered SNPs, dense SNPs included
+
             let mut exon_start = -1;
             let mut exon_end = -1;
             exon_start = pos_on_ref;
@@ -86,6 +84,9 @@ impl SNPFrag {
                                 if self.candidate_snps[frag_elem.snp_idx].for_phasing {
                                     frag_elem.phase_site = true;
                                 }
+                                if self.mnv_window > 0 && frag_elem.p != 0 {
+                                    read_alleles.push((idx, frag_elem.p));
+                                }
                                 // filtered SNP will not be used for haplotype phasing, ase snp will still be used for construct fragment.
                                 if self.candidate_snps[frag_elem.snp_idx].dense == false && frag_elem.p != 0 {
                                     fragment.list.push(frag_elem);
@@ -101,9 +102,18 @@ impl SNPFrag {
                         }
                     }
                     b'I' => {
+                        if self.candidate_indels.len() > 0 && pos_on_ref > 0 {
+                            let ins_seq: Vec<u8> = seq[pos_on_query as usize..pos_on_query as usize + cg.len() as usize].to_ascii_uppercase();
+                            let (anchor, ins_seq) = left_align_insertion(ref_seq, (pos_on_ref - 1) as usize, &ins_seq);
+                            read_indels.push((anchor, ins_seq, 0));
+                        }
                         pos_on_query += cg.len() as i64;
                     }
                     b'D' => {
+                        if self.candidate_indels.len() > 0 && pos_on_ref > 0 {
+                            let anchor = left_align_deletion(ref_seq, (pos_on_ref - 1) as usize, cg.len() as usize);
+                            read_indels.push((anchor, Vec::new(), cg.len()));
+                        }
                         for _ in 0..cg.len() {
                             if pos_on_ref == snp_pos {
                                 idx += 1;
@@ -176,6 +186,54 @@ impl SNPFrag {
             exon_start = -1;
             exon_end = -1;
 
+            // allele combinations of adjacent SNVs, used for merging SNVs into MNVs
+            for k in 1..read_alleles.len() {
+                let (i1, p1) = read_alleles[k - 1];
+                let (i2, p2) = read_alleles[k];
+                if i2 != i1 + 1 || self.candidate_snps[i2].pos - self.candidate_snps[i1].pos > self.mnv_window as i64 {
+                    continue;
+                }
+                let links = self.mnv_links.entry([i1, i2]).or_insert([0, 0, 0, 0]);
+                if p1 == 1 && p2 == 1 {
+                    links[0] += 1;
+                } else if p1 == 1 && p2 == -1 {
+                    links[1] += 1;
+                } else if p1 == -1 && p2 == 1 {
+                    links[2] += 1;
+                } else {
+                    links[3] += 1;
+                }
+            }
+
+            // alleles of candidate indels, the read is informative only if one exon covers the anchor base and the indel
+            let frag_start = if fragment.exons.len() > 0 { fragment.exons.first().unwrap().start } else { pos };
+            let first_indel = self.candidate_indels.partition_point(|x| x.pos < frag_start);
+            for ti in first_indel..self.candidate_indels.len() {
+                let indel = &self.candidate_indels[ti];
+                if indel.pos >= pos_on_ref {
+                    break;
+                }
+                let ref_len = indel.reference.len() as i64;
+                let alt_len = indel.alternative.len() as i64;
+                if !fragment.exons.iter().any(|e| e.start <= indel.pos && indel.pos + ref_len.max(2) <= e.end) {
+                    continue;
+                }
+                let mut p = 1;
+                for (anchor, ins_seq, del_len) in read_indels.iter() {
+                    if *anchor as i64 != indel.pos {
+                        continue;
+                    }
+                    if (alt_len > ref_len && ins_seq.as_slice() == &indel.alternative[1..]) || (ref_len > alt_len && *del_len as i64 == ref_len - 1) {
+                        p = -1;
+                    } else {
+                        p = 0; // other indel allele at the same site
+                    }
+                }
+                if p != 0 {
+                    fragment.indel_list.push(IndelElem { indel_idx: ti, p: p, prob: indel.error_rate });
+                }
+            }
+
             // hete snps >= 1
             let mut hete_links = 0;
             for fe in fragment.list.iter() {
@@ -193,7 +251,12 @@ impl SNPFrag {
                     // record each snp cover by which fragments
                     self.candidate_snps[fe.snp_idx].snp_cover_fragments.push(fragment.fragment_idx);
                 }
+                for ie in fragment.indel_list.iter() {
+                    self.candidate_indels[ie.indel_idx].indel_cover_fragments.push(fragment.fragment_idx);
+                }
                 self.fragments.push(fragment);
+            } else {
+                self.unlinked_reads.insert(qname, hete_links);
             }
         }
     }
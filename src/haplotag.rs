@@ -0,0 +1,505 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use bio::alphabets::dna;
+use bio::io::fastq;
+use rust_htslib::{bam, bam::Format, bam::Read, bam::record::Aux};
+
+use crate::util::Region;
+
+#[derive(Debug, Clone, Default)]
+pub struct ReadAssignment {
+    pub read_id: String,
+    pub haplotype: i32,
+    // 0: unassigned, 1: hap1, 2: hap2, up to the ploidy in polyploid regions
+    pub region: String,
+    pub ploidy: u32,
+    // number of haplotypes of the region
+    pub phase_set: u32,
+    // 0 if the read has no phase set
+    pub score: f64,
+    // assignment confidence, probability of the read belonging to the assigned haplotype
+    pub num_snps: u32,
+    // number of informative heterozygous SNPs on the read
+    pub reason: String,
+    // reason the read is left unassigned, "." for assigned reads
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BamChunk {
+    pub chr: String,
+    pub start: i64,
+    // start position on the reference, 0-based, inclusive
+    pub end: i64,
+    // end position on the reference, 0-based, exclusive
+    pub region_idx: Option<usize>,
+    // index of the isolated region writing this chunk, None for reads outside all regions
+}
+
+pub fn chunk_bam_path(tmp_dir: &str, chunk_idx: usize) -> String {
+    return format!("{}/{}.bam", tmp_dir, chunk_idx);
+}
+
+pub fn build_bam_chunks(isolated_regions: &Vec<Region>, header_view: &bam::HeaderView) -> (Vec<BamChunk>, HashMap<usize, usize>) {
+    // split every contig into non-overlapping chunks sorted by coordinate. Each read is written by the chunk containing its start position,
+    // so every read is written exactly once and concatenating the chunks keeps the coordinate order.
+    // Overlapping regions own the part not covered by the previous region.
+    let mut region_order: Vec<(u32, i64, usize)> = Vec::new(); // tid, 0-based start, region index
+    for (idx, reg) in isolated_regions.iter().enumerate() {
+        let tid = header_view.tid(reg.chr.as_bytes()).unwrap();
+        region_order.push((tid, reg.start as i64 - 1, idx));
+    }
+    region_order.sort();
+
+    let mut chunks: Vec<BamChunk> = Vec::new();
+    let mut region_chunks: HashMap<usize, usize> = HashMap::new(); // region index -> chunk index
+    let mut ri = 0;
+    for tid in 0..header_view.target_count() {
+        let chr = std::str::from_utf8(header_view.tid2name(tid)).unwrap().to_string();
+        let contig_len = header_view.target_len(tid).unwrap() as i64;
+        let mut cursor = 0;
+        while ri < region_order.len() && region_order[ri].0 == tid {
+            let (_, start, idx) = region_order[ri];
+            let end = isolated_regions[idx].end as i64 - 1;
+            if start > cursor {
+                chunks.push(BamChunk { chr: chr.clone(), start: cursor, end: start, region_idx: None });
+            }
+            let owned_start = start.max(cursor);
+            if end > owned_start {
+                region_chunks.insert(idx, chunks.len());
+                chunks.push(BamChunk { chr: chr.clone(), start: owned_start, end: end, region_idx: Some(idx) });
+                cursor = end;
+            }
+            ri += 1;
+        }
+        if cursor < contig_len {
+            chunks.push(BamChunk { chr: chr.clone(), start: cursor, end: contig_len, region_idx: None });
+        }
+    }
+    return (chunks, region_chunks);
+}
+
+fn push_haplotype_tags(record: &mut bam::Record, asg: &ReadAssignment, assignment_tags: bool) {
+    let _ = record.push_aux(b"HP:i", Aux::I32(asg.haplotype));
+    if asg.phase_set != 0 {
+        let _ = record.push_aux(b"PS:i", Aux::U32(asg.phase_set));
+    }
+    if assignment_tags {
+        let _ = record.push_aux(b"hc:f", Aux::Float(asg.score as f32));
+        let _ = record.push_aux(b"hn:i", Aux::U32(asg.num_snps));
+    }
+}
+
+fn remove_haplotype_tags(record: &mut bam::Record) {
+    let _ = record.remove_aux(b"HP");
+    let _ = record.remove_aux(b"PS");
+    let _ = record.remove_aux(b"hc");
+    let _ = record.remove_aux(b"hn");
+}
+
+pub fn write_region_bam(
+    out_path: &str,
+    header: &bam::Header,
+    records: &Vec<bam::Record>,
+    chunk: &BamChunk,
+    read_assignments: &HashMap<String, ReadAssignment>,
+    assignment_tags: bool,
+) {
+    // write the reads starting in the chunk into a temporary uncompressed bam, tagged with the assignments of the region.
+    let mut bam_writer = bam::Writer::from_path(out_path, header, Format::Bam).unwrap();
+    bam_writer.set_compression_level(bam::CompressionLevel::Uncompressed).unwrap();
+    for r in records.iter() {
+        if r.pos() < chunk.start || r.pos() >= chunk.end {
+            // written by another chunk
+            continue;
+        }
+        if r.is_unmapped() || r.is_secondary() {
+            bam_writer.write(r).unwrap();
+            continue;
+        }
+        let mut record = r.clone();
+        let qname = std::str::from_utf8(record.qname()).unwrap().to_string();
+        if let Some(asg) = read_assignments.get(&qname) {
+            if asg.haplotype != 0 {
+                push_haplotype_tags(&mut record, asg, assignment_tags);
+            }
+        }
+        bam_writer.write(&record).unwrap();
+    }
+}
+
+pub fn write_gap_bam(bam_file: &str, out_path: &str, header: &bam::Header, chunk: &BamChunk) {
+    // copy the reads starting outside all isolated regions
+    let mut bam_reader = bam::IndexedReader::from_path(bam_file).unwrap();
+    let mut bam_writer = bam::Writer::from_path(out_path, header, Format::Bam).unwrap();
+    bam_writer.set_compression_level(bam::CompressionLevel::Uncompressed).unwrap();
+    bam_reader.fetch((chunk.chr.as_str(), chunk.start, chunk.end)).unwrap();
+    for r in bam_reader.records() {
+        let record = r.unwrap();
+        if record.pos() < chunk.start || record.pos() >= chunk.end {
+            continue;
+        }
+        bam_writer.write(&record).unwrap();
+    }
+}
+
+pub fn concat_chunk_bams(
+    bam_file: &str,
+    tmp_dir: &str,
+    phased_bam_file: &str,
+    num_chunks: usize,
+    read_tags: &HashMap<String, ReadAssignment>,
+    conflict_reads: &HashSet<String>,
+    assignment_tags: bool,
+    thread_size: usize,
+) {
+    // concatenate the chunk bams in coordinate order, append unmapped reads without coordinate and index the phased bam.
+    // Reads assigned by several regions keep one consistent assignment on all of their records, or are left untagged with a reason when the regions disagree.
+    let mut bam_reader = bam::IndexedReader::from_path(bam_file).unwrap();
+    let header = bam::Header::from_template(bam_reader.header());
+    let mut bam_writer = bam::Writer::from_path(phased_bam_file, &header, Format::Bam).unwrap();
+    bam_writer.set_threads(thread_size).unwrap();
+    for chunk_idx in 0..num_chunks {
+        let path = chunk_bam_path(tmp_dir, chunk_idx);
+        let mut reader = bam::Reader::from_path(&path).unwrap();
+        for r in reader.records() {
+            let mut record = r.unwrap();
+            if !record.is_unmapped() && !record.is_secondary() {
+                let qname = std::str::from_utf8(record.qname()).unwrap().to_string();
+                if conflict_reads.contains(&qname) {
+                    remove_haplotype_tags(&mut record);
+                    let _ = record.push_aux(b"ur:Z", Aux::String("region_conflict"));
+                } else if let Some(asg) = read_tags.get(&qname) {
+                    // the record may be written by a region other than the one assigning the read, and phase sets may be
+                    // merged across regions after the region was written
+                    remove_haplotype_tags(&mut record);
+                    push_haplotype_tags(&mut record, asg, assignment_tags);
+                }
+            }
+            bam_writer.write(&record).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+    }
+    bam_reader.fetch(bam::FetchDefinition::Unmapped).unwrap();
+    for r in bam_reader.records() {
+        let record = r.unwrap();
+        bam_writer.write(&record).unwrap();
+    }
+    drop(bam_writer);
+    let _ = fs::remove_dir_all(tmp_dir);
+    bam::index::build(phased_bam_file, None, bam::index::Type::Bai, thread_size as u32).unwrap();
+}
+
+fn write_fastq_record(writer: &mut fastq::Writer<fs::File>, record: &bam::Record) {
+    // reads are written in their original orientation
+    let qname = std::str::from_utf8(record.qname()).unwrap();
+    let mut seq = record.seq().as_bytes();
+    let mut qual: Vec<u8> = record.qual().iter().map(|q| if *q == 255 { b'!' } else { q + 33 }).collect();
+    if record.is_reverse() {
+        seq = dna::revcomp(&seq);
+        qual.reverse();
+    }
+    writer.write(qname, None, &seq, &qual).unwrap();
+}
+
+pub fn split_chunk_bams(
+    bam_file: &str,
+    tmp_dir: &str,
+    phased_bam_file: &str,
+    num_chunks: usize,
+    hap1_reads: &HashSet<String>,
+    hap2_reads: &HashSet<String>,
+    read_tags: &HashMap<String, ReadAssignment>,
+    conflict_reads: &HashSet<String>,
+    assignment_tags: bool,
+    fastq_output: bool,
+    thread_size: usize,
+) {
+    // split the primary reads of the chunk bams into hap1, hap2 and unassigned outputs.
+    // Reads from regions without enough reads on both haplotypes and reads with conflicting assignments go to the unassigned output.
+    let names = ["unassigned", "hap1", "hap2"];
+    let mut bam_reader = bam::IndexedReader::from_path(bam_file).unwrap();
+    let header = bam::Header::from_template(bam_reader.header());
+    let mut bam_writers: Vec<bam::Writer> = Vec::new();
+    let mut fastq_writers: Vec<fastq::Writer<fs::File>> = Vec::new();
+    for name in names.iter() {
+        if fastq_output {
+            fastq_writers.push(fastq::Writer::to_file(phased_bam_file.replace(".phased.bam", &format!(".{}.fastq", name))).unwrap());
+        } else {
+            let mut writer = bam::Writer::from_path(phased_bam_file.replace(".phased.bam", &format!(".{}.bam", name)), &header, Format::Bam).unwrap();
+            writer.set_threads(thread_size).unwrap();
+            bam_writers.push(writer);
+        }
+    }
+    for chunk_idx in 0..num_chunks {
+        let path = chunk_bam_path(tmp_dir, chunk_idx);
+        let mut reader = bam::Reader::from_path(&path).unwrap();
+        for r in reader.records() {
+            let mut record = r.unwrap();
+            if record.is_secondary() || record.is_supplementary() {
+                continue;
+            }
+            let qname = std::str::from_utf8(record.qname()).unwrap().to_string();
+            let mut hap = 0;
+            if conflict_reads.contains(&qname) {
+                remove_haplotype_tags(&mut record);
+            } else {
+                if let Some(asg) = read_tags.get(&qname) {
+                    remove_haplotype_tags(&mut record);
+                    push_haplotype_tags(&mut record, asg, assignment_tags);
+                }
+                if hap1_reads.contains(&qname) {
+                    hap = 1;
+                } else if hap2_reads.contains(&qname) {
+                    hap = 2;
+                }
+            }
+            if fastq_output {
+                write_fastq_record(&mut fastq_writers[hap], &record);
+            } else {
+                bam_writers[hap].write(&record).unwrap();
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+    // unmapped reads without coordinate are unassigned
+    bam_reader.fetch(bam::FetchDefinition::Unmapped).unwrap();
+    for r in bam_reader.records() {
+        let record = r.unwrap();
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        if fastq_output {
+            write_fastq_record(&mut fastq_writers[0], &record);
+        } else {
+            bam_writers[0].write(&record).unwrap();
+        }
+    }
+    drop(bam_writers);
+    drop(fastq_writers);
+    let _ = fs::remove_dir_all(tmp_dir);
+    if !fastq_output {
+        for name in names.iter() {
+            bam::index::build(phased_bam_file.replace(".phased.bam", &format!(".{}.bam", name)), None, bam::index::Type::Bai, thread_size as u32).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    use crate::util::fetch_region_records;
+
+    fn mapped_record(qname: &str, pos: i64, len: u32) -> bam::Record {
+        let mut record = bam::Record::new();
+        let seq = vec![b'A'; len as usize];
+        let qual = vec![30; len as usize];
+        record.set(qname.as_bytes(), Some(&CigarString(vec![Cigar::Match(len)])), &seq, &qual);
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_mapq(60);
+        record.unset_unmapped();
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+        return record;
+    }
+
+    fn unmapped_record(qname: &str, tid: i32, pos: i64) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(qname.as_bytes(), None, b"ACGT", &[30, 30, 30, 30]);
+        record.set_tid(tid);
+        record.set_pos(pos);
+        record.set_unmapped();
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+        return record;
+    }
+
+    #[test]
+    fn merged_bam_keeps_every_input_read() {
+        let tmp = std::env::temp_dir().join(format!("longcallr_chunk_test_{}", std::process::id()));
+        fs::create_dir_all(&tmp).unwrap();
+        let input = tmp.join("input.bam").to_str().unwrap().to_string();
+        let output = tmp.join("output.phased.bam").to_str().unwrap().to_string();
+        let tmp_dir = tmp.join("chunks").to_str().unwrap().to_string();
+        fs::create_dir_all(&tmp_dir).unwrap();
+
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1");
+        sq.push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        let records = vec![
+            mapped_record("gap_before", 10, 50),
+            mapped_record("first_base", 100, 1), // covers only the first base of the region
+            unmapped_record("placed_unmapped", 0, 100),
+            mapped_record("inside", 120, 30),
+            mapped_record("region_end", 195, 20),
+            mapped_record("gap_after", 300, 10),
+            unmapped_record("unplaced", -1, -1),
+        ];
+        {
+            let mut writer = bam::Writer::from_path(&input, &header, Format::Bam).unwrap();
+            for r in records.iter() {
+                writer.write(r).unwrap();
+            }
+        }
+        bam::index::build(&input, None, bam::index::Type::Bai, 1).unwrap();
+
+        let regions = vec![Region { chr: "chr1".to_string(), start: 101, end: 201, gene_id: None }];
+        let header_view = bam::IndexedReader::from_path(&input).unwrap().header().clone();
+        let bam_header = bam::Header::from_template(&header_view);
+        let (chunks, _) = build_bam_chunks(&regions, &header_view);
+        for (chunk_idx, chunk) in chunks.iter().enumerate() {
+            let path = chunk_bam_path(&tmp_dir, chunk_idx);
+            match chunk.region_idx {
+                Some(idx) => {
+                    let region_records = fetch_region_records(&input, &regions[idx]);
+                    write_region_bam(&path, &bam_header, &region_records, chunk, &HashMap::new(), false);
+                }
+                None => write_gap_bam(&input, &path, &bam_header, chunk),
+            }
+        }
+        concat_chunk_bams(&input, &tmp_dir, &output, chunks.len(), &HashMap::new(), &HashSet::new(), false, 1);
+
+        let mut reader = bam::Reader::from_path(&output).unwrap();
+        let names: Vec<String> = reader.records().map(|r| String::from_utf8(r.unwrap().qname().to_vec()).unwrap()).collect();
+        let _ = fs::remove_dir_all(&tmp);
+        assert_eq!(names.len(), records.len(), "{:?}", names);
+        for r in records.iter() {
+            assert!(names.contains(&String::from_utf8(r.qname().to_vec()).unwrap()));
+        }
+    }
+
+    fn test_header() -> bam::Header {
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1");
+        sq.push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        return header;
+    }
+
+    fn assignment(read_id: &str, haplotype: i32, score: f64, num_snps: u32) -> ReadAssignment {
+        return ReadAssignment { read_id: read_id.to_string(), haplotype, region: "chr1:1-1000".to_string(), ploidy: 2, phase_set: 101, score, num_snps, reason: ".".to_string() };
+    }
+
+    fn split_reads(tmp: &std::path::Path, fastq_output: bool) -> String {
+        // hap1_read and hap2_read are assigned, conflict_read was assigned in two regions, the others are unassigned
+        let input = tmp.join("input.bam").to_str().unwrap().to_string();
+        let output = tmp.join("output.phased.bam").to_str().unwrap().to_string();
+        let tmp_dir = tmp.join("chunks").to_str().unwrap().to_string();
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let header = test_header();
+        let mut reverse = mapped_record("reverse_read", 300, 4);
+        reverse.set(b"reverse_read", Some(&CigarString(vec![Cigar::Match(4)])), b"AACG", &[10, 20, 30, 40]);
+        reverse.set_reverse();
+        let records = vec![mapped_record("hap1_read", 100, 10), mapped_record("hap2_read", 110, 10), mapped_record("conflict_read", 120, 10), reverse];
+        {
+            let mut writer = bam::Writer::from_path(&input, &header, Format::Bam).unwrap();
+            for r in records.iter() {
+                writer.write(r).unwrap();
+            }
+            writer.write(&unmapped_record("unplaced", -1, -1)).unwrap();
+        }
+        bam::index::build(&input, None, bam::index::Type::Bai, 1).unwrap();
+
+        let mut read_tags: HashMap<String, ReadAssignment> = HashMap::new();
+        read_tags.insert("hap1_read".to_string(), assignment("hap1_read", 1, 0.95, 3));
+        read_tags.insert("hap2_read".to_string(), assignment("hap2_read", 2, 0.75, 2));
+        read_tags.insert("conflict_read".to_string(), assignment("conflict_read", 1, 0.9, 4));
+        let chunk = BamChunk { chr: "chr1".to_string(), start: 0, end: 1000, region_idx: Some(0) };
+        write_region_bam(&chunk_bam_path(&tmp_dir, 0), &header, &records, &chunk, &read_tags, true);
+        let hap1_reads: HashSet<String> = ["hap1_read".to_string(), "conflict_read".to_string()].into_iter().collect();
+        let hap2_reads: HashSet<String> = ["hap2_read".to_string()].into_iter().collect();
+        let conflict_reads: HashSet<String> = ["conflict_read".to_string()].into_iter().collect();
+        split_chunk_bams(&input, &tmp_dir, &output, 1, &hap1_reads, &hap2_reads, &read_tags, &conflict_reads, true, fastq_output, 1);
+        return output;
+    }
+
+    fn read_bam(path: &str) -> Vec<bam::Record> {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        return reader.records().map(|r| r.unwrap()).collect();
+    }
+
+    #[test]
+    fn split_bams_carry_assignment_tags() {
+        let tmp = std::env::temp_dir().join(format!("longcallr_split_bam_test_{}", std::process::id()));
+        fs::create_dir_all(&tmp).unwrap();
+        let output = split_reads(&tmp, false);
+        let hap1 = read_bam(&output.replace(".phased.bam", ".hap1.bam"));
+        let hap2 = read_bam(&output.replace(".phased.bam", ".hap2.bam"));
+        let unassigned = read_bam(&output.replace(".phased.bam", ".unassigned.bam"));
+        let _ = fs::remove_dir_all(&tmp);
+
+        assert_eq!(hap1.len(), 1);
+        assert_eq!(hap1[0].qname(), b"hap1_read");
+        assert!(!hap1[0].is_unmapped());
+        assert!(matches!(hap1[0].aux(b"HP").unwrap(), Aux::I32(1)));
+        assert!(matches!(hap1[0].aux(b"PS").unwrap(), Aux::U32(101)));
+        match hap1[0].aux(b"hc").unwrap() {
+            Aux::Float(hc) => assert!((hc - 0.95).abs() < 1e-6),
+            _ => panic!("hc is not a float"),
+        }
+        assert!(matches!(hap1[0].aux(b"hn").unwrap(), Aux::U32(3)));
+        assert_eq!(hap2.len(), 1);
+        assert_eq!(hap2[0].qname(), b"hap2_read");
+        assert!(matches!(hap2[0].aux(b"HP").unwrap(), Aux::I32(2)));
+        assert!(matches!(hap2[0].aux(b"hn").unwrap(), Aux::U32(2)));
+
+        // conflicting reads lose their tags, unassigned and unmapped reads have none
+        let names: Vec<&[u8]> = unassigned.iter().map(|r| r.qname()).collect();
+        assert_eq!(names, vec![b"conflict_read".as_slice(), b"reverse_read".as_slice(), b"unplaced".as_slice()]);
+        for r in unassigned.iter() {
+            assert!(r.aux(b"HP").is_err() && r.aux(b"hc").is_err() && r.aux(b"hn").is_err());
+        }
+    }
+
+    #[test]
+    fn split_fastq_restores_read_orientation() {
+        let tmp = std::env::temp_dir().join(format!("longcallr_split_fastq_test_{}", std::process::id()));
+        fs::create_dir_all(&tmp).unwrap();
+        let output = split_reads(&tmp, true);
+        let read_fastq = |name: &str| -> Vec<fastq::Record> {
+            let reader = fastq::Reader::from_file(output.replace(".phased.bam", &format!(".{}.fastq", name))).unwrap();
+            return reader.records().map(|r| r.unwrap()).collect();
+        };
+        let (hap1, hap2, unassigned) = (read_fastq("hap1"), read_fastq("hap2"), read_fastq("unassigned"));
+        let _ = fs::remove_dir_all(&tmp);
+
+        assert_eq!(hap1.iter().map(|r| r.id()).collect::<Vec<&str>>(), vec!["hap1_read"]);
+        assert_eq!(hap2.iter().map(|r| r.id()).collect::<Vec<&str>>(), vec!["hap2_read"]);
+        assert_eq!(unassigned.iter().map(|r| r.id()).collect::<Vec<&str>>(), vec!["conflict_read", "reverse_read", "unplaced"]);
+        // the reverse strand read is reverse complemented with reversed qualities
+        assert_eq!(unassigned[1].seq(), b"CGTT");
+        assert_eq!(unassigned[1].qual(), &[40 + 33, 30 + 33, 20 + 33, 10 + 33]);
+        assert_eq!(hap1[0].seq(), b"AAAAAAAAAA");
+    }
+
+    #[test]
+    fn phased_bam_carries_assignment_tags_when_requested() {
+        let tmp = std::env::temp_dir().join(format!("longcallr_tag_test_{}", std::process::id()));
+        fs::create_dir_all(&tmp).unwrap();
+        let header = test_header();
+        let records = vec![mapped_record("assigned", 100, 10), mapped_record("unassigned", 110, 10)];
+        let mut read_tags: HashMap<String, ReadAssignment> = HashMap::new();
+        read_tags.insert("assigned".to_string(), assignment("assigned", 2, 0.875, 5));
+        read_tags.insert("unassigned".to_string(), assignment("unassigned", 0, 0.0, 1));
+        let chunk = BamChunk { chr: "chr1".to_string(), start: 0, end: 1000, region_idx: Some(0) };
+        let with_tags = tmp.join("with_tags.bam").to_str().unwrap().to_string();
+        let without_tags = tmp.join("without_tags.bam").to_str().unwrap().to_string();
+        write_region_bam(&with_tags, &header, &records, &chunk, &read_tags, true);
+        write_region_bam(&without_tags, &header, &records, &chunk, &read_tags, false);
+        let (tagged, untagged) = (read_bam(&with_tags), read_bam(&without_tags));
+        let _ = fs::remove_dir_all(&tmp);
+
+        assert!(matches!(tagged[0].aux(b"HP").unwrap(), Aux::I32(2)));
+        assert!(matches!(tagged[0].aux(b"hc").unwrap(), Aux::Float(hc) if hc == 0.875));
+        assert!(matches!(tagged[0].aux(b"hn").unwrap(), Aux::U32(5)));
+        assert!(tagged[1].aux(b"HP").is_err() && tagged[1].aux(b"hc").is_err());
+        assert!(matches!(untagged[0].aux(b"HP").unwrap(), Aux::I32(2)));
+        assert!(untagged[0].aux(b"hc").is_err() && untagged[0].aux(b"hn").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::{HashMap, HashSet};
+
+use rust_htslib::{bam, bam::Read, bam::record::Cigar};
+
+use crate::ase::ln_choose;
+use crate::vcf::VCFRecord;
+
+#[derive(Debug, Clone, Default)]
+pub struct Heteroplasmy {
+    pub chromosome: String,
+    pub pos: i64,
+    // position on the reference, 0-based
+    pub reference: char,
+    pub allele: char,
+    pub allele_cnt: u32,
+    pub depth: u32,
+    // number of bases passing the base quality filter
+    pub allele_frac: f64,
+    pub ci: [f64; 2],
+    // 95% Wilson score interval of the allele fraction
+    pub qual: f64,
+    // phred-scaled probability of the allele arising from sequencing errors alone
+}
+
+pub fn is_mito_contig(chr: &str) -> bool {
+    return chr == "chrM" || chr == "chrMT" || chr == "M" || chr == "MT";
+}
+
+fn wilson_interval(k: u32, n: u32) -> [f64; 2] {
+    let z = 1.96;
+    let n = n as f64;
+    let p = k as f64 / n;
+    let center = (p + z * z / (2.0 * n)) / (1.0 + z * z / n);
+    let half = z * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt() / (1.0 + z * z / n);
+    return [(center - half).max(0.0), (center + half).min(1.0)];
+}
+
+fn aligned_bases(record: &bam::Record) -> Vec<(i64, usize, usize)> {
+    // reference position, index in the record sequence and index in the sequenced read of every aligned base.
+    // The index in the sequenced read counts hard clipped bases and follows the strand of the read, so the primary and
+    // supplementary alignments of a split read refer to the same read bases.
+    let cigar = record.cigar();
+    let mut leading_clip = 0;
+    let mut hard_clip = 0;
+    for (i, cg) in cigar.iter().enumerate() {
+        if let Cigar::HardClip(l) = cg {
+            hard_clip += *l as usize;
+            if i == 0 {
+                leading_clip = *l as usize;
+            }
+        }
+    }
+    let read_len = record.seq_len() + hard_clip;
+    let mut bases: Vec<(i64, usize, usize)> = Vec::new();
+    let mut pos_on_ref = record.pos();
+    let mut pos_on_query: usize = 0;
+    for cg in cigar.iter() {
+        match cg.char() as u8 {
+            b'M' | b'X' | b'=' => {
+                for _ in 0..cg.len() {
+                    let read_idx = if record.is_reverse() { read_len - 1 - (leading_clip + pos_on_query) } else { leading_clip + pos_on_query };
+                    bases.push((pos_on_ref, pos_on_query, read_idx));
+                    pos_on_query += 1;
+                    pos_on_ref += 1;
+                }
+            }
+            b'I' | b'S' => {
+                pos_on_query += cg.len() as usize;
+            }
+            b'D' | b'N' => {
+                pos_on_ref += cg.len() as i64;
+            }
+            _ => {}
+        }
+    }
+    return bases;
+}
+
+fn count_bases(bam_file: &str, chr: &str, contig_len: usize, min_mapq: u8, min_baseq: u8) -> (Vec<[u32; 4]>, Vec<f64>) {
+    // A, C, G, T counts and sum of base error rates of every position of a circular contig.
+    // A read spanning the origin is split into a primary and a supplementary alignment, the pieces are joined by read name
+    // and every base of the read is counted once even if the pieces overlap. Alignments against a copy of the contig
+    // extended past the origin run beyond its end and wrap around to its start.
+    let mut counts: Vec<[u32; 4]> = vec![[0, 0, 0, 0]; contig_len];
+    let mut error_sums: Vec<f64> = vec![0.0; contig_len];
+    let mut counted: HashMap<Vec<u8>, HashSet<usize>> = HashMap::new(); // read name -> read bases counted by a piece of a split read
+    let mut bam_reader = bam::IndexedReader::from_path(bam_file).unwrap();
+    bam_reader.fetch(chr).unwrap();
+    for r in bam_reader.records() {
+        let record = r.unwrap();
+        if record.is_unmapped() || record.is_secondary() || record.is_duplicate() || record.mapq() < min_mapq {
+            continue;
+        }
+        let split = record.is_supplementary() || record.aux(b"SA").is_ok();
+        let seq = record.seq().as_bytes();
+        let qual = record.qual();
+        for (ref_pos, query_pos, read_idx) in aligned_bases(&record) {
+            if split && !counted.entry(record.qname().to_vec()).or_insert(HashSet::new()).insert(read_idx) {
+                continue;
+            }
+            let pos = if ref_pos >= contig_len as i64 { ref_pos - contig_len as i64 } else { ref_pos };
+            if pos < 0 || pos >= contig_len as i64 || qual[query_pos] < min_baseq {
+                continue;
+            }
+            let base_idx = match seq[query_pos].to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => continue,
+            };
+            counts[pos as usize][base_idx] += 1;
+            error_sums[pos as usize] += 10.0_f64.powf(-(qual[query_pos] as f64) / 10.0);
+        }
+    }
+    return (counts, error_sums);
+}
+
+fn heteroplasmy_quality(allele_cnt: u32, depth: u32, error_rate: f64) -> f64 {
+    // phred-scaled probability that allele_cnt or more of depth bases show the allele by sequencing errors alone,
+    // an error gives each of the three other bases with equal probability
+    let e = (error_rate / 3.0).max(1e-6).min(0.5);
+    let log_terms: Vec<f64> = (allele_cnt..=depth).map(|i| ln_choose(depth, i) + i as f64 * e.ln() + (depth - i) as f64 * (1.0 - e).ln()).collect();
+    let max_term = log_terms.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let log_p = max_term + log_terms.iter().map(|x| (x - max_term).exp()).sum::<f64>().ln();
+    return (-10.0 * log_p / 10.0_f64.ln()).max(0.0);
+}
+
+pub fn call_heteroplasmy(
+    bam_file: &str,
+    chr: &str,
+    ref_seq: &Vec<u8>,
+    min_mapq: u8,
+    min_baseq: u8,
+    min_heteroplasmy_frac: f32,
+    min_heteroplasmy_cnt: u32,
+) -> Vec<Heteroplasmy> {
+    // Count the bases of every position of a mitochondrial contig without a depth limit and report each non-reference allele
+    // above the detection limit, with the quality of the allele against sequencing errors.
+    let (counts, error_sums) = count_bases(bam_file, chr, ref_seq.len(), min_mapq, min_baseq);
+
+    let bases = ['A', 'C', 'G', 'T'];
+    let mut sites: Vec<Heteroplasmy> = Vec::new();
+    for pos in 0..ref_seq.len() {
+        let reference = (ref_seq[pos] as char).to_ascii_uppercase();
+        let depth: u32 = counts[pos].iter().sum();
+        if depth == 0 {
+            continue;
+        }
+        for b in 0..4 {
+            let cnt = counts[pos][b];
+            if bases[b] == reference || cnt < min_heteroplasmy_cnt || (cnt as f32) / (depth as f32) < min_heteroplasmy_frac {
+                continue;
+            }
+            sites.push(Heteroplasmy {
+                chromosome: chr.to_string(),
+                pos: pos as i64,
+                reference: reference,
+                allele: bases[b],
+                allele_cnt: cnt,
+                depth: depth,
+                allele_frac: cnt as f64 / depth as f64,
+                ci: wilson_interval(cnt, depth),
+                qual: heteroplasmy_quality(cnt, depth, error_sums[pos] / depth as f64),
+            });
+        }
+    }
+    return sites;
+}
+
+pub fn heteroplasmy_vcf_records(sites: &Vec<Heteroplasmy>, min_heteroplasmy_frac: f32, min_qual: u32) -> Vec<VCFRecord> {
+    // one haploid record per allele, sites where the reference allele is also above the detection limit are flagged HTP.
+    // Alleles not distinguishable from sequencing errors are filtered LowQual
+    let mut records: Vec<VCFRecord> = Vec::new();
+    for site in sites.iter() {
+        let mut rd: VCFRecord = VCFRecord::default();
+        rd.chromosome = site.chromosome.clone().into_bytes();
+        rd.position = site.pos as u64 + 1; // position in vcf format is 1-based
+        rd.id = vec!['.' as u8];
+        rd.reference = vec![site.reference as u8];
+        rd.alternative = vec![vec![site.allele as u8]];
+        rd.qual = site.qual as i32;
+        if site.qual < min_qual as f64 {
+            rd.filter = "LowQual".to_string().into_bytes();
+        } else {
+            rd.filter = "PASS".to_string().into_bytes();
+        }
+        if site.allele_frac <= 1.0 - min_heteroplasmy_frac as f64 {
+            rd.info = "RDS=.;HTP".to_string().into_bytes();
+        } else {
+            rd.info = "RDS=.".to_string().into_bytes();
+        }
+        rd.genotype = format!("{}:{}:{:.4}:{:.4},{:.4}", "1", site.depth, site.allele_frac, site.ci[0], site.ci[1]);
+        rd.format = "GT:DP:AF:AFCI".to_string().into_bytes();
+        records.push(rd);
+    }
+    return records;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, CigarString};
+    use rust_htslib::bam::Format;
+
+    fn piece(qname: &str, pos: i64, cigar: Vec<Cigar>, seq: &[u8], supplementary: bool) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(qname.as_bytes(), Some(&CigarString(cigar)), seq, &vec![30; seq.len()]);
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_mapq(60);
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+        record.unset_unmapped();
+        if supplementary {
+            record.set_supplementary();
+        }
+        record.push_aux(b"SA", Aux::String("chrM,1,+,15M10S,60,0;")).unwrap();
+        return record;
+    }
+
+    fn write_bam(path: &str, contig_len: u32, records: &Vec<bam::Record>) {
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chrM");
+        sq.push_tag(b"LN", contig_len);
+        header.push_record(&sq);
+        {
+            let mut writer = bam::Writer::from_path(path, &header, Format::Bam).unwrap();
+            for r in records.iter() {
+                writer.write(r).unwrap();
+            }
+        }
+        bam::index::build(path, None, bam::index::Type::Bai, 1).unwrap();
+    }
+
+    #[test]
+    fn read_crossing_the_origin_is_counted_once() {
+        let tmp = std::env::temp_dir().join(format!("longcallr_heteroplasmy_test_{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let path = tmp.join("origin.bam").to_str().unwrap().to_string();
+
+        // a 25 bp read, 15 A aligned to the end of the 100 bp contig and 10 C to its start.
+        // The supplementary piece also aligns the last two A of the primary piece to positions 0 and 1.
+        let mut seq = vec![b'A'; 15];
+        seq.extend(vec![b'C'; 10]);
+        let records = vec![
+            piece("split", 0, vec![Cigar::HardClip(13), Cigar::Match(12)], &seq[13..], true),
+            piece("split", 85, vec![Cigar::Match(15), Cigar::SoftClip(10)], &seq, false),
+        ];
+        write_bam(&path, 100, &records);
+        let (counts, _) = count_bases(&path, "chrM", 100, 0, 0);
+        let depth: Vec<u32> = counts.iter().map(|c| c.iter().sum()).collect();
+        assert_eq!(depth.iter().sum::<u32>(), 25);
+        assert_eq!(counts[0], [1, 0, 0, 0]);
+        assert_eq!(counts[2], [0, 1, 0, 0]);
+        assert_eq!(counts[11], [0, 1, 0, 0]);
+        assert_eq!(counts[97], [1, 0, 0, 0]);
+        assert_eq!(depth[98], 0);
+
+        // an alignment against the contig extended past the origin wraps around to its start
+        let path = tmp.join("extended.bam").to_str().unwrap().to_string();
+        write_bam(&path, 110, &vec![piece("extended", 95, vec![Cigar::Match(10)], &[b'C'; 10], false)]);
+        let (counts, _) = count_bases(&path, "chrM", 100, 0, 0);
+        let _ = std::fs::remove_dir_all(&tmp);
+        for pos in [95, 99, 0, 4] {
+            assert_eq!(counts[pos], [0, 1, 0, 0]);
+        }
+        assert_eq!(counts[5], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn heteroplasmy_quality_separates_alleles_from_errors() {
+        // a single base of 1000 at Q30 is expected from errors, 50 are not
+        assert!(heteroplasmy_quality(1, 1000, 0.001) < 10.0);
+        assert!(heteroplasmy_quality(50, 1000, 0.001) > 100.0);
+        assert!(heteroplasmy_quality(50, 1000, 0.001) > heteroplasmy_quality(20, 1000, 0.001));
+
+        let site = |qual: f64| Heteroplasmy { chromosome: "chrM".to_string(), reference: 'A', allele: 'C', allele_cnt: 5, depth: 100, allele_frac: 0.05, qual: qual, ..Default::default() };
+        let records = heteroplasmy_vcf_records(&vec![site(3.0), site(250.0)], 0.01, 20);
+        assert_eq!(records[0].qual, 3);
+        assert_eq!(records[0].filter, b"LowQual".to_vec());
+        assert_eq!(records[1].qual, 250);
+        assert_eq!(records[1].filter, b"PASS".to_vec());
+    }
+}
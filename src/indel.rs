@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+
+use rust_lapper::{Interval, Lapper};
+
+use crate::Platform;
+use crate::snp::CandidateIndel;
+use crate::snpfrags::SNPFrag;
+use crate::util::Profile;
+use crate::vcf::VCFRecord;
+
+pub fn left_align_insertion(ref_seq: &Vec<u8>, anchor: usize, ins_seq: &Vec<u8>) -> (usize, Vec<u8>) {
+    // shift the insertion to the left while the last inserted base equals to the anchor base.
+    // anchor: 0-based position of the base before the insertion.
+    let mut anchor = anchor;
+    let mut ins_seq = ins_seq.clone();
+    if ins_seq.len() == 0 {
+        return (anchor, ins_seq);
+    }
+    while anchor > 0 && ref_seq[anchor].to_ascii_uppercase() == *ins_seq.last().unwrap() {
+        ins_seq.rotate_right(1);
+        anchor -= 1;
+    }
+    return (anchor, ins_seq);
+}
+
+pub fn left_align_deletion(ref_seq: &Vec<u8>, anchor: usize, del_len: usize) -> usize {
+    // shift the deletion to the left while the anchor base equals to the last deleted base.
+    // anchor: 0-based position of the base before the deleted bases.
+    let mut anchor = anchor;
+    while anchor > 0 && anchor + del_len < ref_seq.len() && ref_seq[anchor].to_ascii_uppercase() == ref_seq[anchor + del_len].to_ascii_uppercase() {
+        anchor -= 1;
+    }
+    return anchor;
+}
+
+pub fn homopolymer_length(ref_seq: &Vec<u8>, anchor: usize, indel_seq: &Vec<u8>) -> u32 {
+    // length of the reference homopolymer right after the anchor base, only when the indel sequence is the same repeated base.
+    // Because the indel is left-aligned, the homopolymer starts at anchor + 1.
+    if indel_seq.len() == 0 || anchor + 1 >= ref_seq.len() {
+        return 1;
+    }
+    let base = indel_seq[0].to_ascii_uppercase();
+    if indel_seq.iter().any(|b| b.to_ascii_uppercase() != base) || ref_seq[anchor + 1].to_ascii_uppercase() != base {
+        return 1;
+    }
+    let mut hp_len = 0;
+    let mut i = anchor + 1;
+    while i < ref_seq.len() && ref_seq[i].to_ascii_uppercase() == base {
+        hp_len += 1;
+        i += 1;
+    }
+    return hp_len;
+}
+
+pub fn indel_error_rate(platform: &Platform, homopolymer_len: u32, indel_len: usize) -> f64 {
+    // probability that one read shows this indel by sequencing error.
+    // indel errors grow quickly with the homopolymer length, ont reads have much more indel errors than hifi reads.
+    let (base_rate, growth, max_rate) = match platform {
+        Platform::hifi => (0.001, 2.0_f64, 0.3),
+        Platform::ont => (0.01, 1.6_f64, 0.45),
+    };
+    let hp = if homopolymer_len > 1 { homopolymer_len - 1 } else { 0 };
+    let error_rate = base_rate * growth.powi(hp as i32) / (indel_len as f64);
+    return error_rate.min(max_rate);
+}
+
+pub fn indel_loglikelihood(alt_cnt: u32, depth: u32, error_rate: f64, ploidy: u32) -> [f64; 3] {
+    // log10 likelihood of the reads under homo variant, hete variant and homo reference
+    let mut loglikelihood = [0.0, 0.0, 0.0];
+    loglikelihood[0] = (alt_cnt as f64) * (1.0 - error_rate).log10() + ((depth - alt_cnt) as f64) * error_rate.log10();
+    loglikelihood[1] = -(depth as f64) * 2.0_f64.log10();
+    loglikelihood[2] = (alt_cnt as f64) * error_rate.log10() + ((depth - alt_cnt) as f64) * (1.0 - error_rate).log10();
+    if ploidy == 1 {
+        // hemizygous, no heterozygous genotype
+        loglikelihood[1] = f64::NEG_INFINITY;
+    }
+    return loglikelihood;
+}
+
+impl SNPFrag {
+    pub fn get_candidate_indels(
+        &mut self,
+        profile: &Profile,
+        ref_seq: &Vec<u8>,
+        platform: &Platform,
+        exon_region_vec: Vec<Interval<usize, u8>>,
+        min_indel_freq: f32,
+        min_indel_cnt: u32,
+        min_coverage: u32,
+        max_coverage: u32,
+    ) {
+        let pileup = &profile.freq_vec;
+        let freq_vec_pos = profile.region.start as usize - 1; // 0-based
+        let mut use_annotation: bool = false;
+        if exon_region_vec.len() > 0 { use_annotation = true; }
+        let exon_intervaltree = Lapper::new(exon_region_vec);
+        let mut anchors: Vec<&usize> = profile.indel_freq.keys().collect();
+        anchors.sort();
+        for bfidx in anchors {
+            let position = freq_vec_pos + *bfidx; // 0-based
+            if *bfidx + 1 >= pileup.len() {
+                continue;
+            }
+            if use_annotation && exon_intervaltree.find(position + 1, position + 2).count() == 0 {
+                // filter, not covered by exon
+                continue;
+            }
+            let ifreq = profile.indel_freq.get(bfidx).unwrap();
+            let bf = &pileup[*bfidx];
+
+            // the most frequent indel allele at this anchor
+            let mut reference: Vec<u8> = vec![ref_seq[position].to_ascii_uppercase()];
+            let mut alternative: Vec<u8> = reference.clone();
+            let mut indel_seq: Vec<u8> = Vec::new();
+            let mut alt_cnt = 0;
+            let mut total_indel_cnt = 0;
+            for (ins_seq, cnt) in ifreq.ins.iter() {
+                total_indel_cnt += *cnt;
+                if *cnt > alt_cnt || (*cnt == alt_cnt && ins_seq < &indel_seq) {
+                    alt_cnt = *cnt;
+                    indel_seq = ins_seq.clone();
+                    reference = vec![ref_seq[position].to_ascii_uppercase()];
+                    alternative = reference.clone();
+                    alternative.extend(ins_seq.iter());
+                }
+            }
+            let mut dels: Vec<(&u32, &u32)> = ifreq.del.iter().collect();
+            dels.sort();
+            for (del_len, cnt) in dels {
+                total_indel_cnt += *cnt;
+                let del_end = position + 1 + *del_len as usize;
+                if del_end > ref_seq.len() {
+                    continue;
+                }
+                if *cnt > alt_cnt {
+                    alt_cnt = *cnt;
+                    indel_seq = ref_seq[position + 1..del_end].to_ascii_uppercase();
+                    reference = ref_seq[position..del_end].to_ascii_uppercase();
+                    alternative = vec![ref_seq[position].to_ascii_uppercase()];
+                }
+            }
+            if alt_cnt < min_indel_cnt || reference.contains(&b'N') {
+                continue;
+            }
+
+            // reads with a base or a deletion at the anchor base
+            let depth = (bf.get_depth_exclude_intron_deletion() + bf.d).max(total_indel_cnt);
+            if depth < min_coverage || depth > max_coverage {
+                continue;
+            }
+            let allele_freq = alt_cnt as f32 / depth as f32;
+            if allele_freq < min_indel_freq {
+                continue;
+            }
+
+            // genotype likelihood, the error rate depends on platform and homopolymer length
+            let homopolymer_len = homopolymer_length(ref_seq, position, &indel_seq);
+            let error_rate = indel_error_rate(platform, homopolymer_len, indel_seq.len());
+            let loglikelihood = indel_loglikelihood(alt_cnt, depth, error_rate, self.ploidy);
+            let theta = 0.0001; // indel mutation rate
+            let background_prob: [f64; 3] = [theta / 2.0, theta, 1.0 - 1.5 * theta]; // background of probability of observe homo variant, hete variant and homo reference
+
+            let mut logprob = loglikelihood.clone();
+            logprob[0] += background_prob[0].log10();
+            logprob[1] += background_prob[1].log10();
+            logprob[2] += background_prob[2].log10();
+            let max_logprob = logprob[0].max(logprob[1]).max(logprob[2]);
+            let mut variant_prob = [0.0, 0.0, 0.0];
+            for i in 0..3 {
+                variant_prob[i] = 10.0_f64.powf(logprob[i] - max_logprob);
+            }
+            let sum_variant_prob = variant_prob[0] + variant_prob[1] + variant_prob[2];
+            for i in 0..3 {
+                variant_prob[i] = variant_prob[i] / sum_variant_prob;
+            }
+            let variant_quality = -10.0 * (10e-301_f64.max(variant_prob[2]).log10());
+
+            let max_loglikelihood = loglikelihood[0].max(loglikelihood[1]).max(loglikelihood[2]);
+            let mut genotype_prob = [0.0, 0.0, 0.0];
+            for i in 0..3 {
+                genotype_prob[i] = 10.0_f64.powf(loglikelihood[i] - max_loglikelihood);
+            }
+            let sum_genotype_prob = genotype_prob[0] + genotype_prob[1] + genotype_prob[2];
+            for i in 0..3 {
+                genotype_prob[i] = genotype_prob[i] / sum_genotype_prob;
+            }
+            let mut phred_genotype_prob = [0.0, 0.0, 0.0];
+            for i in 0..3 {
+                phred_genotype_prob[i] = -10.0 * genotype_prob[i].log10();
+            }
+            phred_genotype_prob.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let genotype_quality = phred_genotype_prob[1] - phred_genotype_prob[0];
+
+            let variant_type;
+            if genotype_prob[0] > genotype_prob[1] && genotype_prob[0] > genotype_prob[2] {
+                variant_type = 2;
+            } else if genotype_prob[1] > genotype_prob[0] && genotype_prob[1] > genotype_prob[2] {
+                variant_type = 1;
+            } else {
+                continue;
+            }
+
+            let mut candidate_indel = CandidateIndel::default();
+            candidate_indel.chromosome = profile.region.chr.clone().into_bytes();
+            candidate_indel.pos = position as i64;
+            candidate_indel.reference = reference;
+            candidate_indel.alternative = alternative;
+            candidate_indel.depth = depth;
+            candidate_indel.alt_cnt = alt_cnt;
+            candidate_indel.allele_freq = allele_freq;
+            candidate_indel.homopolymer_len = homopolymer_len;
+            candidate_indel.error_rate = error_rate;
+            candidate_indel.variant_type = variant_type;
+            candidate_indel.variant_quality = variant_quality;
+            candidate_indel.genotype_probability = genotype_prob;
+            candidate_indel.genotype_quality = genotype_quality;
+            self.candidate_indels.push(candidate_indel);
+        }
+    }
+
+    pub fn eval_indel_phase(&mut self, min_phase_score: f32) {
+        // phase heterozygous indels with the haplotype of the reads covering them
+        for ti in 0..self.candidate_indels.len() {
+            if self.candidate_indels[ti].variant_type != 1 || self.candidate_indels[ti].indel_cover_fragments.len() == 0 {
+                continue;
+            }
+            let mut sigma: Vec<i32> = Vec::new();
+            let mut ps: Vec<i32> = Vec::new();
+            let mut probs: Vec<f64> = Vec::new();
+            let mut hap1_reads_num = 0;
+            let mut hap2_reads_num = 0;
+            for k in self.candidate_indels[ti].indel_cover_fragments.iter() {
+                if self.fragments[*k].assignment == 0 { continue; }
+                if self.fragments[*k].num_hete_links < self.min_linkers { continue; }
+                for ie in self.fragments[*k].indel_list.iter() {
+                    if ie.indel_idx == ti {
+                        if self.fragments[*k].assignment == 1 {
+                            hap1_reads_num += 1;
+                        } else if self.fragments[*k].assignment == 2 {
+                            hap2_reads_num += 1;
+                        }
+                        ps.push(ie.p);
+                        probs.push(ie.prob);
+                        sigma.push(self.fragments[*k].haplotag);
+                    }
+                }
+            }
+            if sigma.len() == 0 || hap1_reads_num < 2 || hap2_reads_num < 2 {
+                // each haplotype should have at least 2 reads
+                continue;
+            }
+            let phase_score1 = -10.0_f64 * (1.0 - SNPFrag::cal_delta_sigma_log(1, &sigma, &ps, &probs)).log10();
+            let phase_score2 = -10.0_f64 * (1.0 - SNPFrag::cal_delta_sigma_log(-1, &sigma, &ps, &probs)).log10();
+            let phase_score = phase_score1.max(phase_score2);
+            if phase_score < min_phase_score as f64 {
+                continue;
+            }
+            let mut haplotype_allele_expression: [u32; 4] = [0, 0, 0, 0];   // hap1_ref, hap1_alt, hap2_ref, hap2_alt
+            for k in 0..sigma.len() {
+                if sigma[k] == 1 {
+                    if ps[k] == 1 {
+                        haplotype_allele_expression[0] += 1;
+                    } else if ps[k] == -1 {
+                        haplotype_allele_expression[1] += 1;
+                    }
+                } else if sigma[k] == -1 {
+                    if ps[k] == 1 {
+                        haplotype_allele_expression[2] += 1;
+                    } else if ps[k] == -1 {
+                        haplotype_allele_expression[3] += 1;
+                    }
+                }
+            }
+            let indel = &mut self.candidate_indels[ti];
+            indel.haplotype = if phase_score1 >= phase_score2 { 1 } else { -1 };
+            indel.phase_score = phase_score;
+            indel.haplotype_expression = haplotype_allele_expression;
+        }
+    }
+
+    pub fn assign_indel_phase_set(&mut self, read_phase_sets: &HashMap<String, u32>) {
+        // phased indel takes the phase set shared by most of its assigned reads
+        for ti in 0..self.candidate_indels.len() {
+            if self.candidate_indels[ti].haplotype == 0 {
+                continue;
+            }
+            let mut ps_cnt: HashMap<u32, u32> = HashMap::new();
+            for k in self.candidate_indels[ti].indel_cover_fragments.iter() {
+                let frag = &self.fragments[*k];
+                if frag.assignment == 0 {
+                    continue;
+                }
+                if let Some(ps) = read_phase_sets.get(&frag.read_id) {
+                    *ps_cnt.entry(*ps).or_insert(0) += 1;
+                }
+            }
+            let mut best_ps = 0;
+            let mut best_cnt = 0;
+            for (ps, cnt) in ps_cnt.iter() {
+                if *cnt > best_cnt || (*cnt == best_cnt && *ps < best_ps) {
+                    best_ps = *ps;
+                    best_cnt = *cnt;
+                }
+            }
+            self.candidate_indels[ti].phase_set = best_ps;
+        }
+    }
+
+    pub fn output_indel_vcf(&self, min_phase_score: f32, min_qual: u32, phased: bool) -> Vec<VCFRecord> {
+        let mut records: Vec<VCFRecord> = Vec::new();
+        for indel in self.candidate_indels.iter() {
+            let mut rd: VCFRecord = VCFRecord::default();
+            rd.chromosome = indel.chromosome.clone();
+            rd.position = indel.pos as u64 + 1; // position in vcf format is 1-based
+            rd.id = vec!['.' as u8];
+            rd.reference = indel.reference.clone();
+            rd.alternative = vec![indel.alternative.clone()];
+            rd.qual = indel.variant_quality as i32;
+            if indel.variant_quality < min_qual as f64 {
+                rd.filter = "LowQual".to_string().into_bytes();
+            } else if phased && indel.variant_type == 1 && indel.phase_score < min_phase_score as f64 {
+                rd.filter = "LowQual".to_string().into_bytes();
+            } else {
+                rd.filter = "PASS".to_string().into_bytes();
+            }
+            rd.info = format!("RDS=.;INDEL;HPL={}", indel.homopolymer_len).into_bytes();
+            if indel.variant_type == 2 {
+                rd.genotype = format!("{}:{}:{}:{:.2}", "1/1", indel.genotype_quality as i32, indel.depth, indel.allele_freq);
+                rd.format = "GT:GQ:DP:AF".to_string().into_bytes();
+            } else if phased && indel.haplotype != 0 {
+                let gt = if indel.haplotype == -1 { "0|1" } else { "1|0" };
+                if indel.phase_set != 0 {
+                    rd.genotype = format!(
+                        "{}:{}:{}:{}:{:.2}:{:.2}:{},{},{},{}",
+                        gt,
+                        indel.phase_set,
+                        indel.genotype_quality as i32,
+                        indel.depth,
+                        indel.allele_freq,
+                        indel.phase_score,
+                        indel.haplotype_expression[0],
+                        indel.haplotype_expression[1],
+                        indel.haplotype_expression[2],
+                        indel.haplotype_expression[3]
+                    );
+                    rd.format = "GT:PS:GQ:DP:AF:PQ:AE".to_string().into_bytes();
+                } else {
+                    rd.genotype = format!(
+                        "{}:{}:{}:{:.2}:{:.2}:{},{},{},{}",
+                        gt,
+                        indel.genotype_quality as i32,
+                        indel.depth,
+                        indel.allele_freq,
+                        indel.phase_score,
+                        indel.haplotype_expression[0],
+                        indel.haplotype_expression[1],
+                        indel.haplotype_expression[2],
+                        indel.haplotype_expression[3]
+                    );
+                    rd.format = "GT:GQ:DP:AF:PQ:AE".to_string().into_bytes();
+                }
+            } else {
+                rd.genotype = format!("{}:{}:{}:{:.2}", "0/1", indel.genotype_quality as i32, indel.depth, indel.allele_freq);
+                rd.format = "GT:GQ:DP:AF".to_string().into_bytes();
+            }
+            records.push(rd);
+        }
+        return records;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn best_genotype(loglikelihood: [f64; 3]) -> usize {
+        // 0: homo variant, 1: hete variant, 2: homo reference
+        let mut best = 0;
+        for i in 1..3 {
+            if loglikelihood[i] > loglikelihood[best] {
+                best = i;
+            }
+        }
+        return best;
+    }
+
+    #[test]
+    fn left_align_insertion_in_homopolymer() {
+        let ref_seq = b"GAAAAT".to_vec();
+        assert_eq!(left_align_insertion(&ref_seq, 4, &b"A".to_vec()), (0, b"A".to_vec()));
+        assert_eq!(left_align_insertion(&ref_seq, 4, &b"AA".to_vec()), (0, b"AA".to_vec()));
+        // the inserted base differs from the anchor, nothing to shift
+        assert_eq!(left_align_insertion(&ref_seq, 4, &b"G".to_vec()), (4, b"G".to_vec()));
+    }
+
+    #[test]
+    fn left_align_insertion_in_repeat_unit() {
+        let ref_seq = b"GCACACAT".to_vec();
+        assert_eq!(left_align_insertion(&ref_seq, 6, &b"CA".to_vec()), (0, b"CA".to_vec()));
+        assert_eq!(left_align_insertion(&ref_seq, 5, &b"AC".to_vec()), (0, b"CA".to_vec()));
+    }
+
+    #[test]
+    fn left_align_deletion_in_homopolymer() {
+        let ref_seq = b"GAAAAT".to_vec();
+        assert_eq!(left_align_deletion(&ref_seq, 3, 1), 0);
+        assert_eq!(left_align_deletion(&ref_seq, 2, 2), 0);
+        assert_eq!(left_align_deletion(&ref_seq, 0, 1), 0);
+    }
+
+    #[test]
+    fn left_align_deletion_in_repeat_unit() {
+        let ref_seq = b"TTGCACACAT".to_vec();
+        assert_eq!(left_align_deletion(&ref_seq, 6, 2), 2);
+        // deleting the last T of the repeat-free flank is already left-aligned
+        assert_eq!(left_align_deletion(&ref_seq, 8, 1), 8);
+    }
+
+    #[test]
+    fn genotype_of_clear_het_and_hom_alt_indels() {
+        let error_rate = indel_error_rate(&Platform::hifi, 1, 1);
+        assert_eq!(best_genotype(indel_loglikelihood(10, 20, error_rate, 2)), 1);
+        assert_eq!(best_genotype(indel_loglikelihood(20, 20, error_rate, 2)), 0);
+        assert_eq!(best_genotype(indel_loglikelihood(19, 20, error_rate, 2)), 0);
+        assert_eq!(best_genotype(indel_loglikelihood(0, 20, error_rate, 2)), 2);
+        // haploid sites have no heterozygous genotype
+        assert_eq!(indel_loglikelihood(10, 20, error_rate, 1)[1], f64::NEG_INFINITY);
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use fishers_exact::fishers_exact;
+use rust_htslib::bam;
+use rust_htslib::bam::record::Aux;
+use rust_lapper::Interval;
+
+use crate::ase::{benjamini_hochberg, gene_of_read};
+use crate::phase_link::PhaseSetLink;
+use crate::snpfrags::SNPFrag;
+
+#[derive(Debug, Clone, Default)]
+pub struct ReadIsoform {
+    pub isoform_id: String,
+    pub gene_id: String,
+    // empty if not given, the gene is then taken from the annotation
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IsoformCount {
+    pub gene_id: String,
+    // "." if the gene is unknown, isoforms are then compared within the phase set
+    pub isoform_id: String,
+    pub chromosome: String,
+    pub phase_set: u32,
+    pub hap1_reads: u32,
+    pub hap2_reads: u32,
+    // haplotagged reads of the isoform
+    pub gene_hap1_reads: u32,
+    pub gene_hap2_reads: u32,
+    // haplotagged reads of all isoforms of the gene in the phase set
+    pub pvalue: f64,
+    pub qvalue: f64,
+}
+
+pub fn load_read_isoforms(read_isoform_file: &str) -> HashMap<String, ReadIsoform> {
+    // read to isoform mapping of an isoform classifier, tab separated: read name, isoform id and optionally gene id
+    let mut read_isoforms: HashMap<String, ReadIsoform> = HashMap::new();
+    let reader = BufReader::new(File::open(read_isoform_file).unwrap());
+    for line in reader.lines() {
+        let line = line.unwrap();
+        if line.starts_with("#") || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.trim_end().split("\t").collect();
+        if fields.len() < 2 {
+            continue;
+        }
+        read_isoforms.insert(
+            fields[0].to_string(),
+            ReadIsoform {
+                isoform_id: fields[1].to_string(),
+                gene_id: if fields.len() > 2 { fields[2].to_string() } else { String::new() },
+            },
+        );
+    }
+    return read_isoforms;
+}
+
+pub fn tagged_read_isoforms(records: &Vec<bam::Record>, isoform_tag: &str) -> HashMap<String, ReadIsoform> {
+    // read to isoform mapping from the transcript tag of the reads
+    let mut read_isoforms: HashMap<String, ReadIsoform> = HashMap::new();
+    for record in records.iter() {
+        if let Ok(Aux::String(isoform_id)) = record.aux(isoform_tag.as_bytes()) {
+            read_isoforms.insert(
+                std::str::from_utf8(record.qname()).unwrap().to_string(),
+                ReadIsoform { isoform_id: isoform_id.to_string(), gene_id: String::new() },
+            );
+        }
+    }
+    return read_isoforms;
+}
+
+impl SNPFrag {
+    pub fn collect_isoform_counts(
+        &self,
+        read_isoforms: &HashMap<String, ReadIsoform>,
+        gene_exons: &HashMap<String, Vec<Interval<usize, u8>>>,
+        read_phase_sets: &HashMap<String, u32>,
+    ) -> Vec<IsoformCount> {
+        // haplotagged reads of each isoform and phase set of the region
+        let mut counts: HashMap<(String, String, u32), IsoformCount> = HashMap::new();
+        for frag in self.fragments.iter() {
+            if frag.assignment != 1 && frag.assignment != 2 {
+                continue;
+            }
+            let ps = match read_phase_sets.get(&frag.read_id) {
+                Some(v) => *v,
+                None => continue,
+            };
+            let iso = match read_isoforms.get(&frag.read_id) {
+                Some(v) => v,
+                None => continue,
+            };
+            let mut gene_id = iso.gene_id.clone();
+            if gene_id.is_empty() {
+                gene_id = gene_of_read(gene_exons, &frag.exons);
+            }
+            let cnt = counts.entry((gene_id.clone(), iso.isoform_id.clone(), ps)).or_insert(IsoformCount {
+                gene_id: gene_id,
+                isoform_id: iso.isoform_id.clone(),
+                chromosome: self.region.chr.clone(),
+                phase_set: ps,
+                ..Default::default()
+            });
+            if frag.assignment == 1 {
+                cnt.hap1_reads += 1;
+            } else {
+                cnt.hap2_reads += 1;
+            }
+        }
+        return counts.into_values().collect();
+    }
+}
+
+pub fn relabel_isoform_counts(isoform_counts: &mut VecDeque<IsoformCount>, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    for cnt in isoform_counts.iter_mut() {
+        if let Some(link) = phase_set_links.get(&(cnt.chromosome.clone(), cnt.phase_set)) {
+            cnt.phase_set = link.phase_set;
+            if link.flip {
+                std::mem::swap(&mut cnt.hap1_reads, &mut cnt.hap2_reads);
+            }
+        }
+    }
+}
+
+pub fn test_isoform_usage(isoform_counts: &VecDeque<IsoformCount>) -> Vec<IsoformCount> {
+    // merge the counts of the same isoform collected by different regions, then test every isoform against the other
+    // isoforms of the gene in the same phase set for haplotype dependent usage (Fisher's exact test) and correct for multiple testing
+    let mut merged: Vec<IsoformCount> = Vec::new();
+    let mut merged_idx: HashMap<(String, String, String, u32), usize> = HashMap::new();
+    for cnt in isoform_counts.iter() {
+        let key = (cnt.gene_id.clone(), cnt.isoform_id.clone(), cnt.chromosome.clone(), cnt.phase_set);
+        match merged_idx.get(&key) {
+            Some(i) => {
+                merged[*i].hap1_reads += cnt.hap1_reads;
+                merged[*i].hap2_reads += cnt.hap2_reads;
+            }
+            None => {
+                merged_idx.insert(key, merged.len());
+                merged.push(cnt.clone());
+            }
+        }
+    }
+    let mut gene_reads: HashMap<(String, String, u32), (u32, u32)> = HashMap::new();
+    for cnt in merged.iter() {
+        let g = gene_reads.entry((cnt.gene_id.clone(), cnt.chromosome.clone(), cnt.phase_set)).or_insert((0, 0));
+        g.0 += cnt.hap1_reads;
+        g.1 += cnt.hap2_reads;
+    }
+    for cnt in merged.iter_mut() {
+        let g = gene_reads.get(&(cnt.gene_id.clone(), cnt.chromosome.clone(), cnt.phase_set)).unwrap();
+        cnt.gene_hap1_reads = g.0;
+        cnt.gene_hap2_reads = g.1;
+        let table = [cnt.hap1_reads, cnt.hap2_reads, g.0 - cnt.hap1_reads, g.1 - cnt.hap2_reads];
+        cnt.pvalue = fishers_exact(&table).unwrap().two_tail_pvalue.min(1.0);
+    }
+    let qvalues = benjamini_hochberg(&merged.iter().map(|c| c.pvalue).collect());
+    for (cnt, q) in merged.iter_mut().zip(qvalues.into_iter()) {
+        cnt.qvalue = q;
+    }
+    return merged;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(isoform_id: &str, phase_set: u32, hap1_reads: u32, hap2_reads: u32) -> IsoformCount {
+        return IsoformCount {
+            gene_id: "gene1".to_string(),
+            isoform_id: isoform_id.to_string(),
+            chromosome: "chr1".to_string(),
+            phase_set,
+            hap1_reads,
+            hap2_reads,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn isoform_counts_are_merged_across_regions() {
+        let mut counts: VecDeque<IsoformCount> = VecDeque::new();
+        counts.push_back(count("iso_a", 101, 10, 0));
+        // the same isoform counted by a second region whose phase set is linked with flipped haplotypes
+        counts.push_back(count("iso_a", 2001, 1, 5));
+        counts.push_back(count("iso_b", 101, 1, 12));
+        counts.push_back(count("iso_a", 9001, 3, 3));
+        let mut links: HashMap<(String, u32), PhaseSetLink> = HashMap::new();
+        links.insert(("chr1".to_string(), 2001), PhaseSetLink { phase_set: 101, flip: true });
+        relabel_isoform_counts(&mut counts, &links);
+
+        let merged = test_isoform_usage(&counts);
+        assert_eq!(merged.len(), 3);
+        let iso_a = merged.iter().find(|c| c.isoform_id == "iso_a" && c.phase_set == 101).unwrap();
+        assert_eq!((iso_a.hap1_reads, iso_a.hap2_reads), (15, 1));
+        assert_eq!((iso_a.gene_hap1_reads, iso_a.gene_hap2_reads), (16, 13));
+        assert!(iso_a.pvalue < 1e-4 && iso_a.qvalue >= iso_a.pvalue);
+        // a phase set with a single isoform has nothing to compare against
+        let other = merged.iter().find(|c| c.phase_set == 9001).unwrap();
+        assert_eq!((other.gene_hap1_reads, other.gene_hap2_reads), (3, 3));
+        assert_eq!(other.pvalue, 1.0);
+    }
+}
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 
-use clap::{ArgAction, Parser, ValueEnum};
+use clap::{ArgAction, CommandFactory, Parser, ValueEnum};
+use clap::error::ErrorKind;
 use rand::seq::SliceRandom;
 use rust_htslib::bam::Read;
 
-use crate::thread::multithread_phase_haplotag;
+use crate::thread::{multithread_phase_haplotag, PhaseOptions};
 use crate::util::*;
 
 mod snp;
@@ -16,6 +17,18 @@ mod thread;
 mod vcf;
 mod candidate;
 mod fragment;
+mod indel;
+mod mnv;
+mod haplotag;
+mod dp_phase;
+mod phase_link;
+mod polyploid;
+mod heteroplasmy;
+mod ase;
+mod isoform;
+mod splicing;
+mod transcript_end;
+mod monoallelic;
 
 #[derive(clap::ValueEnum, Debug, Clone)]
 pub enum Preset {
@@ -35,6 +48,31 @@ pub enum Platform {
     ont, // Oxford Nanopore long-read RNA sequencing
 }
 
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum PhasingAlgorithm {
+    heuristic,
+    // haplotype enumeration or random restarts with block flips
+    dp, // dynamic programming over read bipartitions, deterministic
+}
+
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum AseModel {
+    binomial,
+    betabinomial, // overdispersion estimated from the phased SNPs of the sample
+}
+
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum ExonFormat {
+    bed,
+    gtf,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum Sex {
+    female,
+    male, // chrX and chrY are haploid outside the pseudoautosomal regions
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -86,6 +124,74 @@ struct Args {
     #[arg(long, default_value_t = 0.2)]
     random_flip_fraction: f32,
 
+    /// Phasing algorithm, choices: heuristic, dp
+    #[arg(long, value_enum, default_value_t = PhasingAlgorithm::heuristic)]
+    phasing_algorithm: PhasingAlgorithm,
+
+    /// Maximum number of reads spanning a SNP used by the dp phasing algorithm, the most informative reads are kept.
+    /// The dp keeps every bipartition of these reads, 1 to 20
+    #[arg(long, default_value_t = 12, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=20))]
+    max_dp_coverage: usize,
+
+    /// When set, report per-SNP phase confidence (PC) aggregated over the restarts of the heuristic phasing algorithm,
+    /// not available with --phasing-algorithm dp
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    phase_ensemble: bool,
+
+    /// Minimum number of reads supporting the link of two adjacent SNPs in a phase set, weaker links split the phase set.
+    /// The default 1 keeps every link carried by an agreeing read, values above 1 also split blocks joined by fewer reads
+    #[arg(long, default_value_t = 1)]
+    min_link_reads: u32,
+
+    /// Minimum fraction of reads agreeing with the phase of two adjacent SNPs, conflicting links split the phase set
+    #[arg(long, default_value_t = 0.8)]
+    min_link_agreement: f32,
+
+    /// When set, merge phase sets of neighbouring regions linked by reads assigned in both regions
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    link_phase_set: bool,
+
+    /// When set, write the phase blocks (.phase_blocks.tsv), the phasing summary with the block N50 (.phase_stats.tsv)
+    /// and the links removed when splitting phase sets (.phase_splits.tsv)
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    phase_stats: bool,
+
+    /// Path to a phased DNA vcf used to orient and merge phase sets across regions
+    #[arg(long)]
+    dna_vcf: Option<String>,
+
+    /// Path to the paternal vcf, with --maternal-vcf orients hap1 to the paternal haplotype and flags Mendelian inconsistent variants
+    #[arg(long)]
+    paternal_vcf: Option<String>,
+
+    /// Path to the maternal vcf, with --paternal-vcf orients hap2 to the maternal haplotype
+    #[arg(long)]
+    maternal_vcf: Option<String>,
+
+    /// Ploidy of regions not listed in --ploidy-bed, regions with ploidy above 2 are phased into that many haplotypes, at least 1
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    ploidy: u32,
+
+    /// Path to a bed file with the ploidy of contigs or regions in the 4th column, overrides the defaults of sex chromosomes and chrM
+    #[arg(long)]
+    ploidy_bed: Option<String>,
+
+    /// Sex of the sample, choices: female, male. chrX and chrY are haploid for male samples except the GRCh37/GRCh38 pseudoautosomal regions, chrM is always haploid
+    #[arg(long, value_enum)]
+    sex: Option<Sex>,
+
+    /// When set, chrM is not genotyped and phased but scanned for heteroplasmies, written to a heteroplasmy table
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    heteroplasmy: bool,
+
+    /// Minimum allele fraction of a heteroplasmy, the detection limit
+    #[arg(long, default_value_t = 0.01)]
+    min_heteroplasmy_frac: f32,
+
+    /// Minimum number of reads supporting a heteroplasmy
+    #[arg(long, default_value_t = 5)]
+    min_heteroplasmy_cnt: u32,
+
     /// Minimum mapping quality for reads
     #[arg(long, default_value_t = 20)]
     min_mapq: u8,
@@ -198,6 +304,42 @@ struct Args {
     #[arg(long, default_value_t = 2.0)]
     imbalance_allele_expression_cutoff: f32,
 
+    /// When set, test genes for allele-specific expression and write the ASE table
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    ase: bool,
+
+    /// Maximum q-value (Benjamini-Hochberg) of a gene with imbalanced allele expression in the ASE table
+    #[arg(long, default_value_t = 0.05)]
+    max_ase_qvalue: f64,
+
+    /// Model of the allele-specific expression test, choices: binomial, betabinomial
+    #[arg(long, value_enum, default_value_t = AseModel::binomial)]
+    ase_model: AseModel,
+
+    /// Path to a read to isoform mapping of an isoform classifier (read name, isoform id, optional gene id), for haplotype-specific isoform usage
+    #[arg(long)]
+    read_isoform: Option<String>,
+
+    /// Read tag of the isoform id in the bam, used instead of --read-isoform for haplotype-specific isoform usage
+    #[arg(long)]
+    isoform_tag: Option<String>,
+
+    /// Maximum q-value (Benjamini-Hochberg) of a haplotype-specific isoform in the isoform table
+    #[arg(long, default_value_t = 0.05)]
+    max_isoform_qvalue: f64,
+
+    /// When set, test splice junctions for differential usage between haplotypes
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    haplotype_splicing: bool,
+
+    /// Maximum q-value (Benjamini-Hochberg) of a differentially spliced junction
+    #[arg(long, default_value_t = 0.05)]
+    max_splicing_qvalue: f64,
+
+    /// Minimum difference of percent spliced in between haplotypes of a differentially spliced junction
+    #[arg(long, default_value_t = 0.1)]
+    min_delta_psi: f64,
+
     // /// Allele-specific expression allele fraction cutoff
     // #[arg(long, default_value_t = 0.10)]
     // ase_allele_frac_cutoff: f32,
@@ -222,6 +364,26 @@ struct Args {
     #[arg(long, default_value_t = 2)]
     somatic_allele_cnt_cutoff: u32,
 
+    /// When set, call small indels and phase them with the SNP haplotypes.
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    call_indels: bool,
+
+    /// Minimum allele frequency for candidate indels
+    #[arg(long, default_value_t = 0.20)]
+    min_indel_freq: f32,
+
+    /// Minimum number of reads supporting candidate indels
+    #[arg(long, default_value_t = 3)]
+    min_indel_cnt: u32,
+
+    /// When set, merge adjacent heterozygous SNVs in cis into MNV records.
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    merge_mnv: bool,
+
+    /// Maximum distance between adjacent SNVs merged into one MNV
+    #[arg(long, default_value_t = 2)]
+    mnv_window: u32,
+
     /// Without phasing, only using genotype probability
     #[clap(long, action = ArgAction::SetTrue, default_value = "false")]
     genotype_only: bool,
@@ -242,14 +404,62 @@ struct Args {
     #[arg(long, default_value_t = 8)]
     min_sup_haplotype_exon: u32,
 
-    /// haplotype_bam_output
+    /// Format of the track of haplotype-specific exons written next to the .haplotype_exon.tsv table, choices: bed, gtf
+    #[arg(long, value_enum, default_value_t = ExonFormat::bed)]
+    haplotype_exon_format: ExonFormat,
+
+    /// When set, cluster the 5' (TSS) and 3' (polyA) read ends of each haplotype by the ts tag strand and test alternative sites between haplotypes
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    haplotype_end_site: bool,
+
+    /// Maximum distance between read ends of the same TSS/polyA site cluster
+    #[arg(long, default_value_t = 20)]
+    end_site_distance: u32,
+
+    /// Minimum number of haplotagged reads of a TSS/polyA site cluster
+    #[arg(long, default_value_t = 3)]
+    min_end_site_reads: u32,
+
+    /// Maximum q-value (Benjamini-Hochberg) of a TSS/polyA site with differential usage between haplotypes
+    #[arg(long, default_value_t = 0.05)]
+    max_end_site_qvalue: f64,
+
+    /// When set, report genes or phase sets where one haplotype carries nearly all reads
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    monoallelic_report: bool,
+
+    /// Maximum fraction of reads on the minor haplotype of a monoallelic gene or SNP
+    #[arg(long, default_value_t = 0.05)]
+    max_monoallelic_frac: f32,
+
+    /// Minimum number of haplotagged reads to call a gene monoallelic, fewer reads are reported as low coverage
+    #[arg(long, default_value_t = 10)]
+    min_monoallelic_reads: u32,
+
+    /// Path to a list of known imprinted gene ids (first column), annotated in the monoallelic report
+    #[arg(long)]
+    imprinted_genes: Option<String>,
+
+    /// When set, split reads into hap1, hap2 and unassigned outputs instead of a single phased bam.
     #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
     haplotype_bam_output: bool,
 
+    /// When set with --haplotype-bam-output, write the hap1, hap2 and unassigned reads as fastq.
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    haplotype_fastq_output: bool,
+
+    /// minimum number of reads on each haplotype for a region to split its reads by haplotype or find haplotype-specific exons
+    #[arg(long, default_value_t = 10)]
+    min_haplotype_reads: u32,
+
     /// output read assignment
     #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
     output_read_assignment: bool,
 
+    /// When set, add assignment confidence (hc) and number of informative SNPs (hn) tags to phased reads.
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    assignment_tags: bool,
+
     // /// debug SNP
     // #[clap(long, action = ArgAction::SetTrue)]
     // debug_snp: bool,
@@ -257,10 +467,28 @@ struct Args {
     /// get blocks
     #[clap(long, action = ArgAction::SetTrue, default_value = "false")]
     debug_block: bool,
+
+    /// When set, check that every phasing step increases the probability and reaches a local optimum (slow)
+    #[arg(long, action = ArgAction::SetTrue, default_value = "false")]
+    debug_phasing: bool,
+}
+
+fn check_args(arg: &Args) -> Result<(), clap::Error> {
+    // combinations of options clap cannot express
+    if arg.phase_ensemble && matches!(arg.phasing_algorithm, PhasingAlgorithm::dp) {
+        return Err(Args::command().error(
+            ErrorKind::ArgumentConflict,
+            "--phase-ensemble needs the restarts of --phasing-algorithm heuristic, the dp algorithm has a single solution",
+        ));
+    }
+    return Ok(());
 }
 
 fn main() {
     let arg = Args::parse();
+    if let Err(e) = check_args(&arg) {
+        e.exit();
+    }
     let bam_path = arg.bam_path.as_str();
     let out_bam = (arg.output.clone() + ".phased.bam").clone();
     let out_vcf = (arg.output.clone() + ".vcf").clone();
@@ -295,6 +523,48 @@ fn main() {
     let imbalance_allele_expression_cutoff = arg.imbalance_allele_expression_cutoff;
     let somatic_allele_frac_cutoff = arg.somatic_allele_frac_cutoff;
     let somatic_allele_cnt_cutoff = arg.somatic_allele_cnt_cutoff;
+    let call_indels = arg.call_indels;
+    let min_indel_freq = arg.min_indel_freq;
+    let min_indel_cnt = arg.min_indel_cnt;
+    let mnv_window = if arg.merge_mnv { arg.mnv_window } else { 0 };
+    let assignment_tags = arg.assignment_tags;
+    let min_haplotype_reads = arg.min_haplotype_reads;
+    let haplotype_fastq_output = arg.haplotype_fastq_output;
+    let phasing_algorithm = arg.phasing_algorithm;
+    let max_dp_coverage = arg.max_dp_coverage;
+    let debug_phasing = arg.debug_phasing;
+    let phase_ensemble = arg.phase_ensemble;
+    let min_link_reads = arg.min_link_reads;
+    let min_link_agreement = arg.min_link_agreement;
+    let link_phase_set = arg.link_phase_set;
+    let phase_stats = arg.phase_stats;
+    let dna_vcf = arg.dna_vcf;
+    let paternal_vcf = arg.paternal_vcf;
+    let maternal_vcf = arg.maternal_vcf;
+    let ploidy = arg.ploidy;
+    let ploidy_bed = arg.ploidy_bed;
+    let sex = arg.sex;
+    let heteroplasmy = arg.heteroplasmy;
+    let min_heteroplasmy_frac = arg.min_heteroplasmy_frac;
+    let min_heteroplasmy_cnt = arg.min_heteroplasmy_cnt;
+    let ase = arg.ase;
+    let max_ase_qvalue = arg.max_ase_qvalue;
+    let ase_model = arg.ase_model;
+    let read_isoform = arg.read_isoform;
+    let isoform_tag = arg.isoform_tag;
+    let max_isoform_qvalue = arg.max_isoform_qvalue;
+    let haplotype_splicing = arg.haplotype_splicing;
+    let max_splicing_qvalue = arg.max_splicing_qvalue;
+    let min_delta_psi = arg.min_delta_psi;
+    let haplotype_exon_format = arg.haplotype_exon_format;
+    let haplotype_end_site = arg.haplotype_end_site;
+    let end_site_distance = arg.end_site_distance;
+    let min_end_site_reads = arg.min_end_site_reads;
+    let max_end_site_qvalue = arg.max_end_site_qvalue;
+    let monoallelic_report = arg.monoallelic_report;
+    let max_monoallelic_frac = arg.max_monoallelic_frac;
+    let min_monoallelic_reads = arg.min_monoallelic_reads;
+    let imprinted_genes = arg.imprinted_genes;
 
     let mut min_allele_freq = arg.min_allele_freq;
     let mut min_allele_freq_include_intron = arg.min_allele_freq_include_intron;
@@ -484,5 +754,82 @@ fn main() {
         min_sup_haplotype_exon,
         somatic_allele_frac_cutoff,
         somatic_allele_cnt_cutoff,
+        PhaseOptions {
+            call_indels,
+            min_indel_freq,
+            min_indel_cnt,
+            mnv_window,
+            assignment_tags,
+            min_haplotype_reads,
+            haplotype_fastq_output,
+            phasing_algorithm,
+            max_dp_coverage,
+            debug_phasing,
+            phase_ensemble,
+            min_link_reads,
+            min_link_agreement,
+            link_phase_set,
+            phase_stats,
+            dna_vcf,
+            paternal_vcf,
+            maternal_vcf,
+            ploidy,
+            ploidy_bed,
+            sex,
+            heteroplasmy,
+            min_heteroplasmy_frac,
+            min_heteroplasmy_cnt,
+            ase,
+            max_ase_qvalue,
+            ase_model,
+            read_isoform,
+            isoform_tag,
+            max_isoform_qvalue,
+            haplotype_splicing,
+            max_splicing_qvalue,
+            min_delta_psi,
+            haplotype_exon_format,
+            haplotype_end_site,
+            end_site_distance,
+            min_end_site_reads,
+            max_end_site_qvalue,
+            monoallelic_report,
+            max_monoallelic_frac,
+            min_monoallelic_reads,
+            imprinted_genes,
+        },
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ploidy_below_one_is_rejected() {
+        let base = ["longcallR", "-b", "in.bam", "-f", "ref.fa", "-o", "out", "-p", "ont"];
+        assert!(Args::try_parse_from(base.iter().chain(["--ploidy", "0"].iter())).is_err());
+        assert_eq!(Args::try_parse_from(base.iter().chain(["--ploidy", "1"].iter())).unwrap().ploidy, 1);
+        assert_eq!(Args::try_parse_from(base.iter()).unwrap().ploidy, 2);
+    }
+
+    #[test]
+    fn max_dp_coverage_is_bounded() {
+        let base = ["longcallR", "-b", "in.bam", "-f", "ref.fa", "-o", "out", "-p", "ont"];
+        assert!(Args::try_parse_from(base.iter().chain(["--max-dp-coverage", "0"].iter())).is_err());
+        assert!(Args::try_parse_from(base.iter().chain(["--max-dp-coverage", "64"].iter())).is_err());
+        assert_eq!(Args::try_parse_from(base.iter().chain(["--max-dp-coverage", "20"].iter())).unwrap().max_dp_coverage, 20);
+        assert_eq!(Args::try_parse_from(base.iter()).unwrap().max_dp_coverage, 12);
+    }
+
+    #[test]
+    fn phase_ensemble_requires_heuristic_phasing() {
+        let base = ["longcallR", "-b", "in.bam", "-f", "ref.fa", "-o", "out", "-p", "ont", "--phase-ensemble"];
+        let arg = Args::try_parse_from(base.iter()).unwrap();
+        assert!(check_args(&arg).is_ok());
+        let arg = Args::try_parse_from(base.iter().chain(["--phasing-algorithm", "heuristic"].iter())).unwrap();
+        assert!(check_args(&arg).is_ok());
+        let arg = Args::try_parse_from(base.iter().chain(["--phasing-algorithm", "dp"].iter())).unwrap();
+        assert_eq!(check_args(&arg).unwrap_err().kind(), ErrorKind::ArgumentConflict);
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+
+use crate::snp::CandidateSNP;
+use crate::snpfrags::SNPFrag;
+use crate::vcf::VCFRecord;
+
+const MNV_MIN_ALT_LINKS: u32 = 2; // reads carrying the alternative alleles of both SNVs
+const MNV_MIN_CIS_FRAC: f32 = 0.8; // fraction of the linking reads with ref-ref or alt-alt alleles
+
+fn single_alt_allele(snp: &CandidateSNP) -> Option<(char, f32)> {
+    // alternative allele and its frequency of a bi-allelic heterozygous SNV
+    if snp.variant_type != 1 || snp.rna_editing || snp.cand_somatic {
+        return None;
+    }
+    if snp.alleles[0] == snp.reference && snp.alleles[1] != snp.reference {
+        return Some((snp.alleles[1], snp.allele_freqs[1]));
+    } else if snp.alleles[1] == snp.reference && snp.alleles[0] != snp.reference {
+        return Some((snp.alleles[0], snp.allele_freqs[0]));
+    }
+    return None;
+}
+
+impl SNPFrag {
+    fn is_cis_pair(&self, i1: usize, i2: usize, phased: bool) -> bool {
+        // two adjacent SNVs are on the same haplotype when the reads covering both of them agree
+        let snp1 = &self.candidate_snps[i1];
+        let snp2 = &self.candidate_snps[i2];
+        if single_alt_allele(snp1).is_none() || single_alt_allele(snp2).is_none() {
+            return false;
+        }
+        if snp2.pos - snp1.pos > self.mnv_window as i64 {
+            return false;
+        }
+        if phased && snp1.germline && snp2.germline {
+            if snp1.haplotype != snp2.haplotype || snp1.phase_set != snp2.phase_set {
+                return false;
+            }
+        }
+        let links = match self.mnv_links.get(&[i1, i2]) {
+            Some(v) => v,
+            None => return false,
+        };
+        let cis = links[0] + links[3];
+        let trans = links[1] + links[2];
+        if links[3] < MNV_MIN_ALT_LINKS || (cis as f32) / ((cis + trans) as f32) < MNV_MIN_CIS_FRAC {
+            return false;
+        }
+        return true;
+    }
+
+    pub fn find_mnv_groups(&self, phased: bool) -> Vec<Vec<usize>> {
+        // runs of adjacent SNVs in cis, each run with at least two SNVs is reported as one MNV
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        if self.mnv_window == 0 {
+            return groups;
+        }
+        let mut group: Vec<usize> = Vec::new();
+        for i in 1..self.candidate_snps.len() {
+            if self.is_cis_pair(i - 1, i, phased) {
+                if group.len() == 0 {
+                    group.push(i - 1);
+                }
+                group.push(i);
+            } else {
+                if group.len() >= 2 {
+                    groups.push(group.clone());
+                }
+                group.clear();
+            }
+        }
+        if group.len() >= 2 {
+            groups.push(group);
+        }
+        return groups;
+    }
+
+    pub fn output_mnv_vcf(&self, mnv_groups: &Vec<Vec<usize>>, ref_seq: &Vec<u8>, min_phase_score: f32, min_qual: u32, phased: bool) -> Vec<VCFRecord> {
+        let mut records: Vec<VCFRecord> = Vec::new();
+        for group in mnv_groups.iter() {
+            let first = &self.candidate_snps[group[0]];
+            let last = &self.candidate_snps[*group.last().unwrap()];
+            let start = first.pos as usize;
+            let end = last.pos as usize + 1;
+            let reference: Vec<u8> = ref_seq[start..end].to_ascii_uppercase();
+            let mut alternative = reference.clone();
+            let mut qual = f64::MAX;
+            let mut gq = f64::MAX;
+            let mut depth = u32::MAX;
+            let mut af = 1.0_f32;
+            let mut dense = false;
+            let mut phased_snp: Option<&CandidateSNP> = None;
+            let mut phase_score = f64::MAX;
+            for i in group.iter() {
+                let snp = &self.candidate_snps[*i];
+                let (alt, alt_freq) = single_alt_allele(snp).unwrap();
+                alternative[snp.pos as usize - start] = alt as u8;
+                qual = qual.min(snp.variant_quality);
+                gq = gq.min(snp.genotype_quality);
+                depth = depth.min(snp.depth);
+                af = af.min(alt_freq);
+                dense = dense || snp.dense;
+                if snp.germline && snp.haplotype != 0 {
+                    if phased_snp.is_none() {
+                        phased_snp = Some(snp);
+                    }
+                    phase_score = phase_score.min(snp.phase_score);
+                }
+            }
+
+            let mut rd: VCFRecord = VCFRecord::default();
+            rd.chromosome = first.chromosome.clone();
+            rd.position = first.pos as u64 + 1; // position in vcf format is 1-based
+            rd.id = vec!['.' as u8];
+            rd.reference = reference;
+            rd.alternative = vec![alternative];
+            rd.qual = qual as i32;
+            if qual < min_qual as f64 {
+                rd.filter = "LowQual".to_string().into_bytes();
+            } else if dense {
+                rd.filter = "dn".to_string().into_bytes();
+            } else if phased && (phased_snp.is_none() || phase_score < min_phase_score as f64) {
+                rd.filter = "LowQual".to_string().into_bytes();
+            } else {
+                rd.filter = "PASS".to_string().into_bytes();
+            }
+            rd.info = format!("RDS=.;MNV={}", group.len()).into_bytes();
+            if phased && phased_snp.is_some() {
+                let snp = phased_snp.unwrap();
+                let gt = if snp.haplotype == -1 { "0|1" } else { "1|0" };
+                if snp.phase_set != 0 {
+                    rd.genotype = format!("{}:{}:{}:{}:{:.2}:{:.2}", gt, snp.phase_set, gq as i32, depth, af, phase_score);
+                    rd.format = "GT:PS:GQ:DP:AF:PQ".to_string().into_bytes();
+                } else {
+                    rd.genotype = format!("{}:{}:{}:{:.2}:{:.2}", gt, gq as i32, depth, af, phase_score);
+                    rd.format = "GT:GQ:DP:AF:PQ".to_string().into_bytes();
+                }
+            } else {
+                rd.genotype = format!("{}:{}:{}:{:.2}", "0/1", gq as i32, depth, af);
+                rd.format = "GT:GQ:DP:AF".to_string().into_bytes();
+            }
+            records.push(rd);
+        }
+        return records;
+    }
+
+    pub fn mnv_members(mnv_groups: &Vec<Vec<usize>>) -> HashSet<usize> {
+        let mut members: HashSet<usize> = HashSet::new();
+        for group in mnv_groups.iter() {
+            members.extend(group.iter());
+        }
+        return members;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ref_seq() -> Vec<u8> {
+        return b"AAAAACCCCCGGGGGTTTTT".to_vec();
+    }
+
+    fn snv(pos: i64, alt: char) -> CandidateSNP {
+        let mut snp = CandidateSNP::default();
+        snp.chromosome = b"chr1".to_vec();
+        snp.pos = pos;
+        snp.reference = ref_seq()[pos as usize] as char;
+        snp.alleles = [snp.reference, alt];
+        snp.allele_freqs = [0.5, 0.5];
+        snp.variant_type = 1;
+        snp.variant_quality = 30.0;
+        snp.genotype_quality = 30.0;
+        snp.depth = 10;
+        return snp;
+    }
+
+    fn mnv_snpfrag(snps: Vec<CandidateSNP>, links: Vec<[u32; 4]>) -> SNPFrag {
+        // links of each pair of adjacent SNVs: ref-ref, ref-alt, alt-ref, alt-alt
+        let mut snpfrag = SNPFrag::default();
+        snpfrag.mnv_window = 5;
+        snpfrag.candidate_snps = snps;
+        for (i, l) in links.iter().enumerate() {
+            snpfrag.mnv_links.insert([i, i + 1], *l);
+        }
+        return snpfrag;
+    }
+
+    #[test]
+    fn cis_pair_is_merged() {
+        let snpfrag = mnv_snpfrag(vec![snv(5, 'T'), snv(7, 'G')], vec![[5, 0, 0, 5]]);
+        let groups = snpfrag.find_mnv_groups(false);
+        assert_eq!(groups, vec![vec![0, 1]]);
+        let records = snpfrag.output_mnv_vcf(&groups, &ref_seq(), 10.0, 10, false);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].position, 6);
+        assert_eq!(records[0].reference, b"CCC".to_vec());
+        assert_eq!(records[0].alternative, vec![b"TCG".to_vec()]);
+        assert_eq!(records[0].filter, b"PASS".to_vec());
+        assert_eq!(records[0].genotype, "0/1:30:10:0.50");
+    }
+
+    #[test]
+    fn trans_pair_is_not_merged() {
+        let snpfrag = mnv_snpfrag(vec![snv(5, 'T'), snv(7, 'G')], vec![[0, 5, 5, 0]]);
+        assert!(snpfrag.find_mnv_groups(false).is_empty());
+        // a single read with both alternative alleles is not enough
+        let snpfrag = mnv_snpfrag(vec![snv(5, 'T'), snv(7, 'G')], vec![[5, 0, 0, 1]]);
+        assert!(snpfrag.find_mnv_groups(false).is_empty());
+        // too far apart
+        let mut snpfrag = mnv_snpfrag(vec![snv(5, 'T'), snv(11, 'A')], vec![[5, 0, 0, 5]]);
+        assert!(snpfrag.find_mnv_groups(false).is_empty());
+        snpfrag.mnv_window = 0;
+        assert!(snpfrag.find_mnv_groups(false).is_empty());
+    }
+
+    #[test]
+    fn run_is_broken_by_non_het_snp() {
+        let mut hom = snv(9, 'A');
+        hom.alleles = ['A', 'A'];
+        hom.allele_freqs = [1.0, 0.0];
+        hom.variant_type = 2;
+        let snps = vec![snv(5, 'T'), snv(7, 'G'), hom, snv(11, 'A'), snv(12, 'C')];
+        let snpfrag = mnv_snpfrag(snps, vec![[5, 0, 0, 5]; 4]);
+        assert_eq!(snpfrag.find_mnv_groups(false), vec![vec![0, 1], vec![3, 4]]);
+        assert_eq!(SNPFrag::mnv_members(&snpfrag.find_mnv_groups(false)).len(), 4);
+    }
+
+    #[test]
+    fn phased_mnv_carries_genotype_and_phase_set() {
+        let mut snps = vec![snv(5, 'T'), snv(7, 'G')];
+        for snp in snps.iter_mut() {
+            snp.germline = true;
+            snp.haplotype = -1;
+            snp.phase_set = 6;
+            snp.phase_score = 40.0;
+        }
+        snps[1].phase_score = 25.0;
+        let snpfrag = mnv_snpfrag(snps.clone(), vec![[5, 0, 0, 5]]);
+        let groups = snpfrag.find_mnv_groups(true);
+        let records = snpfrag.output_mnv_vcf(&groups, &ref_seq(), 10.0, 10, true);
+        assert_eq!(records[0].format, b"GT:PS:GQ:DP:AF:PQ".to_vec());
+        assert_eq!(records[0].genotype, "0|1:6:30:10:0.50:25.00");
+        assert_eq!(records[0].filter, b"PASS".to_vec());
+
+        // SNVs on different haplotypes are not merged once phased
+        snps[1].haplotype = 1;
+        let snpfrag = mnv_snpfrag(snps, vec![[5, 0, 0, 5]]);
+        assert!(snpfrag.find_mnv_groups(true).is_empty());
+        assert_eq!(snpfrag.find_mnv_groups(false), vec![vec![0, 1]]);
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::ase::AseUnit;
+
+pub fn load_imprinted_genes(imprinted_gene_file: &str) -> HashSet<String> {
+    // gene ids of known imprinted genes, one per line in the first column
+    let mut genes: HashSet<String> = HashSet::new();
+    let reader = BufReader::new(File::open(imprinted_gene_file).unwrap());
+    for line in reader.lines() {
+        let line = line.unwrap();
+        if line.starts_with("#") || line.trim().is_empty() {
+            continue;
+        }
+        genes.insert(line.split_whitespace().next().unwrap().to_string());
+    }
+    return genes;
+}
+
+pub fn is_imprinted(gene_id: &str, imprinted_genes: &HashSet<String>) -> bool {
+    // gene ids are also matched without the version suffix, e.g. ENSG00000130600.19
+    if imprinted_genes.contains(gene_id) {
+        return true;
+    }
+    return match gene_id.rsplit_once('.') {
+        Some((id, _)) => imprinted_genes.contains(id),
+        None => false,
+    };
+}
+
+pub fn dominant_haplotype(unit: &AseUnit) -> i32 {
+    // haplotype carrying the most reads of the unit, 0 if tied
+    if unit.hap1_reads > unit.hap2_reads {
+        return 1;
+    } else if unit.hap2_reads > unit.hap1_reads {
+        return 2;
+    }
+    return 0;
+}
+
+pub fn monoallelic_status(unit: &AseUnit, max_monoallelic_frac: f32, min_monoallelic_reads: u32) -> &'static str {
+    // monoallelic: one haplotype carries nearly all reads and dominates every phased SNP,
+    // inconsistent: nearly all reads on one haplotype but some SNPs are biallelic or dominated by the other haplotype,
+    // low_coverage: too few reads to tell a monoallelic gene from sampling, biallelic: otherwise
+    let total = unit.hap1_reads + unit.hap2_reads;
+    let minor = unit.hap1_reads.min(unit.hap2_reads);
+    if total < min_monoallelic_reads {
+        return "low_coverage";
+    }
+    if minor as f32 > max_monoallelic_frac * total as f32 {
+        return "biallelic";
+    }
+    let dominant_snps = if dominant_haplotype(unit) == 1 { unit.hap1_dominant_snps } else { unit.hap2_dominant_snps };
+    if dominant_snps < unit.num_snps {
+        return "inconsistent";
+    }
+    return "monoallelic";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(hap1_reads: u32, hap2_reads: u32, hap1_dominant_snps: u32, hap2_dominant_snps: u32) -> AseUnit {
+        return AseUnit { gene_id: "gene1".to_string(), num_snps: 3, hap1_reads, hap2_reads, hap1_dominant_snps, hap2_dominant_snps, ..Default::default() };
+    }
+
+    #[test]
+    fn monoallelic_status_of_each_branch() {
+        assert_eq!(monoallelic_status(&unit(5, 0, 3, 0), 0.1, 10), "low_coverage");
+        assert_eq!(monoallelic_status(&unit(15, 5, 1, 0), 0.1, 10), "biallelic");
+        assert_eq!(monoallelic_status(&unit(20, 1, 2, 0), 0.1, 10), "inconsistent");
+        assert_eq!(monoallelic_status(&unit(20, 1, 3, 0), 0.1, 10), "monoallelic");
+        assert_eq!(monoallelic_status(&unit(0, 30, 0, 3), 0.1, 10), "monoallelic");
+        // the dominant SNPs of the minor haplotype do not count
+        assert_eq!(monoallelic_status(&unit(0, 30, 3, 1), 0.1, 10), "inconsistent");
+        // the minor haplotype at exactly the monoallelic fraction
+        assert_eq!(monoallelic_status(&unit(18, 2, 3, 0), 0.1, 10), "monoallelic");
+        assert_eq!(dominant_haplotype(&unit(4, 4, 0, 0)), 0);
+    }
+
+    #[test]
+    fn imprinted_gene_ids_match_without_version() {
+        let imprinted: HashSet<String> = ["ENSG00000130600".to_string(), "PEG10".to_string()].into_iter().collect();
+        assert!(is_imprinted("ENSG00000130600.19", &imprinted));
+        assert!(is_imprinted("ENSG00000130600", &imprinted));
+        assert!(is_imprinted("PEG10", &imprinted));
+        assert!(!is_imprinted("ENSG00000130601.2", &imprinted));
+        assert!(!is_imprinted("PEG1", &imprinted));
+
+        let path = std::env::temp_dir().join(format!("longcallR_imprinted_{}.txt", std::process::id()));
+        std::fs::write(&path, "#gene_id\tname\nENSG00000130600\tH19\n\nPEG10 paternal\n").unwrap();
+        let loaded = load_imprinted_genes(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, imprinted);
+    }
+}
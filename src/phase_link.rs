@@ -0,0 +1,619 @@
+use std::collections::{HashMap, VecDeque};
+
+use rust_htslib::bcf;
+use rust_htslib::bcf::record::GenotypeAllele;
+use rust_htslib::bcf::Read;
+
+use crate::haplotag::ReadAssignment;
+use crate::snp::PhaseBlock;
+use crate::vcf::VCFRecord;
+
+#[derive(Debug, Clone, Default)]
+pub struct PhaseSetLink {
+    pub phase_set: u32,
+    // merged phase set id, the smallest SNP position of the linked phase sets (1-based)
+    pub flip: bool,
+    // hap1 and hap2 are swapped to match the orientation of the merged phase set or of the DNA haplotypes
+}
+
+fn find(parent: &mut Vec<(usize, bool)>, x: usize) -> (usize, bool) {
+    // root of x and whether x is flipped relative to the root
+    let (p, flip) = parent[x];
+    if p == x {
+        return (x, false);
+    }
+    let (root, root_flip) = find(parent, p);
+    parent[x] = (root, flip ^ root_flip);
+    return (root, flip ^ root_flip);
+}
+
+fn vcf_phase(rd: &VCFRecord) -> Option<(u32, bool)> {
+    // phase set and whether the alternative allele is on the first haplotype of a phased record
+    let keys: Vec<&str> = std::str::from_utf8(&rd.format).unwrap().split(':').collect();
+    let values: Vec<&str> = rd.genotype.split(':').collect();
+    if keys.len() < 2 || keys[0] != "GT" || keys[1] != "PS" || values.len() < 2 {
+        return None;
+    }
+    let alleles: Vec<&str> = values[0].split('|').collect();
+    if alleles.len() != 2 || alleles[0] == alleles[1] {
+        return None;
+    }
+    return Some((values[1].parse::<u32>().unwrap(), alleles[0] != "0"));
+}
+
+fn load_dna_phase(dna_vcf: &str) -> HashMap<(String, u64, Vec<u8>, Vec<u8>), (u32, bool)> {
+    // phased heterozygous variants of the DNA vcf, key is chromosome, 1-based position, reference and alternative allele.
+    // Variants phased without PS belong to one phase set per chromosome.
+    let mut dna_phase = HashMap::new();
+    let mut reader = bcf::Reader::from_path(dna_vcf).unwrap();
+    let header = reader.header().clone();
+    for r in reader.records() {
+        let record = r.unwrap();
+        if record.allele_count() != 2 {
+            continue;
+        }
+        let gts = record.genotypes().unwrap();
+        let gt = gts.get(0);
+        if gt.len() != 2 {
+            continue;
+        }
+        let (a0, a1) = match (gt[0], gt[1]) {
+            (GenotypeAllele::Unphased(a0), GenotypeAllele::Phased(a1)) => (a0, a1),
+            (GenotypeAllele::Phased(a0), GenotypeAllele::Phased(a1)) => (a0, a1),
+            _ => continue,
+        };
+        if a0 == a1 {
+            continue;
+        }
+        let ps = match record.format(b"PS").integer() {
+            Ok(v) => if v[0].len() > 0 && v[0][0] >= 0 { v[0][0] as u32 } else { 0 },
+            Err(_) => 0,
+        };
+        let chr = std::str::from_utf8(header.rid2name(record.rid().unwrap()).unwrap()).unwrap().to_string();
+        let alleles = record.alleles();
+        dna_phase.insert((chr, record.pos() as u64 + 1, alleles[0].to_vec(), alleles[1].to_vec()), (ps, a0 != 0));
+    }
+    return dna_phase;
+}
+
+pub fn link_phase_sets(
+    read_assignments: &VecDeque<ReadAssignment>,
+    vcf_records: &VecDeque<VCFRecord>,
+    dna_vcf: Option<String>,
+    min_link_reads: u32,
+    min_link_agreement: f32,
+) -> HashMap<(String, u32), PhaseSetLink> {
+    // Phase sets of different regions are linked by reads assigned in both regions, and by the phased heterozygous variants
+    // shared with a DNA vcf. Links are kept when they are supported by enough agreeing evidence, the strongest links first.
+    let mut node_idx: HashMap<(String, String, u32), usize> = HashMap::new(); // chromosome, source ("rna" or "dna"), phase set
+    let mut nodes: Vec<(String, String, u32)> = Vec::new();
+    let mut votes: HashMap<(usize, usize), [u32; 2]> = HashMap::new(); // same orientation, opposite orientation
+    let mut node_of = |key: (String, String, u32), nodes: &mut Vec<(String, String, u32)>| -> usize {
+        if let Some(i) = node_idx.get(&key) {
+            return *i;
+        }
+        node_idx.insert(key.clone(), nodes.len());
+        nodes.push(key);
+        return nodes.len() - 1;
+    };
+
+    // reads assigned in several regions
+    let mut read_phases: HashMap<String, Vec<(String, u32, i32)>> = HashMap::new();
+    for asg in read_assignments.iter() {
+        if asg.haplotype == 0 || asg.phase_set == 0 || asg.ploidy != 2 {
+            // phase sets of polyploid regions are not linked
+            continue;
+        }
+        let chr = asg.region.rsplit_once(':').unwrap().0.to_string();
+        read_phases.entry(asg.read_id.clone()).or_insert(Vec::new()).push((chr, asg.phase_set, asg.haplotype));
+    }
+    for (_, phases) in read_phases.iter() {
+        for a in 0..phases.len() {
+            for b in a + 1..phases.len() {
+                if phases[a].0 != phases[b].0 || phases[a].1 == phases[b].1 {
+                    continue;
+                }
+                let u = node_of((phases[a].0.clone(), "rna".to_string(), phases[a].1), &mut nodes);
+                let v = node_of((phases[b].0.clone(), "rna".to_string(), phases[b].1), &mut nodes);
+                let vote = votes.entry((u.min(v), u.max(v))).or_insert([0, 0]);
+                if phases[a].2 == phases[b].2 {
+                    vote[0] += 1;
+                } else {
+                    vote[1] += 1;
+                }
+            }
+        }
+    }
+
+    // phased variants shared with the DNA vcf
+    if dna_vcf.is_some() {
+        let dna_phase = load_dna_phase(dna_vcf.unwrap().as_str());
+        for rd in vcf_records.iter() {
+            if rd.alternative.len() != 1 {
+                continue;
+            }
+            let (ps, alt_first) = match vcf_phase(rd) {
+                Some(v) => v,
+                None => continue,
+            };
+            let chr = std::str::from_utf8(&rd.chromosome).unwrap().to_string();
+            let key = (chr.clone(), rd.position, rd.reference.clone(), rd.alternative[0].clone());
+            if let Some((dna_ps, dna_alt_first)) = dna_phase.get(&key) {
+                let u = node_of((chr.clone(), "rna".to_string(), ps), &mut nodes);
+                let v = node_of((chr.clone(), "dna".to_string(), *dna_ps), &mut nodes);
+                let vote = votes.entry((u.min(v), u.max(v))).or_insert([0, 0]);
+                if alt_first == *dna_alt_first {
+                    vote[0] += 1;
+                } else {
+                    vote[1] += 1;
+                }
+            }
+        }
+    }
+
+    let mut links: Vec<((usize, usize), [u32; 2])> = votes.into_iter().collect();
+    links.sort_by(|a, b| b.1[0].max(b.1[1]).cmp(&a.1[0].max(a.1[1])).then(a.0.cmp(&b.0)));
+    let mut parent: Vec<(usize, bool)> = (0..nodes.len()).map(|i| (i, false)).collect();
+    for ((u, v), vote) in links.iter() {
+        let support = vote[0].max(vote[1]);
+        if support < min_link_reads || (support as f32) / ((vote[0] + vote[1]) as f32) < min_link_agreement {
+            continue;
+        }
+        let flip = vote[1] > vote[0];
+        let (ru, fu) = find(&mut parent, *u);
+        let (rv, fv) = find(&mut parent, *v);
+        if ru == rv {
+            // already linked, a contradicting link is ignored
+            continue;
+        }
+        parent[rv] = (ru, fu ^ fv ^ flip);
+    }
+
+    // smallest SNP position of each phase set, the merged phase set id is the smallest SNP position of the merged set
+    let mut first_pos: HashMap<(String, u32), u32> = HashMap::new();
+    for rd in vcf_records.iter() {
+        if let Some((ps, _)) = vcf_phase(rd) {
+            let chr = std::str::from_utf8(&rd.chromosome).unwrap().to_string();
+            let pos = first_pos.entry((chr, ps)).or_insert(rd.position as u32);
+            *pos = (*pos).min(rd.position as u32);
+        }
+    }
+
+    // Groups linked to the DNA vcf follow the orientation of the DNA haplotypes, other groups the orientation of the
+    // phase set with the smallest id.
+    let mut group_ps: HashMap<usize, u32> = HashMap::new();
+    let mut group_orient: HashMap<usize, (bool, u32, bool)> = HashMap::new(); // from dna, phase set, flip of the reference phase set
+    for i in 0..nodes.len() {
+        let (root, flip) = find(&mut parent, i);
+        let from_dna = nodes[i].1 == "dna";
+        if !from_dna {
+            let pos = *first_pos.get(&(nodes[i].0.clone(), nodes[i].2)).unwrap_or(&nodes[i].2);
+            let ps = group_ps.entry(root).or_insert(pos);
+            *ps = (*ps).min(pos);
+        }
+        let orient = group_orient.entry(root).or_insert((from_dna, nodes[i].2, flip));
+        if (from_dna && !orient.0) || (from_dna == orient.0 && nodes[i].2 < orient.1) {
+            *orient = (from_dna, nodes[i].2, flip);
+        }
+    }
+
+    // merged phase set ids must be unique on each contig. The ids of phase sets not linked to any other phase set are kept,
+    // a group whose id is already used by another phase set or group is left unmerged.
+    let mut owner: HashMap<(String, u32), usize> = HashMap::new();
+    for (chr, ps) in first_pos.keys() {
+        if !node_idx.contains_key(&(chr.clone(), "rna".to_string(), *ps)) {
+            owner.insert((chr.clone(), *ps), usize::MAX);
+        }
+    }
+    let mut collided: Vec<usize> = Vec::new();
+    let mut roots: Vec<(String, usize)> = Vec::new();
+    for i in 0..nodes.len() {
+        let (root, _) = find(&mut parent, i);
+        if nodes[i].1 == "rna" && !roots.contains(&(nodes[i].0.clone(), root)) {
+            roots.push((nodes[i].0.clone(), root));
+        }
+    }
+    roots.sort_by(|a, b| a.0.cmp(&b.0).then(group_ps[&a.1].cmp(&group_ps[&b.1])).then(a.1.cmp(&b.1)));
+    for (chr, root) in roots.iter() {
+        let key = (chr.clone(), group_ps[root]);
+        if owner.contains_key(&key) {
+            collided.push(*root);
+        } else {
+            owner.insert(key, *root);
+        }
+    }
+
+    let mut phase_set_links: HashMap<(String, u32), PhaseSetLink> = HashMap::new();
+    for i in 0..nodes.len() {
+        if nodes[i].1 != "rna" {
+            continue;
+        }
+        let (root, flip) = find(&mut parent, i);
+        if collided.contains(&root) {
+            continue;
+        }
+        let ps = group_ps[&root];
+        let flip = flip != group_orient[&root].2;
+        if ps == nodes[i].2 && !flip {
+            continue;
+        }
+        phase_set_links.insert((nodes[i].0.clone(), nodes[i].2), PhaseSetLink { phase_set: ps, flip: flip });
+    }
+    return phase_set_links;
+}
+
+pub fn relabel_vcf_record(rd: &mut VCFRecord, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    let (ps, _) = match vcf_phase(rd) {
+        Some(v) => v,
+        None => return,
+    };
+    let chr = std::str::from_utf8(&rd.chromosome).unwrap().to_string();
+    let link = match phase_set_links.get(&(chr, ps)) {
+        Some(v) => v,
+        None => return,
+    };
+    let keys: Vec<String> = std::str::from_utf8(&rd.format).unwrap().split(':').map(|x| x.to_string()).collect();
+    let mut values: Vec<String> = rd.genotype.split(':').map(|x| x.to_string()).collect();
+    for (f, key) in keys.iter().enumerate() {
+        if key == "PS" {
+            values[f] = link.phase_set.to_string();
+        } else if key == "GT" && link.flip {
+            let alleles: Vec<&str> = values[f].split('|').collect();
+            values[f] = format!("{}|{}", alleles[1], alleles[0]);
+        } else if key == "AE" && link.flip {
+            // hap1_ref, hap1_alt, hap2_ref, hap2_alt
+            let ae: Vec<&str> = values[f].split(',').collect();
+            values[f] = format!("{},{},{},{}", ae[2], ae[3], ae[0], ae[1]);
+        }
+    }
+    rd.genotype = values.join(":");
+}
+
+pub fn relabel_read_assignment(asg: &mut ReadAssignment, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    if asg.phase_set == 0 || asg.ploidy != 2 {
+        return;
+    }
+    let chr = asg.region.rsplit_once(':').unwrap().0.to_string();
+    if let Some(link) = phase_set_links.get(&(chr, asg.phase_set)) {
+        asg.phase_set = link.phase_set;
+        if link.flip && asg.haplotype != 0 {
+            asg.haplotype = 3 - asg.haplotype;
+        }
+    }
+}
+
+pub fn merge_phase_blocks(phase_blocks: &mut VecDeque<PhaseBlock>, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    // blocks of linked phase sets are merged, reads are counted once per merged block
+    let mut merged_blocks: Vec<PhaseBlock> = Vec::new();
+    let mut merged_idx: HashMap<(String, u32), usize> = HashMap::new();
+    for block in phase_blocks.drain(..) {
+        let ps = match phase_set_links.get(&(block.chromosome.clone(), block.phase_set)) {
+            Some(link) => link.phase_set,
+            None => block.phase_set,
+        };
+        match merged_idx.get(&(block.chromosome.clone(), ps)) {
+            Some(i) => {
+                let merged = &mut merged_blocks[*i];
+                merged.mean_phase_score = (merged.mean_phase_score * merged.num_snps as f64 + block.mean_phase_score * block.num_snps as f64) / (merged.num_snps + block.num_snps) as f64;
+                merged.start = merged.start.min(block.start);
+                merged.end = merged.end.max(block.end);
+                merged.num_snps += block.num_snps;
+                merged.read_ids.extend(block.read_ids.into_iter());
+                merged.num_reads = merged.read_ids.len() as u32;
+            }
+            None => {
+                let mut merged = block;
+                merged.phase_set = ps;
+                merged_idx.insert((merged.chromosome.clone(), ps), merged_blocks.len());
+                merged_blocks.push(merged);
+            }
+        }
+    }
+    phase_blocks.extend(merged_blocks.into_iter());
+}
+
+fn genotype_alt_count(gt: &[GenotypeAllele]) -> Option<u8> {
+    // number of alternative alleles of a bi-allelic genotype, None if missing
+    let mut cnt = 0;
+    for a in gt.iter() {
+        match a.index() {
+            Some(0) => {}
+            Some(_) => cnt += 1,
+            None => return None,
+        }
+    }
+    return Some(cnt);
+}
+
+fn load_genotypes(vcf: &str) -> HashMap<(String, u64, Vec<u8>, Vec<u8>), u8> {
+    // alternative allele count of the bi-allelic variants of the first sample, key is chromosome, 1-based position, reference and alternative allele
+    let mut genotypes = HashMap::new();
+    let mut reader = bcf::Reader::from_path(vcf).unwrap();
+    let header = reader.header().clone();
+    for r in reader.records() {
+        let record = r.unwrap();
+        if record.allele_count() != 2 {
+            continue;
+        }
+        let gts = record.genotypes().unwrap();
+        let gt = gts.get(0);
+        if let Some(cnt) = genotype_alt_count(&gt) {
+            let chr = std::str::from_utf8(header.rid2name(record.rid().unwrap()).unwrap()).unwrap().to_string();
+            let alleles = record.alleles();
+            genotypes.insert((chr, record.pos() as u64 + 1, alleles[0].to_vec(), alleles[1].to_vec()), cnt);
+        }
+    }
+    return genotypes;
+}
+
+fn record_key(rd: &VCFRecord) -> Option<(String, u64, Vec<u8>, Vec<u8>)> {
+    if rd.alternative.len() != 1 {
+        return None;
+    }
+    let chr = std::str::from_utf8(&rd.chromosome).unwrap().to_string();
+    return Some((chr, rd.position, rd.reference.clone(), rd.alternative[0].clone()));
+}
+
+pub struct TrioGenotypes {
+    paternal: HashMap<(String, u64, Vec<u8>, Vec<u8>), u8>,
+    maternal: HashMap<(String, u64, Vec<u8>, Vec<u8>), u8>,
+}
+
+impl TrioGenotypes {
+    pub fn load(paternal_vcf: &str, maternal_vcf: &str) -> TrioGenotypes {
+        return TrioGenotypes { paternal: load_genotypes(paternal_vcf), maternal: load_genotypes(maternal_vcf) };
+    }
+
+    fn parental_alt_count(&self, key: &(String, u64, Vec<u8>, Vec<u8>)) -> Option<(u8, u8)> {
+        // variants missing in a parental vcf are unknown, not homozygous reference
+        match (self.paternal.get(key), self.maternal.get(key)) {
+            (Some(p), Some(m)) => Some((*p, *m)),
+            _ => None,
+        }
+    }
+
+    fn paternal_alt(&self, key: &(String, u64, Vec<u8>, Vec<u8>)) -> Option<bool> {
+        // whether the alternative allele of a heterozygous child variant is inherited from the father, None if not informative
+        let (p, m) = self.parental_alt_count(key)?;
+        if p > 0 && m == 0 {
+            return Some(true);
+        } else if p == 0 && m > 0 {
+            return Some(false);
+        } else if p == 2 && m == 1 {
+            return Some(true);
+        } else if p == 1 && m == 2 {
+            return Some(false);
+        }
+        return None;
+    }
+
+    pub fn orient_phase_sets(&self, vcf_records: &VecDeque<VCFRecord>) -> HashMap<(String, u32), PhaseSetLink> {
+        // each phase set is flipped when most informative variants have the paternal allele on hap2.
+        // The alleles of the two haplotypes are taken from the haplotype expression (AE) of the variant.
+        let mut votes: HashMap<(String, u32), [u32; 2]> = HashMap::new(); // paternal allele on hap1, on hap2
+        for rd in vcf_records.iter() {
+            let key = match record_key(rd) {
+                Some(v) => v,
+                None => continue,
+            };
+            let (ps, _) = match vcf_phase(rd) {
+                Some(v) => v,
+                None => continue,
+            };
+            let keys: Vec<&str> = std::str::from_utf8(&rd.format).unwrap().split(':').collect();
+            let values: Vec<&str> = rd.genotype.split(':').collect();
+            let ae_idx = match keys.iter().position(|x| *x == "AE") {
+                Some(v) => v,
+                None => continue,
+            };
+            let ae: Vec<u32> = values[ae_idx].split(',').map(|x| x.parse::<u32>().unwrap()).collect();
+            if ae[1] + ae[2] == ae[0] + ae[3] {
+                continue;
+            }
+            let alt_on_hap1 = ae[1] + ae[2] > ae[0] + ae[3];
+            if let Some(paternal_alt) = self.paternal_alt(&key) {
+                let vote = votes.entry((key.0.clone(), ps)).or_insert([0, 0]);
+                if alt_on_hap1 == paternal_alt {
+                    vote[0] += 1;
+                } else {
+                    vote[1] += 1;
+                }
+            }
+        }
+        let mut phase_set_links: HashMap<(String, u32), PhaseSetLink> = HashMap::new();
+        for (key, vote) in votes.iter() {
+            if vote[1] > vote[0] {
+                phase_set_links.insert(key.clone(), PhaseSetLink { phase_set: key.1, flip: true });
+            }
+        }
+        return phase_set_links;
+    }
+
+    pub fn flag_mendelian_inconsistent(&self, rd: &mut VCFRecord) {
+        let key = match record_key(rd) {
+            Some(v) => v,
+            None => return,
+        };
+        let (p, m) = match self.parental_alt_count(&key) {
+            Some(v) => v,
+            None => return,
+        };
+        let gt = rd.genotype.split(':').next().unwrap().to_string();
+        if gt.split(|c| c == '/' || c == '|').count() != 2 {
+            // polyploid genotype
+            return;
+        }
+        let child = gt.matches('1').count() as u8;
+        let consistent = match child {
+            0 => p < 2 && m < 2,
+            1 => !(p == 0 && m == 0) && !(p == 2 && m == 2),
+            _ => p > 0 && m > 0,
+        };
+        if !consistent {
+            rd.info.extend(";MIE".as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phased_record(pos: u64, ps: u32) -> VCFRecord {
+        let mut rd = VCFRecord::default();
+        rd.chromosome = b"chr1".to_vec();
+        rd.position = pos;
+        rd.reference = b"A".to_vec();
+        rd.alternative = vec![b"G".to_vec()];
+        rd.format = b"GT:PS".to_vec();
+        rd.genotype = format!("0|1:{}", ps);
+        return rd;
+    }
+
+    fn assignment(read_id: &str, region: &str, phase_set: u32, haplotype: i32) -> ReadAssignment {
+        return ReadAssignment { read_id: read_id.to_string(), haplotype: haplotype, region: region.to_string(), ploidy: 2, phase_set: phase_set, ..Default::default() };
+    }
+
+    fn linking_reads(ps1: u32, ps2: u32) -> VecDeque<ReadAssignment> {
+        // two reads assigned to the same haplotype in both regions
+        let mut asgs = VecDeque::new();
+        for read_id in ["r1", "r2"] {
+            asgs.push_back(assignment(read_id, "chr1:1-1500", ps1, 1));
+            asgs.push_back(assignment(read_id, "chr1:1500-3000", ps2, 1));
+        }
+        return asgs;
+    }
+
+    #[test]
+    fn linked_phase_set_id_is_smallest_snp_position() {
+        // the phase set of the first region is labelled by its second SNP
+        let records: VecDeque<VCFRecord> = vec![phased_record(1001, 1101), phased_record(1101, 1101), phased_record(1601, 1601), phased_record(1701, 1601)].into();
+        let links = link_phase_sets(&linking_reads(1101, 1601), &records, None, 1, 0.8);
+        assert_eq!(links.len(), 2);
+        for ps in [1101, 1601] {
+            let link = &links[&("chr1".to_string(), ps)];
+            assert_eq!(link.phase_set, 1001);
+            assert!(!link.flip);
+        }
+    }
+
+    #[test]
+    fn linked_phase_set_id_is_unique_per_contig() {
+        // phase set 1001 is not linked, the merged set of 2001 and 3001 shares its first SNP and would take the same id
+        let records: VecDeque<VCFRecord> = vec![phased_record(1001, 1001), phased_record(1001, 2001), phased_record(2001, 2001), phased_record(3001, 3001)].into();
+        let links = link_phase_sets(&linking_reads(2001, 3001), &records, None, 1, 0.8);
+        assert_eq!(links.len(), 0);
+
+        // without the colliding phase set the two are merged
+        let records: VecDeque<VCFRecord> = vec![phased_record(2001, 2001), phased_record(3001, 3001)].into();
+        let links = link_phase_sets(&linking_reads(2001, 3001), &records, None, 1, 0.8);
+        assert_eq!(links[&("chr1".to_string(), 3001)].phase_set, 2001);
+    }
+
+    fn phase_block(phase_set: u32, start: i64, end: i64, reads: &[&str]) -> PhaseBlock {
+        let read_ids: std::collections::HashSet<String> = reads.iter().map(|r| r.to_string()).collect();
+        return PhaseBlock {
+            chromosome: "chr1".to_string(),
+            phase_set,
+            start,
+            end,
+            num_snps: 2,
+            num_reads: read_ids.len() as u32,
+            mean_phase_score: 30.0,
+            read_ids,
+        };
+    }
+
+    #[test]
+    fn merged_phase_block_counts_each_read_once() {
+        let mut blocks: VecDeque<PhaseBlock> = VecDeque::new();
+        blocks.push_back(phase_block(101, 100, 1400, &["r1", "r2", "r3"]));
+        blocks.push_back(phase_block(1601, 1600, 2900, &["r1", "r2", "r4"]));
+        blocks.push_back(phase_block(5001, 5000, 5200, &["r5"]));
+        let mut links: HashMap<(String, u32), PhaseSetLink> = HashMap::new();
+        links.insert(("chr1".to_string(), 101), PhaseSetLink { phase_set: 101, flip: false });
+        links.insert(("chr1".to_string(), 1601), PhaseSetLink { phase_set: 101, flip: true });
+        merge_phase_blocks(&mut blocks, &links);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!((blocks[0].phase_set, blocks[0].start, blocks[0].end), (101, 100, 2900));
+        assert_eq!((blocks[0].num_snps, blocks[0].num_reads), (4, 4));
+        assert_eq!((blocks[1].phase_set, blocks[1].num_reads), (5001, 1));
+    }
+
+    fn trio(genotypes: &[(u64, u8, u8)]) -> TrioGenotypes {
+        // position, paternal and maternal alternative allele count of A>G variants
+        let mut trio = TrioGenotypes { paternal: HashMap::new(), maternal: HashMap::new() };
+        for (pos, p, m) in genotypes.iter() {
+            trio.paternal.insert(("chr1".to_string(), *pos, b"A".to_vec(), b"G".to_vec()), *p);
+            trio.maternal.insert(("chr1".to_string(), *pos, b"A".to_vec(), b"G".to_vec()), *m);
+        }
+        return trio;
+    }
+
+    fn key(pos: u64) -> (String, u64, Vec<u8>, Vec<u8>) {
+        return ("chr1".to_string(), pos, b"A".to_vec(), b"G".to_vec());
+    }
+
+    #[test]
+    fn paternal_alt_of_informative_parents() {
+        let trio = trio(&[(1, 1, 0), (2, 0, 1), (3, 2, 1), (4, 1, 2), (5, 1, 1), (6, 2, 2), (7, 0, 0)]);
+        assert_eq!(trio.paternal_alt(&key(1)), Some(true));
+        assert_eq!(trio.paternal_alt(&key(2)), Some(false));
+        assert_eq!(trio.paternal_alt(&key(3)), Some(true));
+        assert_eq!(trio.paternal_alt(&key(4)), Some(false));
+        // both parents heterozygous or homozygous for the same allele
+        assert_eq!(trio.paternal_alt(&key(5)), None);
+        assert_eq!(trio.paternal_alt(&key(6)), None);
+        assert_eq!(trio.paternal_alt(&key(7)), None);
+        // missing in the parental vcfs
+        assert_eq!(trio.paternal_alt(&key(8)), None);
+    }
+
+    fn expressed_record(pos: u64, ps: u32, alt_on_hap1: bool) -> VCFRecord {
+        let mut rd = phased_record(pos, ps);
+        rd.format = b"GT:PS:AE".to_vec();
+        rd.genotype = if alt_on_hap1 { format!("1|0:{}:0,10,0,0", ps) } else { format!("0|1:{}:10,0,0,0", ps) };
+        return rd;
+    }
+
+    #[test]
+    fn phase_set_with_paternal_allele_on_hap2_is_flipped() {
+        // paternal alleles at 100, 200 and 300, maternal allele at 400
+        let trio = trio(&[(100, 1, 0), (200, 1, 0), (300, 2, 1), (400, 0, 1), (500, 1, 1)]);
+        let mut records: VecDeque<VCFRecord> = VecDeque::new();
+        // phase set 100 has the paternal alleles on hap2 and the maternal allele on hap1
+        records.push_back(expressed_record(100, 100, false));
+        records.push_back(expressed_record(200, 100, false));
+        records.push_back(expressed_record(400, 100, true));
+        // phase set 300 already has the paternal allele on hap1, 500 is not informative
+        records.push_back(expressed_record(300, 300, true));
+        records.push_back(expressed_record(500, 300, false));
+        let links = trio.orient_phase_sets(&records);
+        assert_eq!(links.len(), 1);
+        let link = &links[&("chr1".to_string(), 100)];
+        assert_eq!((link.phase_set, link.flip), (100, true));
+
+        let mut rd = records[0].clone();
+        relabel_vcf_record(&mut rd, &links);
+        assert!(rd.genotype.starts_with("1|0:100"));
+    }
+
+    #[test]
+    fn homozygous_reference_child_of_homozygous_alternative_parent_is_inconsistent() {
+        let trio = trio(&[(100, 2, 0), (200, 1, 0), (300, 0, 0), (400, 1, 1)]);
+        let flagged = |pos: u64, gt: &str| {
+            let mut rd = phased_record(pos, 100);
+            rd.format = b"GT".to_vec();
+            rd.genotype = gt.to_string();
+            trio.flag_mendelian_inconsistent(&mut rd);
+            return rd.info.ends_with(b";MIE");
+        };
+        assert!(flagged(100, "0/0"));
+        assert!(!flagged(200, "0/0"));
+        assert!(flagged(300, "0/1"));
+        assert!(!flagged(400, "1/1"));
+        assert!(flagged(200, "1/1"));
+        // unknown in the parents
+        assert!(!flagged(900, "1/1"));
+    }
+}
@@ -0,0 +1,557 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::heteroplasmy::is_mito_contig;
+use crate::Sex;
+use crate::snpfrags::SNPFrag;
+use crate::util::Region;
+use crate::vcf::VCFRecord;
+
+pub struct PloidyRegions {
+    default_ploidy: u32,
+    haploid_contigs: Vec<String>,
+    // sex chromosomes of male samples, haploid by default like chrM
+    intervals: HashMap<String, Vec<(i64, i64, u32)>>,
+    // chromosome -> 0-based start, 0-based exclusive end, ploidy
+}
+
+// pseudoautosomal regions of chrX and chrY, 0-based start, exclusive end. They are diploid in male samples.
+// The reference build is recognised by the length of chrX.
+const CHRX_LEN_GRCH38: u32 = 156040895;
+const CHRX_LEN_GRCH37: u32 = 155270560;
+const PAR_GRCH38: [(&str, i64, i64); 4] = [("X", 10000, 2781479), ("X", 155701382, 156030895), ("Y", 10000, 2781479), ("Y", 56887902, 57217415)];
+const PAR_GRCH37: [(&str, i64, i64); 4] = [("X", 60000, 2699520), ("X", 154931043, 155260560), ("Y", 10000, 2649520), ("Y", 59034049, 59363566)];
+
+impl PloidyRegions {
+    pub fn load(ploidy_bed: Option<String>, default_ploidy: u32, sex: &Option<Sex>, contig_lengths: &Vec<(String, u32)>) -> PloidyRegions {
+        // bed file with the ploidy in the 4th column, e.g. "chr1  0  248956422  4" for a whole contig
+        let mut intervals: HashMap<String, Vec<(i64, i64, u32)>> = HashMap::new();
+        if ploidy_bed.is_some() {
+            let reader = BufReader::new(File::open(ploidy_bed.unwrap()).unwrap());
+            for line in reader.lines() {
+                let line = line.unwrap();
+                if line.starts_with('#') || line.starts_with("track") || line.trim().is_empty() {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split('\t').collect();
+                if fields.len() < 4 {
+                    panic!("Error: ploidy bed requires chromosome, start, end and ploidy columns: {}", line);
+                }
+                let ploidy = fields[3].trim().parse::<u32>().unwrap();
+                assert!(ploidy >= 1, "Error: ploidy must be at least 1: {}", line);
+                intervals.entry(fields[0].to_string()).or_insert(Vec::new()).push((
+                    fields[1].parse::<i64>().unwrap(),
+                    fields[2].parse::<i64>().unwrap(),
+                    ploidy,
+                ));
+            }
+        }
+        assert!(default_ploidy >= 1, "Error: ploidy must be at least 1");
+        let mut haploid_contigs: Vec<String> = Vec::new();
+        if let Some(Sex::male) = sex {
+            haploid_contigs.extend(vec!["chrX", "chrY", "X", "Y"].iter().map(|x| x.to_string()));
+            // the pseudoautosomal regions stay diploid unless the ploidy bed lists the contig
+            let chrx_len = contig_lengths.iter().find(|(chr, _)| chr == "chrX" || chr == "X").map(|(_, len)| *len);
+            let par_regions = match chrx_len {
+                Some(CHRX_LEN_GRCH38) => PAR_GRCH38.to_vec(),
+                Some(CHRX_LEN_GRCH37) => PAR_GRCH37.to_vec(),
+                _ => Vec::new(),
+            };
+            for (chr, _) in contig_lengths.iter() {
+                if intervals.contains_key(chr) {
+                    continue;
+                }
+                let name = chr.trim_start_matches("chr");
+                let pars: Vec<(i64, i64, u32)> = par_regions.iter().filter(|(c, _, _)| *c == name).map(|(_, s, e)| (*s, *e, 2)).collect();
+                if pars.len() > 0 {
+                    intervals.insert(chr.clone(), pars);
+                }
+            }
+        }
+        return PloidyRegions { default_ploidy: default_ploidy, haploid_contigs: haploid_contigs, intervals: intervals };
+    }
+
+    pub fn region_ploidy(&self, reg: &Region) -> u32 {
+        // ploidy of the bed interval overlapping the region most, the default ploidy of the contig if no interval overlaps the region
+        let start = reg.start as i64 - 1;
+        let end = reg.end as i64 - 1;
+        let mut ploidy = if is_mito_contig(&reg.chr) || self.haploid_contigs.contains(&reg.chr) { 1 } else { self.default_ploidy };
+        let mut max_overlap = 0;
+        if let Some(intervals) = self.intervals.get(&reg.chr) {
+            for (s, e, p) in intervals.iter() {
+                let overlap = end.min(*e) - start.max(*s);
+                if overlap > max_overlap {
+                    max_overlap = overlap;
+                    ploidy = *p;
+                }
+            }
+        }
+        return ploidy;
+    }
+}
+
+fn allele_dosage(allele_freq: f32, ploidy: u32) -> u32 {
+    // number of haplotypes carrying the alternative allele of a heterozygous variant.
+    // A haploid region has a single haplotype, the variant is reported with the alternative allele
+    if ploidy == 1 {
+        return 1;
+    }
+    let dosage = (allele_freq * ploidy as f32).round() as u32;
+    return dosage.max(1).min(ploidy - 1);
+}
+
+fn unphased_genotype(alt_cnt: u32, ploidy: u32, first_allele: u32, alt_allele: u32) -> String {
+    // e.g. 0/0/1/1, the first allele is listed first
+    let mut alleles: Vec<String> = Vec::new();
+    for h in 0..ploidy {
+        alleles.push(if h < ploidy - alt_cnt { first_allele.to_string() } else { alt_allele.to_string() });
+    }
+    return alleles.join("/");
+}
+
+fn read_loglik(entries: &Vec<(usize, i32, f64)>, hap: &Vec<i32>) -> f64 {
+    // log10 likelihood of the alleles of a read given the alleles of one haplotype
+    let mut logp = 0.0;
+    for (j, p, prob) in entries.iter() {
+        let e = prob.max(1e-6).min(0.5);
+        if hap[*j] == *p {
+            logp += (1.0 - e).log10();
+        } else {
+            logp += e.log10();
+        }
+    }
+    return logp;
+}
+
+impl SNPFrag {
+    pub fn init_polyploid_sites(&mut self) {
+        // with more than two haplotypes a heterozygous allele may be carried by a single haplotype, so low fraction
+        // heterozygous SNPs are also used for phasing. Must be called before get_fragments.
+        for i in self.low_frac_het_snps.iter() {
+            if self.candidate_snps[*i].dense == false {
+                self.candidate_snps[*i].for_phasing = true;
+            }
+        }
+    }
+
+    pub fn phase_polyploid(&mut self, max_iters: i32, read_assignment_cutoff: f64, min_phase_score: f32) {
+        // Cluster the reads into self.ploidy haplotypes by hard EM: the allele of each haplotype at a SNP is the allele
+        // supported by most of its reads, and each read moves to the haplotype it fits best. The best of max_iters random
+        // restarts is kept. A SNP is phased when both alleles are carried by at least one haplotype.
+        let k = self.ploidy as usize;
+        let mut cols: Vec<usize> = Vec::new();
+        let mut col_of: HashMap<usize, usize> = HashMap::new();
+        for i in 0..self.candidate_snps.len() {
+            if self.candidate_snps[i].for_phasing && self.candidate_snps[i].variant_type == 1 {
+                col_of.insert(i, cols.len());
+                cols.push(i);
+            }
+        }
+        let mut reads: Vec<(usize, Vec<(usize, i32, f64)>)> = Vec::new(); // fragment index, (column, allele, error rate)
+        for f in 0..self.fragments.len() {
+            let mut entries: Vec<(usize, i32, f64)> = Vec::new();
+            for fe in self.fragments[f].list.iter() {
+                if fe.phase_site && col_of.contains_key(&fe.snp_idx) {
+                    entries.push((col_of[&fe.snp_idx], fe.p, fe.prob));
+                }
+            }
+            if entries.len() > 0 {
+                reads.push((f, entries));
+            }
+        }
+        if cols.len() == 0 || reads.len() == 0 {
+            return;
+        }
+
+        // seeded by the region, so a region is phased the same way in every run
+        let mut rng = StdRng::seed_from_u64(self.region.start as u64);
+        let mut best_logp = f64::NEG_INFINITY;
+        let mut best_haps: Vec<Vec<i32>> = Vec::new(); // haplotype -> allele of each column, 1: ref, -1: alt
+        let mut best_labels: Vec<usize> = Vec::new();
+        for _ in 0..max_iters.max(1) {
+            let mut labels: Vec<usize> = (0..reads.len()).map(|_| rng.gen_range(0..k)).collect();
+            let mut haps: Vec<Vec<i32>> = vec![vec![1; cols.len()]; k];
+            let mut logp = f64::NEG_INFINITY;
+            for _ in 0..100 {
+                // haplotype alleles from the reads of each haplotype
+                let mut support: Vec<Vec<f64>> = vec![vec![0.0; cols.len()]; k]; // > 0: alt
+                for (r, (_, entries)) in reads.iter().enumerate() {
+                    for (j, p, prob) in entries.iter() {
+                        let e = prob.max(1e-6).min(0.5);
+                        support[labels[r]][*j] -= (*p as f64) * ((1.0 - e) / e).log10();
+                    }
+                }
+                for h in 0..k {
+                    for j in 0..cols.len() {
+                        haps[h][j] = if support[h][j] > 0.0 { -1 } else { 1 };
+                    }
+                }
+                // reads to the best fitting haplotype
+                let mut changed = false;
+                logp = 0.0;
+                for (r, (_, entries)) in reads.iter().enumerate() {
+                    let mut best_h = labels[r];
+                    let mut best_q = read_loglik(entries, &haps[best_h]);
+                    for h in 0..k {
+                        let q = read_loglik(entries, &haps[h]);
+                        if q > best_q {
+                            best_q = q;
+                            best_h = h;
+                        }
+                    }
+                    if best_h != labels[r] {
+                        labels[r] = best_h;
+                        changed = true;
+                    }
+                    logp += best_q;
+                }
+                if !changed {
+                    break;
+                }
+            }
+            if logp > best_logp {
+                best_logp = logp;
+                best_haps = haps;
+                best_labels = labels;
+            }
+        }
+
+        // assign reads, the posterior of the best haplotype has to exceed the second best by read_assignment_cutoff.
+        // Reads fitting several identical haplotypes equally well stay unassigned.
+        for (r, (f, entries)) in reads.iter().enumerate() {
+            let logliks: Vec<f64> = best_haps.iter().map(|hap| read_loglik(entries, hap)).collect();
+            let max_q = logliks.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let sum: f64 = logliks.iter().map(|q| 10.0_f64.powf(q - max_q)).sum();
+            let mut posteriors: Vec<(f64, usize)> = logliks.iter().enumerate().map(|(h, q)| (10.0_f64.powf(q - max_q) / sum, h)).collect();
+            posteriors.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap().then(a.1.cmp(&b.1)));
+            let frag = &mut self.fragments[*f];
+            frag.haplotag = best_labels[r] as i32 + 1;
+            if posteriors[0].0 - posteriors[1].0 >= read_assignment_cutoff {
+                frag.assignment = posteriors[0].1 as i32 + 1;
+                frag.assignment_score = posteriors[0].0;
+            } else {
+                frag.assignment = 0;
+                frag.assignment_score = 0.0;
+            }
+        }
+
+        // phase score of each SNP from the reads agreeing with the allele of their haplotype cluster
+        let mut agree: Vec<[u32; 2]> = vec![[0, 0]; cols.len()];
+        for (r, (_, entries)) in reads.iter().enumerate() {
+            for (j, p, _) in entries.iter() {
+                if best_haps[best_labels[r]][*j] == *p {
+                    agree[*j][0] += 1;
+                } else {
+                    agree[*j][1] += 1;
+                }
+            }
+        }
+        for (j, i) in cols.iter().enumerate() {
+            let alleles: Vec<i32> = (0..k).map(|h| best_haps[h][j]).collect();
+            let alt_cnt = alleles.iter().filter(|a| **a == -1).count();
+            let snp = &mut self.candidate_snps[*i];
+            if alt_cnt == 0 || alt_cnt == k {
+                continue;
+            }
+            let err = (agree[j][1] as f64 + 1.0) / ((agree[j][0] + agree[j][1]) as f64 + 2.0);
+            snp.phase_score = -10.0 * err.log10();
+            if snp.phase_score >= min_phase_score as f64 {
+                snp.hap_alleles = alleles;
+            }
+        }
+    }
+
+    pub fn assign_polyploid_phase_set(&mut self) -> HashMap<String, u32> {
+        // phased SNPs linked by reads form a phase set, the phase set id is the position of its first SNP
+        let mut parent: HashMap<usize, usize> = HashMap::new();
+        fn root(parent: &mut HashMap<usize, usize>, x: usize) -> usize {
+            let p = parent[&x];
+            if p == x {
+                return x;
+            }
+            let r = root(parent, p);
+            parent.insert(x, r);
+            return r;
+        }
+        let mut read_snps: Vec<(String, Vec<usize>)> = Vec::new();
+        for frag in self.fragments.iter() {
+            if frag.haplotag == 0 {
+                continue;
+            }
+            let snps: Vec<usize> = frag.list.iter().filter(|fe| self.candidate_snps[fe.snp_idx].hap_alleles.len() > 0).map(|fe| fe.snp_idx).collect();
+            for i in snps.iter() {
+                parent.entry(*i).or_insert(*i);
+            }
+            for w in snps.windows(2) {
+                let (a, b) = (root(&mut parent, w[0]), root(&mut parent, w[1]));
+                if a != b {
+                    parent.insert(a.max(b), a.min(b));
+                }
+            }
+            read_snps.push((frag.read_id.clone(), snps));
+        }
+        let mut component_size: HashMap<usize, u32> = HashMap::new();
+        let nodes: Vec<usize> = parent.keys().cloned().collect();
+        for i in nodes.iter() {
+            *component_size.entry(root(&mut parent, *i)).or_insert(0) += 1;
+        }
+        for i in nodes.iter() {
+            // the root is the SNP with the smallest index, i.e. the first SNP of the phase set
+            let r = root(&mut parent, *i);
+            if component_size[&r] > 1 {
+                self.candidate_snps[*i].phase_set = (self.candidate_snps[r].pos + 1) as u32;
+            }
+        }
+        let mut phase_set: HashMap<String, u32> = HashMap::new();
+        for (read_id, snps) in read_snps.iter() {
+            if let Some(i) = snps.first() {
+                if self.candidate_snps[*i].phase_set != 0 {
+                    phase_set.insert(read_id.clone(), self.candidate_snps[*i].phase_set);
+                }
+            }
+        }
+        return phase_set;
+    }
+
+    pub fn output_polyploid_vcf(&self, min_qual: u32) -> Vec<VCFRecord> {
+        // genotypes with self.ploidy alleles, phased heterozygous SNPs list the allele of each haplotype (e.g. 0|1|0|0),
+        // other heterozygous variants get the allele dosage estimated from the allele frequency (e.g. 0/0/1/1).
+        // Haploid variants are hemizygous (e.g. 1). Adjacent SNVs are not merged into MNVs.
+        let ploidy = self.ploidy;
+        let mut records: Vec<VCFRecord> = Vec::new();
+        for snp in self.candidate_snps.iter() {
+            if snp.cand_somatic || snp.variant_type == 0 {
+                continue;
+            }
+            let mut rd: VCFRecord = VCFRecord::default();
+            rd.chromosome = snp.chromosome.clone();
+            rd.position = snp.pos as u64 + 1; // position in vcf format is 1-based
+            rd.id = vec!['.' as u8];
+            rd.reference = vec![snp.reference as u8];
+            rd.qual = snp.variant_quality as i32;
+            if snp.dense {
+                rd.filter = "dn".to_string().into_bytes();
+                rd.info = format!("RDS={}", "dense_snp").to_string().into_bytes();
+            } else if snp.rna_editing {
+                rd.filter = "RnaEdit".to_string().into_bytes();
+                rd.info = "RDS=.".to_string().into_bytes();
+            } else if snp.variant_quality < min_qual as f64 {
+                rd.filter = "LowQual".to_string().into_bytes();
+                rd.info = "RDS=.".to_string().into_bytes();
+            } else if ploidy == 1 && snp.variant_type == 1 {
+                // both alleles in a haploid region, e.g. a mapping artefact or a wrong sex
+                rd.filter = "HetInHaploid".to_string().into_bytes();
+                rd.info = "RDS=.".to_string().into_bytes();
+            } else {
+                rd.filter = "PASS".to_string().into_bytes();
+                rd.info = "RDS=.".to_string().into_bytes();
+            }
+            if snp.variant_type == 3 {
+                rd.alternative = vec![vec![snp.alleles[0] as u8], vec![snp.alleles[1] as u8]];
+                let alt2_frac = snp.allele_freqs[1] / (snp.allele_freqs[0] + snp.allele_freqs[1]);
+                // a haploid region carries the more frequent alternative allele
+                let alt2_cnt = if ploidy == 1 { (alt2_frac > 0.5) as u32 } else { allele_dosage(alt2_frac, ploidy) };
+                let gt = unphased_genotype(alt2_cnt, ploidy, 1, 2);
+                rd.genotype = format!("{}:{}:{}:{:.2},{:.2}", gt, snp.genotype_quality as i32, snp.depth, snp.allele_freqs[0], snp.allele_freqs[1]);
+                rd.format = "GT:GQ:DP:AF".to_string().into_bytes();
+            } else if snp.variant_type == 2 {
+                rd.alternative = vec![vec![snp.alleles[0] as u8]];
+                rd.genotype = format!("{}:{}:{}:{:.2}", unphased_genotype(ploidy, ploidy, 0, 1), snp.genotype_quality as i32, snp.depth, snp.allele_freqs[0]);
+                rd.format = "GT:GQ:DP:AF".to_string().into_bytes();
+            } else if snp.variant_type == 1 {
+                let af;
+                if snp.alleles[0] == snp.reference {
+                    rd.alternative = vec![vec![snp.alleles[1] as u8]];
+                    af = snp.allele_freqs[1];
+                } else {
+                    rd.alternative = vec![vec![snp.alleles[0] as u8]];
+                    af = snp.allele_freqs[0];
+                }
+                if snp.phase_set != 0 {
+                    let gt: Vec<&str> = snp.hap_alleles.iter().map(|a| if *a == -1 { "1" } else { "0" }).collect();
+                    rd.genotype = format!(
+                        "{}:{}:{}:{}:{:.2}:{:.2}",
+                        gt.join("|"),
+                        snp.phase_set,
+                        snp.genotype_quality as i32,
+                        snp.depth,
+                        af,
+                        snp.phase_score
+                    );
+                    rd.format = "GT:PS:GQ:DP:AF:PQ".to_string().into_bytes();
+                } else {
+                    rd.genotype = format!("{}:{}:{}:{:.2}", unphased_genotype(allele_dosage(af, ploidy), ploidy, 0, 1), snp.genotype_quality as i32, snp.depth, af);
+                    rd.format = "GT:GQ:DP:AF".to_string().into_bytes();
+                }
+            } else {
+                continue;
+            }
+            records.push(rd);
+        }
+        let mut indel_records = self.output_indel_vcf(0.0, min_qual, false);
+        for (rd, indel) in indel_records.iter_mut().zip(self.candidate_indels.iter()) {
+            let alt_cnt = if indel.variant_type == 2 { ploidy } else { allele_dosage(indel.allele_freq, ploidy) };
+            rd.genotype = format!("{}:{}:{}:{:.2}", unphased_genotype(alt_cnt, ploidy, 0, 1), indel.genotype_quality as i32, indel.depth, indel.allele_freq);
+            if ploidy == 1 && indel.variant_type == 1 && rd.filter == b"PASS".to_vec() {
+                rd.filter = "HetInHaploid".to_string().into_bytes();
+            }
+        }
+        records.extend(indel_records);
+        records.sort_by(|a, b| a.position.cmp(&b.position));
+        return records;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snp::{CandidateSNP, FragElem, Fragment};
+
+    use super::*;
+
+    #[test]
+    fn haploid_het_variant_carries_the_alternative_allele() {
+        assert_eq!(allele_dosage(0.5, 1), 1);
+        assert_eq!(allele_dosage(0.2, 1), 1);
+        assert_eq!(unphased_genotype(allele_dosage(0.4, 1), 1, 0, 1), "1");
+        assert_eq!(unphased_genotype(1, 1, 0, 1), "1");
+    }
+
+    #[test]
+    fn polyploid_dosage_stays_heterozygous() {
+        assert_eq!(allele_dosage(0.5, 2), 1);
+        assert_eq!(allele_dosage(0.75, 4), 3);
+        assert_eq!(allele_dosage(0.05, 4), 1);
+        assert_eq!(allele_dosage(0.99, 4), 3);
+        assert_eq!(unphased_genotype(allele_dosage(0.5, 4), 4, 0, 1), "0/0/1/1");
+    }
+
+    fn region(chr: &str, start: u32, end: u32) -> Region {
+        return Region { chr: chr.to_string(), start, end, ..Default::default() };
+    }
+
+    #[test]
+    fn male_pseudoautosomal_regions_stay_diploid() {
+        let contig_lengths = vec![("chr1".to_string(), 248956422), ("chrX".to_string(), CHRX_LEN_GRCH38), ("chrY".to_string(), 57227415)];
+        let regions = PloidyRegions::load(None, 2, &Some(Sex::male), &contig_lengths);
+        assert_eq!(regions.region_ploidy(&region("chr1", 1000, 2000)), 2);
+        assert_eq!(regions.region_ploidy(&region("chrX", 1000001, 1002001)), 2);
+        assert_eq!(regions.region_ploidy(&region("chrX", 50000001, 50002001)), 1);
+        assert_eq!(regions.region_ploidy(&region("chrY", 57000001, 57002001)), 2);
+        assert_eq!(regions.region_ploidy(&region("chrY", 20000001, 20002001)), 1);
+
+        let regions = PloidyRegions::load(None, 2, &Some(Sex::female), &contig_lengths);
+        assert_eq!(regions.region_ploidy(&region("chrX", 50000001, 50002001)), 2);
+
+        // an unknown reference build keeps the whole sex chromosomes haploid
+        let regions = PloidyRegions::load(None, 2, &Some(Sex::male), &vec![("chrX".to_string(), 1000000)]);
+        assert_eq!(regions.region_ploidy(&region("chrX", 10001, 12001)), 1);
+    }
+
+    #[test]
+    fn ploidy_bed_overrides_pseudoautosomal_regions() {
+        let bed = std::env::temp_dir().join(format!("longcallR_ploidy_{}.bed", std::process::id()));
+        std::fs::write(&bed, "chrX\t0\t5000000\t1\n").unwrap();
+        let contig_lengths = vec![("chrX".to_string(), CHRX_LEN_GRCH38)];
+        let regions = PloidyRegions::load(Some(bed.to_str().unwrap().to_string()), 2, &Some(Sex::male), &contig_lengths);
+        std::fs::remove_file(&bed).unwrap();
+        assert_eq!(regions.region_ploidy(&region("chrX", 1000001, 1002001)), 1);
+    }
+
+    #[test]
+    fn haploid_het_snp_is_filtered() {
+        let mut snpfrag = SNPFrag::default();
+        snpfrag.ploidy = 1;
+        let mut snp = CandidateSNP::default();
+        snp.chromosome = b"chrX".to_vec();
+        snp.pos = 50000000;
+        snp.reference = 'A';
+        snp.alleles = ['A', 'G'];
+        snp.allele_freqs = [0.5, 0.5];
+        snp.variant_type = 1;
+        snp.variant_quality = 30.0;
+        snpfrag.candidate_snps.push(snp.clone());
+        snp.pos += 100;
+        snp.alleles = ['G', 'A'];
+        snp.allele_freqs = [1.0, 0.0];
+        snp.variant_type = 2;
+        snpfrag.candidate_snps.push(snp);
+        let records = snpfrag.output_polyploid_vcf(10);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].filter, b"HetInHaploid".to_vec());
+        assert_eq!(records[1].filter, b"PASS".to_vec());
+    }
+
+    fn tetraploid_snpfrag() -> SNPFrag {
+        // six heterozygous SNPs on four haplotypes, five error free reads from each haplotype span all SNPs
+        let haps: Vec<Vec<i32>> = vec![
+            vec![-1, 1, 1, 1, -1, 1],
+            vec![1, -1, 1, 1, 1, -1],
+            vec![1, 1, -1, 1, -1, -1],
+            vec![1, 1, 1, -1, 1, 1],
+        ];
+        let mut snpfrag = SNPFrag::default();
+        snpfrag.ploidy = 4;
+        snpfrag.region = Region { chr: "chr1".to_string(), start: 1, end: 1000, ..Default::default() };
+        for i in 0..6 {
+            let mut snp = CandidateSNP::default();
+            snp.pos = 100 * (i + 1) as i64;
+            snp.variant_type = 1;
+            snp.for_phasing = true;
+            snpfrag.candidate_snps.push(snp);
+        }
+        for (h, hap) in haps.iter().enumerate() {
+            for r in 0..5 {
+                let mut frag = Fragment::default();
+                frag.fragment_idx = snpfrag.fragments.len();
+                frag.read_id = format!("hap{}_read{}", h, r);
+                for (i, p) in hap.iter().enumerate() {
+                    frag.list.push(FragElem { snp_idx: i, pos: 100 * (i + 1) as i64, p: *p, prob: 0.01, phase_site: true, ..Default::default() });
+                }
+                snpfrag.fragments.push(frag);
+            }
+        }
+        return snpfrag;
+    }
+
+    #[test]
+    fn phase_polyploid_recovers_known_haplotypes() {
+        let mut snpfrag = tetraploid_snpfrag();
+        snpfrag.phase_polyploid(20, 0.5, 10.0);
+        // reads of a haplotype share a cluster, reads of different haplotypes do not
+        let clusters: Vec<i32> = (0..4).map(|h| snpfrag.fragments[h * 5].assignment).collect();
+        for h in 0..4 {
+            assert!(clusters[h] >= 1 && clusters[h] <= 4);
+            for r in 0..5 {
+                assert_eq!(snpfrag.fragments[h * 5 + r].assignment, clusters[h]);
+                assert_eq!(snpfrag.fragments[h * 5 + r].haplotag, clusters[h]);
+            }
+        }
+        let mut distinct = clusters.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 4);
+        // the allele of each cluster is the allele of its haplotype
+        for (i, snp) in snpfrag.candidate_snps.iter().enumerate() {
+            assert_eq!(snp.hap_alleles.len(), 4);
+            for h in 0..4 {
+                assert_eq!(snp.hap_alleles[clusters[h] as usize - 1], snpfrag.fragments[h * 5].list[i].p);
+            }
+        }
+
+        // the seeded restarts give the same clustering in every run
+        let mut again = tetraploid_snpfrag();
+        again.phase_polyploid(20, 0.5, 10.0);
+        let labels: Vec<i32> = again.fragments.iter().map(|f| f.assignment).collect();
+        assert_eq!(labels, snpfrag.fragments.iter().map(|f| f.assignment).collect::<Vec<i32>>());
+
+        // all SNPs are linked by the reads into one phase set named after the first SNP
+        let read_phase_sets = snpfrag.assign_polyploid_phase_set();
+        assert!(snpfrag.candidate_snps.iter().all(|snp| snp.phase_set == 101));
+        assert_eq!(read_phase_sets.len(), 20);
+        assert!(read_phase_sets.values().all(|ps| *ps == 101));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::hash::Hash;
 
 use probability::distribution::Distribution;
@@ -71,6 +72,10 @@ pub struct CandidateSNP {
     // phase set id is the position of the first snp in the phase set
     pub haplotype_expression: [u32; 4],
     // hap1_ref, hap1_alt, hap2_ref, hap2_alt
+    pub phase_confidence: Option<f64>,
+    // likelihood-weighted fraction of phasing restarts agreeing with the reported phase, None if not estimated
+    pub hap_alleles: Vec<i32>,
+    // allele of each haplotype in polyploid regions, 1: ref, -1: alt, empty if not phased
 }
 
 #[derive(Debug, Clone, Default)]
@@ -85,6 +90,36 @@ pub struct Edge {
     // weight of edge,  w_{ij}=\sum_{k}x_{ki}x_{kj}log\frac{1-\epsilon_{kij}}{\epsilon_{kij}}
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct PhaseSplit {
+    pub chromosome: String,
+    pub snp_poses: [i64; 2],
+    // position of the two SNPs of the removed link, 0-based
+    pub phase_sets: [u32; 2],
+    // phase set on each side of the split, 0 if the SNP is left unphased
+    pub agree_reads: u32,
+    // reads consistent with the phase of the two SNPs
+    pub disagree_reads: u32,
+    // reads supporting the opposite phase of the two SNPs
+    pub reason: String,
+    // low_support or conflict
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PhaseBlock {
+    pub chromosome: String,
+    pub phase_set: u32,
+    pub start: i64,
+    // position of the first SNP, 0-based
+    pub end: i64,
+    // position of the last SNP, 0-based
+    pub num_snps: u32,
+    pub num_reads: u32,
+    pub mean_phase_score: f64,
+    pub read_ids: HashSet<String>,
+    // reads in the phase set, a read shared by merged blocks is counted once
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct FragElem {
     pub snp_idx: usize,
@@ -114,7 +149,7 @@ pub struct Fragment {
     pub list: Vec<FragElem>,
     // single fragment
     pub haplotag: i32,
-    // sigma: 0,1,-1
+    // sigma: 0,1,-1. Haplotype cluster 1..k in polyploid regions
     pub assignment: i32,
     // haplotype assignment of the fragment, 0,1,2. 0: unassigned, 1: hap1, 2: hap2
     pub assignment_score: f64,
@@ -123,5 +158,51 @@ pub struct Fragment {
     // exons of the read on the reference, 0-based, [start, end)
     pub num_hete_links: u32,
     // number of linked heterozygous snps in the fragment
+    pub indel_list: Vec<IndelElem>,
+    // alleles of candidate indels covered by the fragment
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CandidateIndel {
+    pub chromosome: Vec<u8>,
+    pub pos: i64,
+    // position of the anchor base on the reference, 0-based. Left-aligned.
+    pub reference: Vec<u8>,
+    // reference allele, anchor base included
+    pub alternative: Vec<u8>,
+    // alternative allele, anchor base included
+    pub depth: u32,
+    // number of reads covering the anchor base
+    pub alt_cnt: u32,
+    // number of reads supporting the alternative allele
+    pub allele_freq: f32,
+    pub homopolymer_len: u32,
+    // length of the reference homopolymer the indel lies in, 1 if not in a homopolymer
+    pub error_rate: f64,
+    // probability of observing this indel by sequencing error in one read
+    pub variant_type: i32,
+    // 1: heterozygous indel, 2: homozygous indel
+    pub variant_quality: f64,
+    pub genotype_probability: [f64; 3],
+    // 0th: homo var, 1st: hete var, 2nd: homo ref
+    pub genotype_quality: f64,
+    pub haplotype: i32,
+    // delta: 1,-1: hap1, hap2 if phased, 0: unassigned
+    pub phase_score: f64,
+    pub phase_set: u32,
+    pub haplotype_expression: [u32; 4],
+    // hap1_ref, hap1_alt, hap2_ref, hap2_alt
+    pub indel_cover_fragments: Vec<usize>,
+    // index of the fragment cover this indel
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IndelElem {
+    pub indel_idx: usize,
+    // index of candidate indels(SNPFrag.candidate_indels)
+    pub p: i32,
+    // 1: read has reference allele, -1: read has the candidate indel
+    pub prob: f64,
+    // error rate of observe current allele
 }
 
@@ -4,9 +4,10 @@ use petgraph::algo::kosaraju_scc;
 use petgraph::graphmap::GraphMap;
 use petgraph::Undirected;
 use rand::Rng;
-use rust_htslib::{bam, bam::Read, bam::record::Record};
+use rust_htslib::bam::record::Record;
 
-use crate::snp::{CandidateSNP, Edge, Fragment};
+use crate::haplotag::ReadAssignment;
+use crate::snp::{CandidateIndel, CandidateSNP, Edge, Fragment, PhaseBlock, PhaseSplit};
 use crate::somatic::calculate_prob_somatic;
 use crate::util::Region;
 
@@ -33,6 +34,22 @@ pub struct SNPFrag {
     // edges of the graph, key is [snp_idx of start_node, snp_idx of end_node]
     pub min_linkers: u32,
     // the number of links for snps can be phased
+    pub candidate_indels: Vec<CandidateIndel>,
+    // candidate indels, sorted by position
+    pub mnv_window: u32,
+    // maximum distance between adjacent SNVs merged into one MNV, 0: no merging
+    pub mnv_links: HashMap<[usize; 2], [u32; 4]>,
+    // reads covering two adjacent SNVs, key is [snp_idx, next snp_idx], value is count of ref-ref, ref-alt, alt-ref, alt-alt
+    pub unlinked_reads: HashMap<String, u32>,
+    // reads with fewer than min_linkers heterozygous SNPs, value is the number of heterozygous SNPs
+    pub debug_phasing: bool,
+    // check that each step of cross_optimize increases the probability and reaches a local optimum
+    pub phase_ensemble: bool,
+    // estimate phase confidence of each SNP from the restarts of the phasing
+    pub phase_splits: Vec<PhaseSplit>,
+    // weak or conflicting links removed when assigning phase sets
+    pub ploidy: u32,
+    // number of haplotypes of the region
 }
 
 impl SNPFrag {
@@ -64,18 +81,12 @@ impl SNPFrag {
         }
     }
 
-    pub fn get_somatic_haplotype_baseqs(&mut self, bam_path: &str, region: &Region, phased_fragments: &HashMap<String, i32>) {
-        let mut bam_reader: bam::IndexedReader = bam::IndexedReader::from_path(bam_path).unwrap();
-        bam_reader.fetch((region.chr.as_str(), region.start, region.end)).unwrap();
-        let mut record = Record::new();
+    pub fn get_somatic_haplotype_baseqs(&mut self, records: &Vec<Record>, phased_fragments: &HashMap<String, i32>) {
+        if self.somatic_snps.len() == 0 {
+            return;
+        }
         // assert!(self.min_linkers >= 0, "Error: min_linkers <= 0");
-        while let Some(result) = bam_reader.read(&mut record) {
-            if self.somatic_snps.len() == 0 {
-                continue;
-            }
-            if result.is_err() {
-                panic!("BAM parsing failed...");
-            }
+        for record in records.iter() {
             // TODO: filtering unmapped, secondary, supplementary reads?
             if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
                 continue;
@@ -608,105 +619,145 @@ impl SNPFrag {
         //     2. evaluate the SNP haplotype based on the read assignment.
         // If P(sigma, delta) increase, repeat Iteration;
         // Else break;
+        // The log-likelihood of each read under both assignments and of each SNP under both haplotypes are kept and only
+        // the reads and SNPs touched by a flip are updated and re-evaluated in the next step.
+
+        let snp_num = self.candidate_snps.len();
+        let mut frag_logq: Vec<[f64; 2]> = vec![[0.0, 0.0]; self.fragments.len()]; // log10 P(read | sigma=1), log10 P(read | sigma=-1)
+        let mut snp_logq: Vec<[f64; 2]> = vec![[0.0, 0.0]; snp_num]; // log10 P(reads | delta=1), log10 P(reads | delta=-1)
+        let mut snp_elems: Vec<Vec<(usize, i32, f64, f64)>> = vec![Vec::new(); snp_num]; // fragment index, allele, log10(1-e), log10(e)
+        let mut is_het: Vec<bool> = vec![false; snp_num];
+        for i in self.high_frac_het_snps.iter() {
+            is_het[*i] = true;
+        }
+        for k in 0..self.fragments.len() {
+            let sigma_k = self.fragments[k].haplotag;
+            if sigma_k == 0 {
+                continue;
+            }
+            for fe in self.fragments[k].list.iter() {
+                if fe.phase_site == false {
+                    continue;
+                }
+                assert_ne!(fe.p, 0, "Error: phase for unexpected allele.");
+                let lc = (1.0 - fe.prob).log10();
+                let le = fe.prob.log10();
+                let delta_i = self.candidate_snps[fe.snp_idx].haplotype;
+                if delta_i == fe.p {
+                    frag_logq[k][0] += lc;
+                    frag_logq[k][1] += le;
+                } else {
+                    frag_logq[k][0] += le;
+                    frag_logq[k][1] += lc;
+                }
+                if is_het[fe.snp_idx] {
+                    snp_elems[fe.snp_idx].push((k, fe.p, lc, le));
+                    if sigma_k == fe.p {
+                        snp_logq[fe.snp_idx][0] += lc;
+                        snp_logq[fe.snp_idx][1] += le;
+                    } else {
+                        snp_logq[fe.snp_idx][0] += le;
+                        snp_logq[fe.snp_idx][1] += lc;
+                    }
+                }
+            }
+        }
 
+        let mut dirty_frags: Vec<usize> = (0..self.fragments.len()).collect();
+        let mut dirty_snps: HashSet<usize> = self.high_frac_het_snps.iter().cloned().collect();
         let mut phasing_increase: bool = true;
         let mut haplotag_increase: bool = true;
         let mut num_iters = 0;
 
         while phasing_increase | haplotag_increase {
-            // optimize sigma
-            let mut tmp_haplotag: HashMap<usize, i32> = HashMap::new();
-            let mut processed_snps = HashSet::new(); // some snps in self.hete_snps may be filtered by previous steps, record the snps that covered by the fragments
-            for k in 0..self.fragments.len() {
-                let sigma_k = self.fragments[k].haplotag;
-                let mut delta: Vec<i32> = Vec::new();
-                let mut ps: Vec<i32> = Vec::new();
-                let mut probs: Vec<f64> = Vec::new();
+            // optimize sigma, only reads covering SNPs flipped in the previous step can change
+            let mut flipped_frags: Vec<usize> = Vec::new();
+            for k in dirty_frags.iter() {
+                let sigma_k = self.fragments[*k].haplotag;
                 if sigma_k == 0 {
                     continue;
                 }
-                for fe in self.fragments[k].list.iter() {
-                    if fe.phase_site == false {
-                        continue;
-                    }
-                    assert_ne!(fe.p, 0, "Error: phase for unexpected allele.");
-                    ps.push(fe.p);
-                    probs.push(fe.prob);
-                    delta.push(self.candidate_snps[fe.snp_idx].haplotype);
-                    processed_snps.insert(fe.snp_idx);
+                let (q, qn) = if sigma_k == 1 { (frag_logq[*k][0], frag_logq[*k][1]) } else { (frag_logq[*k][1], frag_logq[*k][0]) };
+                if q < qn - 1e-9 {
+                    flipped_frags.push(*k);
                 }
-
-                let q = SNPFrag::cal_sigma_delta_log(sigma_k, &delta, &ps, &probs);
-                let qn = SNPFrag::cal_sigma_delta_log(sigma_k * (-1), &delta, &ps, &probs);
-                // println!("optimize sigma {} q:{}, qn:{}, sigma: {}", k, q, qn, sigma_k);
-
-                if q < qn {
-                    tmp_haplotag.insert(k, sigma_k * (-1));
-                } else {
-                    tmp_haplotag.insert(k, sigma_k);
+            }
+            if self.debug_phasing {
+                let mut tmp_haplotag: HashMap<usize, i32> = HashMap::new();
+                for k in flipped_frags.iter() {
+                    tmp_haplotag.insert(*k, self.fragments[*k].haplotag * (-1));
                 }
+                let check_val = SNPFrag::check_new_haplotag(&self, &tmp_haplotag);
+                assert!(check_val >= 0, "ckeck val bug: {:?}", self.candidate_snps);
             }
-
-            // assert!(SNPFrag::cal_overall_probability(&self, &processed_snps, &self.haplotype) >= SNPFrag::cal_overall_probability(&self, &self.haplotag, &self.haplotype));
-            let check_val = SNPFrag::check_new_haplotag(&self, &tmp_haplotag);
-            assert!(check_val >= 0, "ckeck val bug: {:?}", self.candidate_snps);
-            for (k, h) in tmp_haplotag.iter() {
-                // when prob is equal, we still perform the flip to avoid bug of underflow
-                self.fragments[*k].haplotag = *h;
+            for k in flipped_frags.iter() {
+                let sigma_k = self.fragments[*k].haplotag;
+                for fe in self.fragments[*k].list.iter() {
+                    if fe.phase_site == false || !is_het[fe.snp_idx] {
+                        continue;
+                    }
+                    // the term of this read moves between the two haplotypes of the SNP
+                    let diff = (1.0 - fe.prob).log10() - fe.prob.log10();
+                    let sign = if sigma_k == fe.p { -1.0 } else { 1.0 };
+                    snp_logq[fe.snp_idx][0] += sign * diff;
+                    snp_logq[fe.snp_idx][1] -= sign * diff;
+                    dirty_snps.insert(fe.snp_idx);
+                }
+                self.fragments[*k].haplotag = sigma_k * (-1);
             }
-            if check_val == 0 {
+            if flipped_frags.len() == 0 {
                 haplotag_increase = false;
             } else {
                 haplotag_increase = true;
                 phasing_increase = true;
             }
-            self.check_local_optimal_configuration(false, true);
+            if self.debug_phasing {
+                self.check_local_optimal_configuration(false, true);
+            }
 
-            // optimize delta
-            let mut tmp_haplotype: HashMap<usize, i32> = HashMap::new();
+            // optimize delta, only SNPs covered by reads flipped in the previous step can change
+            let mut flipped_snps: Vec<usize> = Vec::new();
             for i in self.high_frac_het_snps.iter() {
+                if !dirty_snps.contains(i) || snp_elems[*i].len() == 0 {
+                    continue;
+                }
                 let delta_i = self.candidate_snps[*i].haplotype;
-                let mut sigma: Vec<i32> = Vec::new();
-                let mut ps: Vec<i32> = Vec::new();
-                let mut probs: Vec<f64> = Vec::new();
-                for k in self.candidate_snps[*i].snp_cover_fragments.iter() {
-                    if self.fragments[*k].haplotag == 0 {
-                        continue;
-                    }
-                    // k is fragment index
-                    for fe in self.fragments[*k].list.iter() {
-                        if fe.snp_idx == *i {
-                            assert_ne!(fe.phase_site, false, "Error: phase for non-phase site.");
-                            assert_ne!(fe.p, 0, "Error: phase for unexpected allele.");
-                            ps.push(fe.p);
-                            probs.push(fe.prob);
-                            sigma.push(self.fragments[*k].haplotag);
-                        }
-                    }
+                let (q, qn) = if delta_i == 1 { (snp_logq[*i][0], snp_logq[*i][1]) } else { (snp_logq[*i][1], snp_logq[*i][0]) };
+                if q < qn - 1e-9 {
+                    flipped_snps.push(*i);
                 }
-
-                let q = SNPFrag::cal_delta_sigma_log(delta_i, &sigma, &ps, &probs);
-                let qn = SNPFrag::cal_delta_sigma_log(delta_i * (-1), &sigma, &ps, &probs);
-                // println!("optimize delta {} q:{:?}, qn:{:?}, delta: {}", i, q, qn, delta_i);
-                if q < qn {
-                    tmp_haplotype.insert(*i, delta_i * (-1));
-                } else {
-                    tmp_haplotype.insert(*i, delta_i);
+            }
+            dirty_snps.clear();
+            if self.debug_phasing {
+                let mut tmp_haplotype: HashMap<usize, i32> = HashMap::new();
+                for i in flipped_snps.iter() {
+                    tmp_haplotype.insert(*i, self.candidate_snps[*i].haplotype * (-1));
                 }
+                let check_val = SNPFrag::check_new_haplotype(&self, &tmp_haplotype);
+                assert!(check_val >= 0, "ckeck val bug: {:?}", self.candidate_snps);
             }
-            let check_val = SNPFrag::check_new_haplotype(&self, &tmp_haplotype);
-            assert!(check_val >= 0, "ckeck val bug: {:?}", self.candidate_snps);
-            for (i, h) in tmp_haplotype.iter() {
-                // when prob is equal, we still perform the flip to avoid bug of underflow
-                self.candidate_snps[*i].haplotype = *h;
+            let mut touched_frags: HashSet<usize> = HashSet::new();
+            for i in flipped_snps.iter() {
+                let delta_i = self.candidate_snps[*i].haplotype;
+                for (k, p, lc, le) in snp_elems[*i].iter() {
+                    // the term of this SNP moves between the two assignments of the read
+                    let sign = if delta_i == *p { -1.0 } else { 1.0 };
+                    frag_logq[*k][0] += sign * (lc - le);
+                    frag_logq[*k][1] -= sign * (lc - le);
+                    touched_frags.insert(*k);
+                }
+                self.candidate_snps[*i].haplotype = delta_i * (-1);
             }
-            if check_val == 0 {
+            dirty_frags = touched_frags.into_iter().collect();
+            if flipped_snps.len() == 0 {
                 phasing_increase = false;
             } else {
                 phasing_increase = true;
                 haplotag_increase = true;
             }
-            self.check_local_optimal_configuration(true, false);
+            if self.debug_phasing {
+                self.check_local_optimal_configuration(true, false);
+            }
             num_iters += 1;
             if num_iters > 20 {
                 break;
@@ -750,6 +801,7 @@ impl SNPFrag {
         let mut largest_prob = f64::NEG_INFINITY;
         let mut best_haplotype: HashMap<usize, i32> = HashMap::new();
         let mut best_haplotag: HashMap<usize, i32> = HashMap::new();
+        let mut ensemble: Vec<(f64, Vec<i32>)> = Vec::new(); // probability and haplotype of each restart, used for phase confidence
 
         if self.high_frac_het_snps.len() <= max_enum_snps {
             // enumerate the haplotype, then optimize the assignment
@@ -772,6 +824,7 @@ impl SNPFrag {
                     self.init_assignment();
                 }
                 let prob = self.cross_optimize();
+                self.record_configuration(prob, &mut ensemble);
                 if prob > largest_prob {
                     largest_prob = prob;
                     self.save_best_configuration(&mut best_haplotype, &mut best_haplotag);
@@ -790,6 +843,7 @@ impl SNPFrag {
                     self.init_assignment();
                 }
                 let prob = self.cross_optimize();
+                self.record_configuration(prob, &mut ensemble);
                 if prob > largest_prob {
                     largest_prob = prob;
                     self.save_best_configuration(&mut best_haplotype, &mut best_haplotag);
@@ -818,6 +872,7 @@ impl SNPFrag {
                         }
                     }
                     let prob = self.cross_optimize();
+                    self.record_configuration(prob, &mut ensemble);
                     if prob > largest_prob {
                         largest_prob = prob;
                         self.save_best_configuration(&mut best_haplotype, &mut best_haplotag);
@@ -844,6 +899,7 @@ impl SNPFrag {
                         }
                     }
                     let prob = self.cross_optimize();
+                    self.record_configuration(prob, &mut ensemble);
                     if prob > largest_prob {
                         largest_prob = prob;
                         self.save_best_configuration(&mut best_haplotype, &mut best_haplotag);
@@ -855,6 +911,92 @@ impl SNPFrag {
             }
             self.load_best_configuration(&best_haplotype, &best_haplotag);
         }
+        if self.phase_ensemble {
+            self.estimate_phase_confidence(&ensemble);
+        }
+    }
+
+    fn record_configuration(&self, prob: f64, ensemble: &mut Vec<(f64, Vec<i32>)>) {
+        if !self.phase_ensemble {
+            return;
+        }
+        let mut hap: Vec<i32> = Vec::new();
+        for i in self.high_frac_het_snps.iter() {
+            hap.push(self.candidate_snps[*i].haplotype);
+        }
+        ensemble.push((prob, hap));
+    }
+
+    fn estimate_phase_confidence(&mut self, ensemble: &Vec<(f64, Vec<i32>)>) {
+        // Phase confidence of each SNP is the likelihood-weighted fraction of the restarts agreeing with the best solution, a solution
+        // reached by several restarts counts once per restart. Solutions are compared within groups of SNPs linked by reads, after
+        // flipping each group to the orientation of the best solution.
+        let n = self.high_frac_het_snps.len();
+        if n == 0 || ensemble.len() == 0 {
+            return;
+        }
+        let mut col_of: HashMap<usize, usize> = HashMap::new();
+        for (j, i) in self.high_frac_het_snps.iter().enumerate() {
+            col_of.insert(*i, j);
+        }
+        let mut parent: Vec<usize> = (0..n).collect();
+        fn find(parent: &mut Vec<usize>, x: usize) -> usize {
+            let mut r = x;
+            while parent[r] != r {
+                r = parent[r];
+            }
+            parent[x] = r;
+            return r;
+        }
+        for frag in self.fragments.iter() {
+            if frag.haplotag == 0 {
+                continue;
+            }
+            let mut first: Option<usize> = None;
+            for fe in frag.list.iter() {
+                if fe.phase_site == false || !col_of.contains_key(&fe.snp_idx) {
+                    continue;
+                }
+                let j = col_of[&fe.snp_idx];
+                match first {
+                    Some(f) => {
+                        let (rf, rj) = (find(&mut parent, f), find(&mut parent, j));
+                        parent[rj] = rf;
+                    }
+                    None => first = Some(j),
+                }
+            }
+        }
+        let mut group: Vec<usize> = Vec::new();
+        for j in 0..n {
+            group.push(find(&mut parent, j));
+        }
+
+        let best: Vec<i32> = self.high_frac_het_snps.iter().map(|i| self.candidate_snps[*i].haplotype).collect();
+        let best_prob = ensemble.iter().map(|x| x.0).fold(f64::NEG_INFINITY, f64::max);
+        let mut agree: Vec<f64> = vec![0.0; n];
+        let mut total = 0.0;
+        for (prob, hap) in ensemble.iter() {
+            let mut orient: HashMap<usize, i32> = HashMap::new();
+            for j in 0..n {
+                *orient.entry(group[j]).or_insert(0) += hap[j] * best[j];
+            }
+            let aligned: Vec<i32> = (0..n).map(|j| if orient[&group[j]] < 0 { -hap[j] } else { hap[j] }).collect();
+            let w = 10.0_f64.powf(prob - best_prob);
+            total += w;
+            for j in 0..n {
+                if aligned[j] == best[j] {
+                    agree[j] += w;
+                }
+            }
+        }
+        if total == 0.0 {
+            // the best solution is not one of the restarts
+            return;
+        }
+        for (j, i) in self.high_frac_het_snps.iter().enumerate() {
+            self.candidate_snps[*i].phase_confidence = Some(agree[j] / total);
+        }
     }
 
     pub fn assign_het_var_haplotype(
@@ -1309,7 +1451,50 @@ impl SNPFrag {
     }
 
 
-    pub fn assign_phase_set(&mut self) -> HashMap<String, u32> {
+    pub fn collect_read_assignments(&self, records: &Vec<Record>, read_phase_sets: &HashMap<String, u32>) -> HashMap<String, ReadAssignment> {
+        // assignment of every primary read in the region, with the reason for unassigned reads
+        let mut read_assignments: HashMap<String, ReadAssignment> = HashMap::new();
+        let mut frag_idx: HashMap<&String, usize> = HashMap::new();
+        for k in 0..self.fragments.len() {
+            frag_idx.insert(&self.fragments[k].read_id, k);
+        }
+        for record in records.iter() {
+            if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
+                continue;
+            }
+            let qname = std::str::from_utf8(record.qname()).unwrap().to_string();
+            let mut asg = ReadAssignment::default();
+            asg.region = self.region.to_string();
+            asg.ploidy = self.ploidy;
+            asg.reason = ".".to_string();
+            if let Some(k) = frag_idx.get(&qname) {
+                let frag = &self.fragments[*k];
+                asg.haplotype = frag.assignment;
+                asg.num_snps = frag.num_hete_links;
+                if frag.assignment != 0 {
+                    asg.score = frag.assignment_score;
+                    asg.phase_set = *read_phase_sets.get(&qname).unwrap_or(&0);
+                } else if frag.haplotag == 0 {
+                    asg.reason = "unphased_snps".to_string();
+                } else {
+                    asg.reason = "low_confidence".to_string();
+                }
+            } else if let Some(n) = self.unlinked_reads.get(&qname) {
+                asg.num_snps = *n;
+                asg.reason = if *n > 0 { "few_het_snps".to_string() } else { "no_het_snp".to_string() };
+            } else {
+                asg.reason = "no_het_snp".to_string();
+            }
+            if self.ploidy == 1 {
+                asg.reason = "haploid".to_string();
+            }
+            asg.read_id = qname.clone();
+            read_assignments.insert(qname, asg);
+        }
+        return read_assignments;
+    }
+
+    pub fn assign_phase_set(&mut self, min_link_reads: u32, min_link_agreement: f32) -> HashMap<String, u32> {
         let mut phase_set: HashMap<String, u32> = HashMap::new();
         let mut graph: GraphMap<usize, Vec<usize>, Undirected> = GraphMap::new();  // node is index in candidate snp, edge is index in fragments
         // construct graph for hete snps
@@ -1358,13 +1543,16 @@ impl SNPFrag {
                 graph.add_node(i);
             }
         }
+        let mut link_support: HashMap<[usize; 2], [u32; 2]> = HashMap::new(); // reads agreeing and disagreeing with the phase of the two SNPs
         for k in 0..self.fragments.len() {
             let frag = &self.fragments[k];
             if frag.assignment == 0 { continue; }
             let mut node_snps = Vec::new();
+            let mut node_alleles = Vec::new();
             for fe in frag.list.iter() {
                 if graph.contains_node(fe.snp_idx) {
                     node_snps.push(fe.snp_idx);
+                    node_alleles.push(fe.p);
                 }
             }
             if node_snps.len() >= 2 {
@@ -1374,20 +1562,43 @@ impl SNPFrag {
                     } else {
                         graph.edge_weight_mut(node_snps[j], node_snps[j + 1]).unwrap().push(k);
                     }
+                    // the read agrees with the phase if it carries the alleles of the same haplotype at both SNPs
+                    let d1 = self.candidate_snps[node_snps[j]].haplotype;
+                    let d2 = self.candidate_snps[node_snps[j + 1]].haplotype;
+                    let support = link_support.entry([node_snps[j], node_snps[j + 1]]).or_insert([0, 0]);
+                    if node_alleles[j] * d1 == node_alleles[j + 1] * d2 {
+                        support[0] += 1;
+                    } else {
+                        support[1] += 1;
+                    }
                 }
             }
         }
+
+        // remove links supported by few reads or by reads disagreeing with the phase, they may join haplotypes across a switch error
+        let mut weak_links: Vec<([usize; 2], [u32; 2], String)> = Vec::new();
+        for (link, support) in link_support.iter() {
+            let agreement = support[0] as f32 / (support[0] + support[1]) as f32;
+            if agreement < min_link_agreement {
+                weak_links.push((*link, *support, "conflict".to_string()));
+            } else if support[0] < min_link_reads {
+                weak_links.push((*link, *support, "low_support".to_string()));
+            }
+        }
+        weak_links.sort_by(|a, b| a.0.cmp(&b.0));
+        for (link, _, _) in weak_links.iter() {
+            graph.remove_edge(link[0], link[1]);
+        }
+
         let scc = kosaraju_scc(&graph);
         let region = self.region.clone().to_string();
         for component_nodes in scc.iter() {
             if component_nodes.len() <= 1 {
                 continue;
             }
-            let mut phase_id = 0;
+            // the phase set id is the smallest SNP position of the component, independent of the order of the nodes
+            let phase_id = component_nodes.iter().map(|node| (self.candidate_snps[*node].pos + 1) as u32).min().unwrap();  // 1-based;
             for node in component_nodes.iter() {
-                if phase_id == 0 {
-                    phase_id = (self.candidate_snps[*node].pos + 1) as u32;  // 1-based;
-                }
                 self.candidate_snps[*node].phase_set = phase_id;
                 for edge in graph.edges(*node) {
                     let frag_idxes = edge.2;
@@ -1402,10 +1613,85 @@ impl SNPFrag {
                 }
             }
         }
+
+        // a removed link splits the phase block only when no other reads connect the two SNPs
+        let mut component_of: HashMap<usize, usize> = HashMap::new();
+        for (c, component_nodes) in scc.iter().enumerate() {
+            for node in component_nodes.iter() {
+                component_of.insert(*node, c);
+            }
+        }
+        self.phase_splits.clear();
+        for (link, support, reason) in weak_links.iter() {
+            if component_of[&link[0]] == component_of[&link[1]] {
+                continue;
+            }
+            self.phase_splits.push(PhaseSplit {
+                chromosome: self.region.chr.clone(),
+                snp_poses: [self.candidate_snps[link[0]].pos, self.candidate_snps[link[1]].pos],
+                phase_sets: [self.candidate_snps[link[0]].phase_set, self.candidate_snps[link[1]].phase_set],
+                agree_reads: support[0],
+                disagree_reads: support[1],
+                reason: reason.clone(),
+            });
+        }
         return phase_set;
     }
 
-    pub fn detect_somatic_by_het(&mut self, bam_path: &str, region: &Region) {
+    pub fn get_phase_blocks(&self, read_phase_sets: &HashMap<String, u32>) -> Vec<PhaseBlock> {
+        // phase blocks of the region, spanning the phased heterozygous SNPs of each phase set
+        let mut blocks: HashMap<u32, PhaseBlock> = HashMap::new();
+        for snp in self.candidate_snps.iter() {
+            if snp.variant_type != 1 || snp.phase_set == 0 {
+                continue;
+            }
+            let block = blocks.entry(snp.phase_set).or_insert(PhaseBlock {
+                chromosome: self.region.chr.clone(),
+                phase_set: snp.phase_set,
+                start: snp.pos,
+                end: snp.pos,
+                num_snps: 0,
+                num_reads: 0,
+                mean_phase_score: 0.0,
+                read_ids: HashSet::new(),
+            });
+            block.start = block.start.min(snp.pos);
+            block.end = block.end.max(snp.pos);
+            block.num_snps += 1;
+            block.mean_phase_score += snp.phase_score;
+        }
+        for (read_id, ps) in read_phase_sets.iter() {
+            if let Some(block) = blocks.get_mut(ps) {
+                block.read_ids.insert(read_id.clone());
+                block.num_reads = block.read_ids.len() as u32;
+            }
+        }
+        let mut phase_blocks: Vec<PhaseBlock> = Vec::new();
+        for (_, mut block) in blocks.into_iter() {
+            block.mean_phase_score = block.mean_phase_score / block.num_snps as f64;
+            phase_blocks.push(block);
+        }
+        phase_blocks.sort_by(|a, b| a.start.cmp(&b.start));
+        return phase_blocks;
+    }
+
+    pub fn count_het_snps(&self) -> (u32, u32) {
+        // number of heterozygous SNPs and number of them in a phase set
+        let mut het_cnt = 0;
+        let mut phased_cnt = 0;
+        for snp in self.candidate_snps.iter() {
+            if snp.variant_type != 1 || snp.somatic {
+                continue;
+            }
+            het_cnt += 1;
+            if snp.phase_set != 0 {
+                phased_cnt += 1;
+            }
+        }
+        return (het_cnt, phased_cnt);
+    }
+
+    pub fn detect_somatic_by_het(&mut self, records: &Vec<Record>) {
         if self.somatic_snps.len() == 0 {
             return;
         }
@@ -1418,7 +1704,7 @@ impl SNPFrag {
                 phased_fragments.insert(frag.read_id.clone(), frag.assignment);
             }
         }
-        self.get_somatic_haplotype_baseqs(bam_path, region, &phased_fragments);
+        self.get_somatic_haplotype_baseqs(records, &phased_fragments);
         // 2. find candidates meet the criteria of somatic mutation. haplotype-specific
         for i in 0..self.somatic_snps.len() {
             let som_cand = &mut self.candidate_snps[self.somatic_snps[i]];
@@ -1558,4 +1844,246 @@ impl SNPFrag {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snp::FragElem;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn linked_snpfrag() -> SNPFrag {
+        // three phased heterozygous SNPs, two reads link SNP 0 and 1, a single read links SNP 1 and 2
+        let mut snpfrag = SNPFrag::default();
+        snpfrag.region = Region { chr: "chr1".to_string(), start: 1, end: 1000, ..Default::default() };
+        for i in 0..3 {
+            let mut snp = CandidateSNP::default();
+            snp.pos = 100 * (i + 1) as i64;
+            snp.variant_type = 1;
+            snp.haplotype = 1;
+            snp.phase_score = 30.0;
+            snpfrag.candidate_snps.push(snp);
+        }
+        let links: Vec<Vec<usize>> = vec![vec![0, 1], vec![0, 1], vec![1, 2]];
+        for (k, snps) in links.iter().enumerate() {
+            let mut frag = Fragment::default();
+            frag.fragment_idx = k;
+            frag.read_id = format!("read{}", k);
+            frag.assignment = 1;
+            for i in snps.iter() {
+                frag.list.push(FragElem { snp_idx: *i, pos: 100 * (*i + 1) as i64, p: 1, prob: 0.01, phase_site: true, ..Default::default() });
+            }
+            snpfrag.fragments.push(frag);
+        }
+        return snpfrag;
+    }
+
+    #[test]
+    fn phase_set_joined_by_one_read_is_kept_by_default() {
+        let mut snpfrag = linked_snpfrag();
+        let phase_sets = snpfrag.assign_phase_set(1, 0.8);
+        let ps: Vec<u32> = snpfrag.candidate_snps.iter().map(|snp| snp.phase_set).collect();
+        // phase set id is the 1-based position of the first SNP
+        assert_eq!(ps, vec![101; 3]);
+        assert_eq!(phase_sets.len(), 3);
+        assert_eq!(snpfrag.phase_splits.len(), 0);
+    }
+
+    #[test]
+    fn phase_set_joined_by_one_read_is_split_above_one() {
+        let mut snpfrag = linked_snpfrag();
+        snpfrag.assign_phase_set(2, 0.8);
+        let ps: Vec<u32> = snpfrag.candidate_snps.iter().map(|snp| snp.phase_set).collect();
+        assert_eq!(ps[0], ps[1]);
+        // SNP 2 has no other link left and is no longer phased
+        assert_eq!(ps[2], 0);
+        assert_eq!(snpfrag.phase_splits.len(), 1);
+        assert_eq!(snpfrag.phase_splits[0].snp_poses, [200, 300]);
+        assert_eq!(snpfrag.phase_splits[0].agree_reads, 1);
+        assert_eq!(snpfrag.phase_splits[0].reason, "low_support");
+    }
+
+    #[test]
+    fn phase_blocks_span_phased_het_snps() {
+        let mut snpfrag = linked_snpfrag();
+        snpfrag.candidate_snps[0].phase_set = 101;
+        snpfrag.candidate_snps[1].phase_set = 101;
+        snpfrag.candidate_snps[1].phase_score = 20.0;
+        snpfrag.candidate_snps[2].phase_set = 301;
+        let mut hom = CandidateSNP::default();
+        hom.pos = 150;
+        hom.variant_type = 2;
+        hom.phase_set = 101;
+        snpfrag.candidate_snps.insert(1, hom);
+        let read_phase_sets: HashMap<String, u32> =
+            [("read0", 101), ("read1", 101), ("read2", 301), ("read3", 901)].iter().map(|(r, ps)| (r.to_string(), *ps)).collect();
+        let blocks = snpfrag.get_phase_blocks(&read_phase_sets);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!((blocks[0].phase_set, blocks[0].start, blocks[0].end), (101, 100, 200));
+        assert_eq!((blocks[0].num_snps, blocks[0].num_reads), (2, 2));
+        assert_eq!(blocks[0].mean_phase_score, 25.0);
+        assert_eq!((blocks[1].phase_set, blocks[1].start, blocks[1].end), (301, 300, 300));
+        assert_eq!((blocks[1].num_snps, blocks[1].num_reads), (1, 1));
+    }
+
+    #[test]
+    fn phase_confidence_counts_each_restart() {
+        // one read links three SNPs, the third SNP is flipped in one of four restarts
+        let mut snpfrag = linked_snpfrag();
+        snpfrag.high_frac_het_snps = vec![0, 1, 2];
+        snpfrag.fragments[0].list.push(FragElem { snp_idx: 2, pos: 300, p: 1, prob: 0.01, phase_site: true, ..Default::default() });
+        snpfrag.fragments[0].haplotag = 1;
+        let ensemble = vec![(-10.0, vec![1, 1, 1]), (-10.0, vec![1, 1, 1]), (-10.0, vec![1, 1, -1]), (-10.0, vec![-1, -1, -1])];
+        snpfrag.estimate_phase_confidence(&ensemble);
+        let pc: Vec<f64> = snpfrag.candidate_snps.iter().map(|snp| snp.phase_confidence.unwrap()).collect();
+        // the last restart is the best solution with both haplotypes swapped
+        assert_eq!(pc, vec![1.0, 1.0, 0.75]);
+
+        // a less likely restart weighs less
+        let ensemble = vec![(-10.0, vec![1, 1, 1]), (-11.0, vec![1, 1, -1])];
+        snpfrag.estimate_phase_confidence(&ensemble);
+        assert!((snpfrag.candidate_snps[2].phase_confidence.unwrap() - 1.0 / 1.1).abs() < 1e-9);
+        assert_eq!(snpfrag.candidate_snps[0].phase_confidence, Some(1.0));
+    }
+
+    fn random_snpfrag(rng: &mut StdRng) -> SNPFrag {
+        // 15 heterozygous SNPs and one phased SNP outside the optimization, reads with 10% errors over random spans
+        let mut snpfrag = SNPFrag::default();
+        let truth: Vec<i32> = (0..16).map(|_| if rng.gen::<f64>() < 0.5 { 1 } else { -1 }).collect();
+        for i in 0..16 {
+            let mut snp = CandidateSNP::default();
+            snp.pos = 100 * (i + 1) as i64;
+            snp.variant_type = 1;
+            snp.haplotype = if i == 15 { truth[i] } else if rng.gen::<f64>() < 0.5 { 1 } else { -1 };
+            snpfrag.candidate_snps.push(snp);
+        }
+        snpfrag.high_frac_het_snps = (0..15).collect();
+        for k in 0..40 {
+            let start = rng.gen_range(0..15);
+            let end = rng.gen_range(start + 1..17);
+            let sigma = if rng.gen::<f64>() < 0.5 { 1 } else { -1 };
+            let mut frag = Fragment::default();
+            frag.fragment_idx = k;
+            frag.read_id = format!("read{}", k);
+            frag.haplotag = if rng.gen::<f64>() < 0.5 { 1 } else { -1 };
+            for i in start..end {
+                let p = if rng.gen::<f64>() < 0.1 { -sigma * truth[i] } else { sigma * truth[i] };
+                let prob = rng.gen_range(0.01..0.2);
+                frag.list.push(FragElem { snp_idx: i, pos: 100 * (i + 1) as i64, p, prob, phase_site: true, ..Default::default() });
+            }
+            snpfrag.fragments.push(frag);
+        }
+        return snpfrag;
+    }
+
+    fn full_cross_optimize(snpfrag: &mut SNPFrag) -> f64 {
+        // cross_optimize recomputing the likelihood of every read and SNP at each step
+        let fe_logq = |fe: &FragElem, sigma: i32, delta: i32| if sigma * delta == fe.p { (1.0 - fe.prob).log10() } else { fe.prob.log10() };
+        let (mut phasing_increase, mut haplotag_increase) = (true, true);
+        let mut num_iters = 0;
+        while phasing_increase | haplotag_increase {
+            let mut flipped_frags: Vec<usize> = Vec::new();
+            for (k, frag) in snpfrag.fragments.iter().enumerate() {
+                if frag.haplotag == 0 {
+                    continue;
+                }
+                let q: f64 = frag.list.iter().map(|fe| fe_logq(fe, frag.haplotag, snpfrag.candidate_snps[fe.snp_idx].haplotype)).sum();
+                let qn: f64 = frag.list.iter().map(|fe| fe_logq(fe, -frag.haplotag, snpfrag.candidate_snps[fe.snp_idx].haplotype)).sum();
+                if q < qn - 1e-9 {
+                    flipped_frags.push(k);
+                }
+            }
+            for k in flipped_frags.iter() {
+                snpfrag.fragments[*k].haplotag *= -1;
+            }
+            haplotag_increase = flipped_frags.len() > 0;
+            phasing_increase = phasing_increase || haplotag_increase;
+
+            let mut flipped_snps: Vec<usize> = Vec::new();
+            for i in snpfrag.high_frac_het_snps.iter() {
+                let delta = snpfrag.candidate_snps[*i].haplotype;
+                let (mut q, mut qn) = (0.0, 0.0);
+                for frag in snpfrag.fragments.iter() {
+                    for fe in frag.list.iter().filter(|fe| fe.snp_idx == *i) {
+                        q += fe_logq(fe, frag.haplotag, delta);
+                        qn += fe_logq(fe, frag.haplotag, -delta);
+                    }
+                }
+                if q < qn - 1e-9 {
+                    flipped_snps.push(*i);
+                }
+            }
+            for i in flipped_snps.iter() {
+                snpfrag.candidate_snps[*i].haplotype *= -1;
+            }
+            phasing_increase = flipped_snps.len() > 0;
+            haplotag_increase = haplotag_increase || phasing_increase;
+            num_iters += 1;
+            if num_iters > 20 {
+                break;
+            }
+        }
+        return SNPFrag::cal_overall_probability(snpfrag);
+    }
+
+    #[test]
+    fn incremental_cross_optimize_matches_full_recomputation() {
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..50 {
+            let mut snpfrag = random_snpfrag(&mut rng);
+            let mut full = snpfrag.clone();
+            snpfrag.debug_phasing = true;
+            let prob = snpfrag.cross_optimize();
+            let full_prob = full_cross_optimize(&mut full);
+            assert!((prob - full_prob).abs() < 1e-9, "{} != {}", prob, full_prob);
+            let haps: Vec<i32> = snpfrag.candidate_snps.iter().map(|snp| snp.haplotype).collect();
+            assert_eq!(haps, full.candidate_snps.iter().map(|snp| snp.haplotype).collect::<Vec<i32>>());
+            let tags: Vec<i32> = snpfrag.fragments.iter().map(|frag| frag.haplotag).collect();
+            assert_eq!(tags, full.fragments.iter().map(|frag| frag.haplotag).collect::<Vec<i32>>());
+        }
+    }
+
+    fn read_record(qname: &str) -> Record {
+        let mut record = Record::new();
+        record.set(qname.as_bytes(), None, b"ACGT", &[30, 30, 30, 30]);
+        record.unset_unmapped();
+        return record;
+    }
+
+    #[test]
+    fn read_assignment_reasons() {
+        let mut snpfrag = linked_snpfrag();
+        snpfrag.ploidy = 2;
+        snpfrag.fragments[0].assignment_score = 0.9;
+        snpfrag.fragments[0].num_hete_links = 2;
+        snpfrag.fragments[1].assignment = 0;
+        snpfrag.fragments[1].haplotag = 0;
+        snpfrag.fragments[2].assignment = 0;
+        snpfrag.fragments[2].haplotag = 1;
+        snpfrag.unlinked_reads.insert("read3".to_string(), 1);
+        snpfrag.unlinked_reads.insert("read4".to_string(), 0);
+        let mut records: Vec<Record> = ["read0", "read1", "read2", "read3", "read4", "read5"].iter().map(|q| read_record(q)).collect();
+        let mut secondary = read_record("secondary");
+        secondary.set_secondary();
+        records.push(secondary);
+        let read_phase_sets: HashMap<String, u32> = [("read0".to_string(), 101)].into_iter().collect();
+
+        let asgs = snpfrag.collect_read_assignments(&records, &read_phase_sets);
+        assert_eq!(asgs.len(), 6);
+        let asg = &asgs["read0"];
+        assert_eq!((asg.haplotype, asg.phase_set, asg.num_snps, asg.reason.as_str()), (1, 101, 2, "."));
+        assert_eq!(asg.score, 0.9);
+        assert_eq!(asg.region, "chr1:1-1000");
+        assert_eq!(asgs["read1"].reason, "unphased_snps");
+        assert_eq!(asgs["read2"].reason, "low_confidence");
+        assert_eq!(asgs["read2"].phase_set, 0);
+        assert_eq!((asgs["read3"].reason.as_str(), asgs["read3"].num_snps), ("few_het_snps", 1));
+        assert_eq!(asgs["read4"].reason, "no_het_snp");
+        assert_eq!(asgs["read5"].reason, "no_het_snp");
+
+        snpfrag.ploidy = 1;
+        let asgs = snpfrag.collect_read_assignments(&records, &HashMap::new());
+        assert!(asgs.values().all(|asg| asg.reason == "haploid"));
+    }
+}
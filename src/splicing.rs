@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+
+use fishers_exact::fishers_exact;
+
+use crate::ase::benjamini_hochberg;
+use crate::phase_link::PhaseSetLink;
+use crate::snpfrags::SNPFrag;
+
+#[derive(Debug, Clone, Default)]
+pub struct SpliceJunction {
+    pub chromosome: String,
+    pub start: i64,
+    pub end: i64,
+    // intron on the reference, 0-based, [start, end)
+    pub phase_set: u32,
+    pub hap1_reads: u32,
+    pub hap2_reads: u32,
+    // haplotagged reads splicing the junction
+    pub hap1_total: u32,
+    pub hap2_total: u32,
+    // haplotagged reads splicing the junction or a junction sharing its donor or acceptor
+    pub pvalue: f64,
+    pub qvalue: f64,
+}
+
+impl SpliceJunction {
+    pub fn psi(&self) -> (f64, f64) {
+        // percent spliced in of each haplotype
+        let hap1_psi = if self.hap1_total > 0 { self.hap1_reads as f64 / self.hap1_total as f64 } else { 0.0 };
+        let hap2_psi = if self.hap2_total > 0 { self.hap2_reads as f64 / self.hap2_total as f64 } else { 0.0 };
+        return (hap1_psi, hap2_psi);
+    }
+}
+
+impl SNPFrag {
+    pub fn collect_splice_junctions(&self, read_phase_sets: &HashMap<String, u32>) -> Vec<SpliceJunction> {
+        // haplotagged reads of each splice junction and phase set of the region, junctions are the gaps between consecutive exons of a read
+        let mut junctions: HashMap<(i64, i64, u32), SpliceJunction> = HashMap::new();
+        for frag in self.fragments.iter() {
+            if frag.assignment != 1 && frag.assignment != 2 {
+                continue;
+            }
+            let ps = match read_phase_sets.get(&frag.read_id) {
+                Some(v) => *v,
+                None => continue,
+            };
+            for i in 1..frag.exons.len() {
+                let (start, end) = (frag.exons[i - 1].end, frag.exons[i].start);
+                let junc = junctions.entry((start, end, ps)).or_insert(SpliceJunction {
+                    chromosome: self.region.chr.clone(),
+                    start: start,
+                    end: end,
+                    phase_set: ps,
+                    ..Default::default()
+                });
+                if frag.assignment == 1 {
+                    junc.hap1_reads += 1;
+                } else {
+                    junc.hap2_reads += 1;
+                }
+            }
+        }
+        return junctions.into_values().collect();
+    }
+}
+
+pub fn relabel_splice_junctions(junctions: &mut VecDeque<SpliceJunction>, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    for junc in junctions.iter_mut() {
+        if let Some(link) = phase_set_links.get(&(junc.chromosome.clone(), junc.phase_set)) {
+            junc.phase_set = link.phase_set;
+            if link.flip {
+                std::mem::swap(&mut junc.hap1_reads, &mut junc.hap2_reads);
+            }
+        }
+    }
+}
+
+pub fn test_splice_junctions(junctions: &VecDeque<SpliceJunction>) -> Vec<SpliceJunction> {
+    // merge the junctions collected by different regions. A junction sharing its donor or acceptor with other junctions of the
+    // same phase set is alternatively spliced, its usage against the competing junctions is compared between haplotypes with
+    // Fisher's exact test and corrected for multiple testing. Constitutive junctions are not reported.
+    let mut merged: Vec<SpliceJunction> = Vec::new();
+    let mut merged_idx: HashMap<(String, u32, i64, i64), usize> = HashMap::new();
+    for junc in junctions.iter() {
+        let key = (junc.chromosome.clone(), junc.phase_set, junc.start, junc.end);
+        match merged_idx.get(&key) {
+            Some(i) => {
+                merged[*i].hap1_reads += junc.hap1_reads;
+                merged[*i].hap2_reads += junc.hap2_reads;
+            }
+            None => {
+                merged_idx.insert(key, merged.len());
+                merged.push(junc.clone());
+            }
+        }
+    }
+    let mut donors: HashMap<(String, u32, i64), Vec<usize>> = HashMap::new();
+    let mut acceptors: HashMap<(String, u32, i64), Vec<usize>> = HashMap::new();
+    for (i, junc) in merged.iter().enumerate() {
+        donors.entry((junc.chromosome.clone(), junc.phase_set, junc.start)).or_insert(Vec::new()).push(i);
+        acceptors.entry((junc.chromosome.clone(), junc.phase_set, junc.end)).or_insert(Vec::new()).push(i);
+    }
+    let mut alternative: Vec<SpliceJunction> = Vec::new();
+    for junc in merged.iter() {
+        let mut competitors: Vec<usize> = donors.get(&(junc.chromosome.clone(), junc.phase_set, junc.start)).unwrap().clone();
+        for j in acceptors.get(&(junc.chromosome.clone(), junc.phase_set, junc.end)).unwrap().iter() {
+            if !competitors.contains(j) {
+                competitors.push(*j);
+            }
+        }
+        if competitors.len() < 2 {
+            continue;
+        }
+        let mut junc = junc.clone();
+        junc.hap1_total = competitors.iter().map(|j| merged[*j].hap1_reads).sum();
+        junc.hap2_total = competitors.iter().map(|j| merged[*j].hap2_reads).sum();
+        let table = [junc.hap1_reads, junc.hap2_reads, junc.hap1_total - junc.hap1_reads, junc.hap2_total - junc.hap2_reads];
+        junc.pvalue = fishers_exact(&table).unwrap().two_tail_pvalue.min(1.0);
+        alternative.push(junc);
+    }
+    let qvalues = benjamini_hochberg(&alternative.iter().map(|j| j.pvalue).collect());
+    for (junc, q) in alternative.iter_mut().zip(qvalues.into_iter()) {
+        junc.qvalue = q;
+    }
+    return alternative;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn junction(phase_set: u32, start: i64, end: i64, hap1_reads: u32, hap2_reads: u32) -> SpliceJunction {
+        return SpliceJunction { chromosome: "chr1".to_string(), start, end, phase_set, hap1_reads, hap2_reads, ..Default::default() };
+    }
+
+    #[test]
+    fn competitors_share_donor_or_acceptor() {
+        let mut junctions: VecDeque<SpliceJunction> = VecDeque::new();
+        junctions.push_back(junction(101, 100, 200, 6, 0));
+        junctions.push_back(junction(101, 100, 200, 4, 0)); // counted by a second region
+        junctions.push_back(junction(101, 100, 300, 0, 8)); // same donor
+        junctions.push_back(junction(101, 250, 300, 2, 2)); // same acceptor as the second junction only
+        junctions.push_back(junction(101, 500, 600, 5, 5)); // constitutive
+        junctions.push_back(junction(9001, 100, 400, 3, 0)); // same donor in another phase set
+        let tested = test_splice_junctions(&junctions);
+        let find = |start: i64, end: i64| tested.iter().find(|j| j.start == start && j.end == end).unwrap();
+        assert_eq!(tested.len(), 3);
+
+        let j1 = find(100, 200);
+        assert_eq!((j1.hap1_reads, j1.hap2_reads, j1.hap1_total, j1.hap2_total), (10, 0, 10, 8));
+        assert_eq!(j1.psi(), (1.0, 0.0));
+        assert!(j1.pvalue < 1e-4);
+        let j2 = find(100, 300);
+        assert_eq!((j2.hap1_total, j2.hap2_total), (12, 10));
+        let j3 = find(250, 300);
+        assert_eq!((j3.hap1_total, j3.hap2_total), (2, 10));
+        assert!(tested.iter().all(|j| j.phase_set == 101 && j.qvalue >= j.pvalue));
+    }
+}
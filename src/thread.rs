@@ -6,13 +6,77 @@ use std::sync::Mutex;
 
 use rayon::iter::IntoParallelRefIterator;
 use rayon::prelude::*;
-use rust_htslib::{bam, bam::ext::BamRecordExtensions, bam::Format, bam::Read, bam::record::Aux};
+use rust_htslib::{bam, bam::ext::BamRecordExtensions, bam::Format, bam::Read};
 use rust_lapper::Interval;
 
-use crate::exon::{Exon, exon_cluster};
-use crate::Platform;
+use crate::exon::{HaplotypeExon, relabel_haplotype_exons};
+use crate::ase::{AseUnit, benjamini_hochberg, estimate_dispersion, relabel_ase_units, test_ase_units};
+use crate::monoallelic::{dominant_haplotype, is_imprinted, load_imprinted_genes, monoallelic_status};
+use crate::isoform::{IsoformCount, load_read_isoforms, relabel_isoform_counts, tagged_read_isoforms, test_isoform_usage};
+use crate::heteroplasmy::{call_heteroplasmy, Heteroplasmy, heteroplasmy_vcf_records, is_mito_contig};
+use crate::haplotag::{build_bam_chunks, chunk_bam_path, concat_chunk_bams, ReadAssignment, split_chunk_bams, write_gap_bam, write_region_bam};
+use crate::{AseModel, ExonFormat, PhasingAlgorithm, Platform, Sex};
+use crate::phase_link::{link_phase_sets, merge_phase_blocks, PhaseSetLink, relabel_read_assignment, relabel_vcf_record, TrioGenotypes};
+use crate::polyploid::PloidyRegions;
+use crate::snp::{PhaseBlock, PhaseSplit};
 use crate::snpfrags::SNPFrag;
-use crate::util::{load_reference, parse_fai, Profile, Region};
+use crate::transcript_end::{EndSiteCluster, EndSiteCount, relabel_end_sites, test_end_sites, transcript_strands};
+use crate::splicing::{relabel_splice_junctions, SpliceJunction, test_splice_junctions};
+use crate::vcf::VCFRecord;
+use crate::util::{calculate_n50, fetch_region_records, load_reference, parse_fai, Profile, Region};
+
+// options of the indel, read output, phasing, ploidy and expression features, grouped by feature
+#[derive(Debug, Clone)]
+pub struct PhaseOptions {
+    // indels and MNVs
+    pub call_indels: bool,
+    pub min_indel_freq: f32,
+    pub min_indel_cnt: u32,
+    pub mnv_window: u32,
+    // read assignment outputs
+    pub assignment_tags: bool,
+    pub min_haplotype_reads: u32,
+    pub haplotype_fastq_output: bool,
+    // phasing backend
+    pub phasing_algorithm: PhasingAlgorithm,
+    pub max_dp_coverage: usize,
+    pub debug_phasing: bool,
+    pub phase_ensemble: bool,
+    // phase set splitting and linking
+    pub min_link_reads: u32,
+    pub min_link_agreement: f32,
+    pub link_phase_set: bool,
+    pub phase_stats: bool,
+    pub dna_vcf: Option<String>,
+    pub paternal_vcf: Option<String>,
+    pub maternal_vcf: Option<String>,
+    // ploidy and heteroplasmy
+    pub ploidy: u32,
+    pub ploidy_bed: Option<String>,
+    pub sex: Option<Sex>,
+    pub heteroplasmy: bool,
+    pub min_heteroplasmy_frac: f32,
+    pub min_heteroplasmy_cnt: u32,
+    // allele-specific expression and haplotype-specific transcript features
+    pub ase: bool,
+    pub max_ase_qvalue: f64,
+    pub ase_model: AseModel,
+    pub read_isoform: Option<String>,
+    pub isoform_tag: Option<String>,
+    pub max_isoform_qvalue: f64,
+    pub haplotype_splicing: bool,
+    pub max_splicing_qvalue: f64,
+    pub min_delta_psi: f64,
+    pub haplotype_exon_format: ExonFormat,
+    pub haplotype_end_site: bool,
+    pub end_site_distance: u32,
+    pub min_end_site_reads: u32,
+    pub max_end_site_qvalue: f64,
+    pub monoallelic_report: bool,
+    pub max_monoallelic_frac: f32,
+    pub min_monoallelic_reads: u32,
+    pub imprinted_genes: Option<String>,
+}
 
 pub fn multithread_phase_haplotag(
     bam_file: String,
@@ -56,14 +120,72 @@ pub fn multithread_phase_haplotag(
     min_sup_haplotype_exon: u32,
     somatic_allele_frac_cutoff: f32,
     somatic_allele_cnt_cutoff: u32,
+    options: PhaseOptions,
 ) {
+    let PhaseOptions {
+        call_indels,
+        min_indel_freq,
+        min_indel_cnt,
+        mnv_window,
+        assignment_tags,
+        min_haplotype_reads,
+        haplotype_fastq_output,
+        phasing_algorithm,
+        max_dp_coverage,
+        debug_phasing,
+        phase_ensemble,
+        min_link_reads,
+        min_link_agreement,
+        link_phase_set,
+        phase_stats,
+        dna_vcf,
+        paternal_vcf,
+        maternal_vcf,
+        ploidy,
+        ploidy_bed,
+        sex,
+        heteroplasmy,
+        min_heteroplasmy_frac,
+        min_heteroplasmy_cnt,
+        ase,
+        max_ase_qvalue,
+        ase_model,
+        read_isoform,
+        isoform_tag,
+        max_isoform_qvalue,
+        haplotype_splicing,
+        max_splicing_qvalue,
+        min_delta_psi,
+        haplotype_exon_format,
+        haplotype_end_site,
+        end_site_distance,
+        min_end_site_reads,
+        max_end_site_qvalue,
+        monoallelic_report,
+        max_monoallelic_frac,
+        min_monoallelic_reads,
+        imprinted_genes,
+    } = options;
+    let (phasing_algorithm, sex, ase_model, haplotype_exon_format) = (&phasing_algorithm, &sex, &ase_model, &haplotype_exon_format);
     let pool = rayon::ThreadPoolBuilder::new().num_threads(thread_size).build().unwrap();
     let vcf_records_queue = Mutex::new(VecDeque::new());
     let read_haplotag1_queue = Mutex::new(VecDeque::new());
     let read_haplotag2_queue = Mutex::new(VecDeque::new());
     let read_haplotag_queue = Mutex::new(VecDeque::new());
-    let read_phaseset_queue = Mutex::new(VecDeque::new());
     let haplotype_exon_queue = Mutex::new(VecDeque::new());
+    let phase_split_queue = Mutex::new(VecDeque::new());
+    let phase_block_queue = Mutex::new(VecDeque::new());
+    let het_snp_count = Mutex::new((0, 0)); // heterozygous SNPs, phased heterozygous SNPs
+    let ase_queue = Mutex::new(VecDeque::new());
+    let ase_snp_queue = Mutex::new(Vec::new()); // hap1 and hap2 expression of phased SNPs
+    let isoform_queue = Mutex::new(VecDeque::new());
+    let splice_junction_queue = Mutex::new(VecDeque::new());
+    let end_site_queue = Mutex::new(VecDeque::new());
+    let haplotype_isoform = read_isoform.is_some() || isoform_tag.is_some();
+    let read_isoforms = match &read_isoform {
+        Some(f) => load_read_isoforms(f),
+        None => HashMap::new(),
+    };
     let ref_seqs = load_reference(ref_file.clone());
     let fai_path = ref_file + ".fai";
     if fs::metadata(&fai_path).is_err() {
@@ -75,25 +197,53 @@ pub fn multithread_phase_haplotag(
         contig_order.push(k.clone());
     }
 
+    // tagged reads of each region are written to temporary bams and merged after all regions are done
+    let tmp_dir = phased_bam_file.clone() + ".tmp";
+    let bam_header_view = bam::IndexedReader::from_path(&bam_file).unwrap().header().clone();
+    let bam_header = bam::Header::from_template(&bam_header_view);
+    let (bam_chunks, region_chunks) = build_bam_chunks(&isolated_regions, &bam_header_view);
+    let ploidy_regions = PloidyRegions::load(ploidy_bed.clone(), ploidy, sex, &contig_lengths);
+    if !no_bam_output {
+        fs::create_dir_all(&tmp_dir).unwrap();
+    }
+
     pool.install(|| {
-        isolated_regions.par_iter().for_each(|reg| {
+        isolated_regions.par_iter().enumerate().for_each(|(reg_idx, reg)| {
             let mut profile = Profile::default();
             let ref_seq = ref_seqs.get(&reg.chr).unwrap();
             let mut exon_region_vec = Vec::new();
+            let mut gene_exons: HashMap<String, Vec<Interval<usize, u8>>> = HashMap::new();
             if !reg.gene_id.is_none() {
                 let gene_id_field = reg.gene_id.clone().unwrap();
                 for gene_id in gene_id_field.split(",").collect::<Vec<&str>>() {
                     if exon_regions.contains_key(gene_id) {
                         exon_region_vec.extend(exon_regions.get(gene_id).unwrap().clone());
+                        gene_exons.insert(gene_id.to_string(), exon_regions.get(gene_id).unwrap().clone());
                     }
                 }
                 if exon_region_vec.len() == 0 {
                     // this region is done, no exon region covered
+                    if !no_bam_output && region_chunks.contains_key(&reg_idx) {
+                        let chunk_idx = region_chunks[&reg_idx];
+                        let region_records = fetch_region_records(&bam_file.as_str(), &reg);
+                        write_region_bam(&chunk_bam_path(&tmp_dir, chunk_idx), &bam_header, &region_records, &bam_chunks[chunk_idx], &HashMap::new(), assignment_tags);
+                    }
                     return;
                 }
             }
+            if heteroplasmy && is_mito_contig(&reg.chr) {
+                // called on the whole contig after all regions are done
+                if !no_bam_output && region_chunks.contains_key(&reg_idx) {
+                    let chunk_idx = region_chunks[&reg_idx];
+                    let region_records = fetch_region_records(&bam_file.as_str(), &reg);
+                    write_region_bam(&chunk_bam_path(&tmp_dir, chunk_idx), &bam_header, &region_records, &bam_chunks[chunk_idx], &HashMap::new(), assignment_tags);
+                }
+                return;
+            }
+            // decode the reads of this region once
+            let region_records = fetch_region_records(&bam_file.as_str(), &reg);
             profile.init_with_pileup(
-                &bam_file.as_str(),
+                &region_records,
                 &reg,
                 ref_seq,
                 platform,
@@ -104,10 +254,27 @@ pub fn multithread_phase_haplotag(
                 max_depth,
                 distance_to_read_end,
                 polya_tail_len,
+                call_indels,
             );
             let mut snpfrag = SNPFrag::default();
             snpfrag.region = reg.clone();
             snpfrag.min_linkers = min_linkers;
+            snpfrag.mnv_window = mnv_window;
+            snpfrag.debug_phasing = debug_phasing;
+            snpfrag.phase_ensemble = phase_ensemble;
+            snpfrag.ploidy = ploidy_regions.region_ploidy(&reg);
+            if call_indels {
+                snpfrag.get_candidate_indels(
+                    &profile,
+                    ref_seq,
+                    &platform,
+                    exon_region_vec.clone(),
+                    min_indel_freq,
+                    min_indel_cnt,
+                    min_depth,
+                    max_depth,
+                );
+            }
             snpfrag.get_candidate_snps(
                 &profile,
                 &platform,
@@ -129,11 +296,65 @@ pub fn multithread_phase_haplotag(
                 somatic_allele_cnt_cutoff,
                 genotype_only,
             );
+            if snpfrag.ploidy > 2 && !genotype_only {
+                snpfrag.init_polyploid_sites();
+            }
             // TODO: for very high depth region, down-sampling the reads
-            snpfrag.get_fragments(&bam_file, &reg);
+            if snpfrag.ploidy > 1 {
+                snpfrag.get_fragments(&region_records, &reg, ref_seq);
+            }
+            let mut region_read_assignments: HashMap<String, ReadAssignment> = HashMap::new();
             if genotype_only {
                 // without phasing
-                let vcf_records = snpfrag.output_vcf(min_qual_for_candidate);
+                let vcf_records = if snpfrag.ploidy != 2 {
+                    snpfrag.output_polyploid_vcf(min_qual_for_candidate)
+                } else {
+                    snpfrag.output_vcf(ref_seq, min_qual_for_candidate)
+                };
+                {
+                    let mut queue = vcf_records_queue.lock().unwrap();
+                    for rd in vcf_records.iter() {
+                        queue.push_back(rd.clone());
+                    }
+                }
+            } else if snpfrag.ploidy == 1 {
+                // hemizygous variants, nothing to phase
+                region_read_assignments = snpfrag.collect_read_assignments(&region_records, &HashMap::new());
+                if !no_bam_output || output_read_assignment {
+                    let mut queue = read_haplotag_queue.lock().unwrap();
+                    for a in region_read_assignments.values() {
+                        queue.push_back(a.clone());
+                    }
+                }
+                let vcf_records = snpfrag.output_polyploid_vcf(min_qual_for_candidate);
+                {
+                    let mut queue = vcf_records_queue.lock().unwrap();
+                    for rd in vcf_records.iter() {
+                        queue.push_back(rd.clone());
+                    }
+                }
+            } else if snpfrag.ploidy > 2 {
+                // phase the reads into k haplotypes, haplotype bams and haplotype specific exons are only produced for diploid regions
+                snpfrag.phase_polyploid(max_iters, read_assignment_cutoff, min_phase_score);
+                let phase_sets = snpfrag.assign_polyploid_phase_set();
+                region_read_assignments = snpfrag.collect_read_assignments(&region_records, &phase_sets);
+                {
+                    let mut queue = phase_block_queue.lock().unwrap();
+                    for block in snpfrag.get_phase_blocks(&phase_sets).iter() {
+                        queue.push_back(block.clone());
+                    }
+                    let (het_cnt, phased_cnt) = snpfrag.count_het_snps();
+                    let mut count = het_snp_count.lock().unwrap();
+                    count.0 += het_cnt;
+                    count.1 += phased_cnt;
+                }
+                if !no_bam_output || output_read_assignment || link_phase_set {
+                    let mut queue = read_haplotag_queue.lock().unwrap();
+                    for a in region_read_assignments.values() {
+                        queue.push_back(a.clone());
+                    }
+                }
+                let vcf_records = snpfrag.output_polyploid_vcf(min_qual_for_candidate);
                 {
                     let mut queue = vcf_records_queue.lock().unwrap();
                     for rd in vcf_records.iter() {
@@ -148,31 +369,73 @@ pub fn multithread_phase_haplotag(
                     unsafe {
                         snpfrag.init_assignment();
                     }
-                    snpfrag.phase(max_enum_snps, random_flip_fraction, max_iters);
+                    match phasing_algorithm {
+                        PhasingAlgorithm::heuristic => {
+                            snpfrag.phase(max_enum_snps, random_flip_fraction, max_iters);
+                        }
+                        PhasingAlgorithm::dp => {
+                            snpfrag.phase_dp(max_dp_coverage);
+                        }
+                    }
                     let read_assignments = snpfrag.assign_reads_haplotype(read_assignment_cutoff);
                     snpfrag.assign_het_var_haplotype(min_phase_score, somatic_allele_frac_cutoff, somatic_allele_cnt_cutoff);
                     snpfrag.eval_low_frac_het_var_phase(min_phase_score, somatic_allele_frac_cutoff, somatic_allele_cnt_cutoff);
                     snpfrag.eval_rna_edit_var_phase(min_phase_score);
                     snpfrag.eval_hom_var_phase(min_phase_score);
+                    snpfrag.eval_indel_phase(min_phase_score);
                     // assign phased fragments to somatic mutations and detect condifent somatic mutations
                     // println!("somatic: {}", snpfrag.somatic_snps.len());
-                    snpfrag.detect_somatic_by_het(&bam_file.as_str(), &reg);
+                    snpfrag.detect_somatic_by_het(&region_records);
                     // snpfrag.phase_ase_hete_snps(max_enum_snps, random_flip_fraction, max_iters);
                     // assign reads to haplotypes, filter reads having conflicted ase snps and heterozygous snps
                     // let read_assignments_ase = snpfrag.assign_reads_ase(read_assignment_cutoff);
                     // snpfrag.rescue_ase_snps();
                     // snpfrag.rescue_ase_snps_v2(ase_allele_cnt_cutoff, ase_ps_count_cutoff, ase_ps_cutoff);
 
-                    // merge read_assignments and read_assignments_ase, read_assignments_ase first, then read_assignments
-                    let mut merge_reads_assignments = read_assignments.clone();
-                    // for (k, v) in read_assignments.iter() {
-                    //     if !read_assignments_ase.contains_key(k) {
-                    //         merge_reads_assignments.insert(k.clone(), v.clone());
-                    //     }
-                    // }
-                    let phase_sets = snpfrag.assign_phase_set();
+                    let phase_sets = snpfrag.assign_phase_set(min_link_reads, min_link_agreement);
+                    {
+                        let mut queue = phase_split_queue.lock().unwrap();
+                        for sp in snpfrag.phase_splits.iter() {
+                            queue.push_back(sp.clone());
+                        }
+                    }
+                    snpfrag.assign_indel_phase_set(&phase_sets);
+                    region_read_assignments = snpfrag.collect_read_assignments(&region_records, &phase_sets);
+                    if ase || monoallelic_report {
+                        let mut queue = ase_queue.lock().unwrap();
+                        queue.extend(snpfrag.collect_ase_units(&gene_exons, &phase_sets, max_monoallelic_frac));
+                        ase_snp_queue.lock().unwrap().extend(snpfrag.collect_ase_snp_counts());
+                    }
+                    if haplotype_isoform {
+                        let tagged_isoforms;
+                        let region_isoforms = match &isoform_tag {
+                            Some(tag) => {
+                                tagged_isoforms = tagged_read_isoforms(&region_records, tag);
+                                &tagged_isoforms
+                            }
+                            None => &read_isoforms,
+                        };
+                        isoform_queue.lock().unwrap().extend(snpfrag.collect_isoform_counts(region_isoforms, &gene_exons, &phase_sets));
+                    }
+                    if haplotype_splicing {
+                        splice_junction_queue.lock().unwrap().extend(snpfrag.collect_splice_junctions(&phase_sets));
+                    }
+                    if haplotype_end_site {
+                        let read_strands = transcript_strands(&region_records);
+                        end_site_queue.lock().unwrap().extend(snpfrag.collect_end_sites(&read_strands, &gene_exons, &phase_sets));
+                    }
+                    {
+                        let mut queue = phase_block_queue.lock().unwrap();
+                        for block in snpfrag.get_phase_blocks(&phase_sets).iter() {
+                            queue.push_back(block.clone());
+                        }
+                        let (het_cnt, phased_cnt) = snpfrag.count_het_snps();
+                        let mut count = het_snp_count.lock().unwrap();
+                        count.0 += het_cnt;
+                        count.1 += phased_cnt;
+                    }
 
-                    let mut haplotype_exons: Vec<(Exon, i32, i32)> = Vec::new();
+                    let mut haplotype_exons: Vec<HaplotypeExon> = Vec::new();
                     {
                         if haplotype_bam_output || haplotype_specific_exon {
                             let mut queue1 = read_haplotag1_queue.lock().unwrap();
@@ -186,10 +449,9 @@ pub fn multithread_phase_haplotag(
                                 } else if *a.1 == 2 {
                                     hap2_read_count += 1;
                                 }
-                                if hap1_read_count >= 10 && hap2_read_count >= 10 {
-                                    haplotype_read_count_pass = true;
-                                    break;
-                                }
+                            }
+                            if hap1_read_count >= min_haplotype_reads && hap2_read_count >= min_haplotype_reads {
+                                haplotype_read_count_pass = true;
                             }
                             if haplotype_bam_output && haplotype_read_count_pass {
                                 for a in read_assignments.iter() {
@@ -201,154 +463,7 @@ pub fn multithread_phase_haplotag(
                                 }
                             }
                             if haplotype_specific_exon && haplotype_read_count_pass {
-                                let mut hap1_exons: Vec<Exon> = Vec::new();
-                                let mut hap2_exons: Vec<Exon> = Vec::new();
-                                let mut hap1_smallest_start = 0;
-                                let mut hap1_largest_end = 0;
-                                let mut hap2_smallest_start = 0;
-                                let mut hap2_largest_end = 0;
-                                // collect exons
-                                for frag in snpfrag.fragments.iter() {
-                                    if frag.assignment == 1 {
-                                        for e in frag.exons.iter() {
-                                            hap1_exons.push(e.clone());
-                                            if hap1_smallest_start == 0 || e.start < hap1_smallest_start {
-                                                hap1_smallest_start = e.start;
-                                            }
-                                            if e.end > hap1_largest_end {
-                                                hap1_largest_end = e.end;
-                                            }
-                                        }
-                                    } else if frag.assignment == 2 {
-                                        for e in frag.exons.iter() {
-                                            hap2_exons.push(e.clone());
-                                            if hap2_smallest_start == 0 || e.start < hap2_smallest_start {
-                                                hap2_smallest_start = e.start;
-                                            }
-                                            if e.end > hap2_largest_end {
-                                                hap2_largest_end = e.end;
-                                            }
-                                        }
-                                    }
-                                }
-                                // consensus exons
-                                let hap1_consensus_exons = exon_cluster(
-                                    hap1_exons.clone(),
-                                    hap1_smallest_start,
-                                    hap1_largest_end,
-                                    0,
-                                );
-                                let hap2_consensus_exons = exon_cluster(
-                                    hap2_exons.clone(),
-                                    hap2_smallest_start,
-                                    hap2_largest_end,
-                                    0,
-                                );
-                                let mut combined_consensus_exons: HashMap<Exon, (i32, i32)> = HashMap::new();
-                                for (e, v) in hap1_consensus_exons.iter() {
-                                    if combined_consensus_exons.contains_key(e) {
-                                        let (c1, c2) = combined_consensus_exons.get_mut(e).unwrap();
-                                        *c1 += v.len() as i32;
-                                    } else {
-                                        combined_consensus_exons.insert(e.clone(), (v.len() as i32, 0));
-                                    }
-                                }
-                                for (e, v) in hap2_consensus_exons.iter() {
-                                    if combined_consensus_exons.contains_key(e) {
-                                        let (c1, c2) = combined_consensus_exons.get_mut(e).unwrap();
-                                        *c2 += v.len() as i32;
-                                    } else {
-                                        combined_consensus_exons.insert(e.clone(), (0, v.len() as i32));
-                                    }
-                                }
-                                for (e, counts) in combined_consensus_exons.iter() {
-                                    if counts.0 * counts.1 == 0 && counts.0 + counts.1 >= min_sup_haplotype_exon as i32 {
-                                        if e.state == 1 {
-                                            // println!("exon1: {}:{}-{}, hap1:{:?}, hap2:{:?}", e.chr, e.start + 1, e.end + 1, counts.0, counts.1);
-                                            haplotype_exons.push((e.clone(), counts.0, counts.1));
-                                        }
-                                        if e.state == 0 {
-                                            let mut start_sum = 0;
-                                            let mut start_mean = 0;
-                                            let mut exon_cnt = 0;
-                                            if counts.0 > 0 {
-                                                for ex in hap1_consensus_exons.get(e).unwrap().iter() {
-                                                    start_sum += ex.start;
-                                                    exon_cnt += 1;
-                                                }
-                                                start_mean = start_sum / exon_cnt;
-                                            } else {
-                                                for ex in hap2_consensus_exons.get(e).unwrap().iter() {
-                                                    start_sum += ex.start;
-                                                    exon_cnt += 1;
-                                                }
-                                                start_mean = start_sum / exon_cnt;
-                                            }
-                                            // println!("exon0: {}:{}-{}, hap1:{:?}, hap2:{:?}", e.chr, start_mean + 1, e.end + 1, counts.0, counts.1);
-                                            let mut ec = e.clone();
-                                            ec.start = start_mean;
-                                            haplotype_exons.push((ec, counts.0, counts.1));
-                                        }
-                                        if e.state == 2 {
-                                            let mut end_sum = 0;
-                                            let mut end_mean = 0;
-                                            let mut exon_cnt = 0;
-                                            if counts.0 > 0 {
-                                                for ex in hap1_consensus_exons.get(e).unwrap().iter() {
-                                                    end_sum += ex.end;
-                                                    exon_cnt += 1;
-                                                }
-                                                end_mean = end_sum / exon_cnt;
-                                            } else {
-                                                for ex in hap2_consensus_exons.get(e).unwrap().iter() {
-                                                    end_sum += ex.end;
-                                                    exon_cnt += 1;
-                                                }
-                                                end_mean = end_sum / exon_cnt;
-                                            }
-                                            // println!("exon2: {}:{}-{}, hap1:{:?}, hap2:{:?}", e.chr, e.start + 1, end_mean + 1, counts.0, counts.1);
-                                            let mut ec = e.clone();
-                                            ec.end = end_mean;
-                                            haplotype_exons.push((ec, counts.0, counts.1));
-                                        }
-                                        if e.state == 3 {
-                                            let relaxed_start = [e.start - 20, e.start + 20];
-                                            let relaxed_end = [e.end - 20, e.end + 20];
-                                            let mut unique_flag = true;
-                                            if counts.0 > 0 {
-                                                for ex in hap2_consensus_exons.iter() {
-                                                    if ex.0.state != 3 {
-                                                        continue;
-                                                    }
-                                                    if ex.0.start >= relaxed_start[0] && ex.0.start <= relaxed_start[1] && ex.0.end >= relaxed_end[0] && ex.0.end <= relaxed_end[1] {
-                                                        // highly overlapped, not unique
-                                                        unique_flag = false;
-                                                        break;
-                                                    }
-                                                }
-                                            } else {
-                                                for ex in hap1_consensus_exons.iter() {
-                                                    if ex.0.state != 3 {
-                                                        continue;
-                                                    }
-                                                    if ex.0.start >= relaxed_start[0] && ex.0.start <= relaxed_start[1] && ex.0.end >= relaxed_end[0] && ex.0.end <= relaxed_end[1] {
-                                                        // highly overlapped, not unique
-                                                        unique_flag = false;
-                                                        break;
-                                                    }
-                                                }
-                                            }
-                                            if unique_flag {
-                                                // println!("exon3: {}:{}-{}, hap1:{:?}, hap2:{:?}", e.chr, e.start + 1, e.end + 1, counts.0, counts.1);
-                                                haplotype_exons.push((
-                                                    e.clone(),
-                                                    counts.0,
-                                                    counts.1,
-                                                ));
-                                            }
-                                        }
-                                    }
-                                }
+                                haplotype_exons = snpfrag.collect_haplotype_exons(&phase_sets, min_haplotype_reads, min_sup_haplotype_exon);
                             }
                         }
                         // if !no_bam_output {
@@ -359,14 +474,10 @@ pub fn multithread_phase_haplotag(
                         // }
 
                         // output assignment both for ase snps and heterozygous snps
-                        if !no_bam_output {
+                        if !no_bam_output || output_read_assignment || link_phase_set {
                             let mut queue = read_haplotag_queue.lock().unwrap();
-                            for a in merge_reads_assignments.iter() {
-                                queue.push_back((a.0.clone(), a.1.clone()));
-                            }
-                            let mut queue = read_phaseset_queue.lock().unwrap();
-                            for a in phase_sets.iter() {
-                                queue.push_back((a.0.clone(), a.1.clone()));
+                            for a in region_read_assignments.values() {
+                                queue.push_back(a.clone());
                             }
                         }
                     }
@@ -378,7 +489,7 @@ pub fn multithread_phase_haplotag(
                     }
                 }
 
-                let vcf_records = snpfrag.output_phased_vcf(min_phase_score, min_qual_for_candidate);
+                let vcf_records = snpfrag.output_phased_vcf(ref_seq, min_phase_score, min_qual_for_candidate);
                 {
                     let mut queue = vcf_records_queue.lock().unwrap();
                     for rd in vcf_records.iter() {
@@ -386,8 +497,75 @@ pub fn multithread_phase_haplotag(
                     }
                 }
             }
+            if !no_bam_output && region_chunks.contains_key(&reg_idx) {
+                let chunk_idx = region_chunks[&reg_idx];
+                write_region_bam(&chunk_bam_path(&tmp_dir, chunk_idx), &bam_header, &region_records, &bam_chunks[chunk_idx], &region_read_assignments, assignment_tags);
+            }
         });
     });
+
+    let mut heteroplasmies: Vec<Heteroplasmy> = Vec::new();
+    if heteroplasmy {
+        for (chr, _) in contig_lengths.iter() {
+            if !is_mito_contig(chr) || !ref_seqs.contains_key(chr) {
+                continue;
+            }
+            heteroplasmies.extend(call_heteroplasmy(&bam_file, chr, ref_seqs.get(chr).unwrap(), min_mapq, min_baseq, min_heteroplasmy_frac, min_heteroplasmy_cnt));
+        }
+        vcf_records_queue.lock().unwrap().extend(heteroplasmy_vcf_records(&heteroplasmies, min_heteroplasmy_frac, min_qual_for_candidate));
+    }
+
+    if link_phase_set || dna_vcf.is_some() {
+        // orient and merge phase sets across regions
+        let phase_set_links = link_phase_sets(
+            &read_haplotag_queue.lock().unwrap(),
+            &vcf_records_queue.lock().unwrap(),
+            dna_vcf.clone(),
+            min_link_reads,
+            min_link_agreement,
+        );
+        apply_phase_set_links(
+            &phase_set_links,
+            &mut vcf_records_queue.lock().unwrap(),
+            &mut read_haplotag_queue.lock().unwrap(),
+            &mut read_haplotag1_queue.lock().unwrap(),
+            &mut read_haplotag2_queue.lock().unwrap(),
+            &mut phase_split_queue.lock().unwrap(),
+            &mut phase_block_queue.lock().unwrap(),
+            &mut ase_queue.lock().unwrap(),
+            &mut isoform_queue.lock().unwrap(),
+            &mut splice_junction_queue.lock().unwrap(),
+            &mut end_site_queue.lock().unwrap(),
+            &mut haplotype_exon_queue.lock().unwrap(),
+        );
+    }
+
+    if paternal_vcf.is_some() || maternal_vcf.is_some() {
+        if paternal_vcf.is_none() || maternal_vcf.is_none() {
+            panic!("Both paternal and maternal vcf are required for trio phasing.");
+        }
+        // hap1 is the paternal haplotype and hap2 the maternal haplotype, Mendelian inconsistent variants are flagged
+        let trio = TrioGenotypes::load(paternal_vcf.clone().unwrap().as_str(), maternal_vcf.clone().unwrap().as_str());
+        let phase_set_links = trio.orient_phase_sets(&vcf_records_queue.lock().unwrap());
+        apply_phase_set_links(
+            &phase_set_links,
+            &mut vcf_records_queue.lock().unwrap(),
+            &mut read_haplotag_queue.lock().unwrap(),
+            &mut read_haplotag1_queue.lock().unwrap(),
+            &mut read_haplotag2_queue.lock().unwrap(),
+            &mut phase_split_queue.lock().unwrap(),
+            &mut phase_block_queue.lock().unwrap(),
+            &mut ase_queue.lock().unwrap(),
+            &mut isoform_queue.lock().unwrap(),
+            &mut splice_junction_queue.lock().unwrap(),
+            &mut end_site_queue.lock().unwrap(),
+            &mut haplotype_exon_queue.lock().unwrap(),
+        );
+        for rd in vcf_records_queue.lock().unwrap().iter_mut() {
+            trio.flag_mendelian_inconsistent(rd);
+        }
+    }
+
     let mut vf = File::create(vcf_file).unwrap();
     vf.write("##fileformat=VCFv4.3\n".as_bytes()).unwrap();
     for ctglen in contig_lengths.iter() {
@@ -399,7 +577,13 @@ pub fn multithread_phase_haplotag(
     vf.write("##FILTER=<ID=LowQual,Description=\"Low phasing quality\">\n".as_bytes()).unwrap();
     vf.write("##FILTER=<ID=RnaEdit,Description=\"RNA editing\">\n".as_bytes()).unwrap();
     vf.write("##FILTER=<ID=dn,Description=\"Dense cluster of variants\">\n".as_bytes()).unwrap();
+    vf.write("##FILTER=<ID=HetInHaploid,Description=\"Heterozygous call in a haploid region\">\n".as_bytes()).unwrap();
     vf.write("##INFO=<ID=RDS,Number=1,Type=String,Description=\"RNA editing or Dense SNP or Single SNP.\">\n".as_bytes()).unwrap();
+    vf.write("##INFO=<ID=INDEL,Number=0,Type=Flag,Description=\"Small insertion or deletion, left-aligned\">\n".as_bytes()).unwrap();
+    vf.write("##INFO=<ID=MNV,Number=1,Type=Integer,Description=\"Number of adjacent SNVs in cis merged into this MNV\">\n".as_bytes()).unwrap();
+    vf.write("##INFO=<ID=MIE,Number=0,Type=Flag,Description=\"Mendelian inconsistent with the parental genotypes\">\n".as_bytes()).unwrap();
+    vf.write("##INFO=<ID=HTP,Number=0,Type=Flag,Description=\"Heteroplasmy, reference and alternative alleles both above the detection limit\">\n".as_bytes()).unwrap();
+    vf.write("##INFO=<ID=HPL,Number=1,Type=Integer,Description=\"Length of the reference homopolymer the indel lies in\">\n".as_bytes()).unwrap();
     vf.write("##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">\n".as_bytes()).unwrap();
     vf.write("##FORMAT=<ID=PS,Number=1,Type=Integer,Description=\"Phase Set\">\n".as_bytes()).unwrap();
     vf.write("##FORMAT=<ID=GQ,Number=1,Type=Integer,Description=\"Genotype Quality\">\n".as_bytes()).unwrap();
@@ -407,6 +591,8 @@ pub fn multithread_phase_haplotag(
     vf.write("##FORMAT=<ID=AF,Number=A,Type=Float,Description=\"Allele Frequency\">\n".as_bytes()).unwrap();
     vf.write("##FORMAT=<ID=PQ,Number=1,Type=Float,Description=\"Phasing Quality\">\n".as_bytes()).unwrap();
     vf.write("##FORMAT=<ID=AE,Number=A,Type=Integer,Description=\"Haplotype expression of two alleles\">\n".as_bytes()).unwrap();
+    vf.write("##FORMAT=<ID=PC,Number=1,Type=Float,Description=\"Phase confidence, likelihood-weighted fraction of phasing restarts agreeing with the reported phase\">\n".as_bytes()).unwrap();
+    vf.write("##FORMAT=<ID=AFCI,Number=2,Type=Float,Description=\"95% confidence interval of the heteroplasmy allele fraction\">\n".as_bytes()).unwrap();
     vf.write("##FORMAT=<ID=SQ,Number=1,Type=Float,Description=\"Somatic Score\">\n".as_bytes()).unwrap();
     vf.write("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSample\n".as_bytes()).unwrap();
 
@@ -450,101 +636,503 @@ pub fn multithread_phase_haplotag(
 
     if output_read_assignment {
         let mut assignment_writer = File::create(phased_bam_file.replace(".phased.bam", ".assignment.tsv")).unwrap();
+        assignment_writer.write("#Read\tHaplotype\tRegion\tPhase set\tScore\tInformative SNPs\tUnassigned reason\n".as_bytes()).unwrap();
         for rd in read_haplotag_queue.lock().unwrap().iter() {
-            // println!("{}:{}", rd.0, rd.1);
-            assignment_writer.write(format!("{}\t{}\n", rd.0, rd.1).as_bytes()).unwrap();
+            assignment_writer.write(
+                format!(
+                    "{}\t{}\t{}\t{}\t{:.4}\t{}\t{}\n",
+                    rd.read_id,
+                    rd.haplotype,
+                    rd.region,
+                    rd.phase_set,
+                    rd.score,
+                    rd.num_snps,
+                    rd.reason
+                ).as_bytes(),
+            ).unwrap();
         }
         drop(assignment_writer);
     }
 
+    let mut ase_dispersion = 0.0;
+    let mut ase_units: Vec<AseUnit> = Vec::new();
+    if !genotype_only && (ase || monoallelic_report) {
+        if let AseModel::betabinomial = ase_model {
+            ase_dispersion = estimate_dispersion(&ase_snp_queue.lock().unwrap());
+            println!("ASE dispersion: {:.6}", ase_dispersion);
+        }
+        ase_units = test_ase_units(&ase_queue.lock().unwrap(), ase_model, ase_dispersion);
+    }
+
+    if !genotype_only && ase {
+        // gene level allele-specific expression, a unit is imbalanced when significant and the haplotype ratio exceeds the cutoff
+        let mut ase_hashmap: HashMap<String, Vec<AseUnit>> = HashMap::new();
+        for unit in ase_units.iter() {
+            ase_hashmap.entry(unit.chromosome.clone()).or_insert(Vec::new()).push(unit.clone());
+        }
+        let mut ase_writer = File::create(phased_bam_file.replace(".phased.bam", ".ase.tsv")).unwrap();
+        ase_writer.write(
+            "#Gene\tChromosome\tPhase set\tSNPs\tHap1 reads\tHap2 reads\tHap1 allele count\tHap2 allele count\tRatio\tP-value\tQ-value\tImbalanced\n".as_bytes(),
+        ).unwrap();
+        for chr in contig_order.iter() {
+            if !ase_hashmap.contains_key(chr) {
+                continue;
+            }
+            let mut units_sorted = ase_hashmap.get(chr).unwrap().clone();
+            units_sorted.sort_by(|a, b| a.phase_set.cmp(&b.phase_set).then(a.gene_id.cmp(&b.gene_id)));
+            for unit in units_sorted.iter() {
+                let ratio = unit.ratio();
+                let imbalanced = unit.qvalue <= max_ase_qvalue
+                    && (ratio >= imbalance_allele_expression_cutoff as f64 || ratio <= 1.0 / imbalance_allele_expression_cutoff as f64);
+                ase_writer.write(
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4e}\t{:.4e}\t{}\n",
+                        unit.gene_id,
+                        unit.chromosome,
+                        unit.phase_set,
+                        unit.num_snps,
+                        unit.hap1_reads,
+                        unit.hap2_reads,
+                        unit.hap1_allele_cnt,
+                        unit.hap2_allele_cnt,
+                        ratio,
+                        unit.pvalue,
+                        unit.qvalue,
+                        if imbalanced { "yes" } else { "no" }
+                    ).as_bytes(),
+                ).unwrap();
+            }
+        }
+        drop(ase_writer);
+    }
+
+    if !genotype_only && monoallelic_report {
+        // genes or phase sets with nearly all reads on one haplotype, known imprinted genes are always reported
+        let imprinted = match &imprinted_genes {
+            Some(f) => load_imprinted_genes(f),
+            None => HashSet::new(),
+        };
+        let mut mono_hashmap: HashMap<String, Vec<AseUnit>> = HashMap::new();
+        for unit in ase_units.iter() {
+            let status = monoallelic_status(unit, max_monoallelic_frac, min_monoallelic_reads);
+            if status == "biallelic" && !is_imprinted(&unit.gene_id, &imprinted) {
+                continue;
+            }
+            mono_hashmap.entry(unit.chromosome.clone()).or_insert(Vec::new()).push(unit.clone());
+        }
+        let mut mono_writer = File::create(phased_bam_file.replace(".phased.bam", ".monoallelic.tsv")).unwrap();
+        mono_writer.write(
+            "#Gene\tChromosome\tPhase set\tSNPs\tHap1 dominant SNPs\tHap2 dominant SNPs\tHap1 reads\tHap2 reads\tMinor fraction\tDominant haplotype\tStatus\tImprinted\n".as_bytes(),
+        ).unwrap();
+        for chr in contig_order.iter() {
+            if !mono_hashmap.contains_key(chr) {
+                continue;
+            }
+            let mut units_sorted = mono_hashmap.get(chr).unwrap().clone();
+            units_sorted.sort_by(|a, b| a.phase_set.cmp(&b.phase_set).then(a.gene_id.cmp(&b.gene_id)));
+            for unit in units_sorted.iter() {
+                let total = unit.hap1_reads + unit.hap2_reads;
+                let minor_frac = if total > 0 { unit.hap1_reads.min(unit.hap2_reads) as f64 / total as f64 } else { 0.0 };
+                mono_writer.write(
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{}\t{}\t{}\n",
+                        unit.gene_id,
+                        unit.chromosome,
+                        unit.phase_set,
+                        unit.num_snps,
+                        unit.hap1_dominant_snps,
+                        unit.hap2_dominant_snps,
+                        unit.hap1_reads,
+                        unit.hap2_reads,
+                        minor_frac,
+                        dominant_haplotype(unit),
+                        monoallelic_status(unit, max_monoallelic_frac, min_monoallelic_reads),
+                        if is_imprinted(&unit.gene_id, &imprinted) { "yes" } else { "no" }
+                    ).as_bytes(),
+                ).unwrap();
+            }
+        }
+        drop(mono_writer);
+    }
+
+    if !genotype_only && haplotype_isoform {
+        // isoform usage of each haplotype, an isoform is haplotype-specific when its usage differs significantly between haplotypes
+        let isoform_counts = test_isoform_usage(&isoform_queue.lock().unwrap());
+        let mut isoform_hashmap: HashMap<String, Vec<IsoformCount>> = HashMap::new();
+        for cnt in isoform_counts.into_iter() {
+            isoform_hashmap.entry(cnt.chromosome.clone()).or_insert(Vec::new()).push(cnt);
+        }
+        let mut isoform_writer = File::create(phased_bam_file.replace(".phased.bam", ".haplotype_isoform.tsv")).unwrap();
+        isoform_writer.write(
+            "#Gene\tIsoform\tChromosome\tPhase set\tHap1 reads\tHap2 reads\tHap1 usage\tHap2 usage\tP-value\tQ-value\tHaplotype specific\n".as_bytes(),
+        ).unwrap();
+        for chr in contig_order.iter() {
+            if !isoform_hashmap.contains_key(chr) {
+                continue;
+            }
+            let mut counts_sorted = isoform_hashmap.get(chr).unwrap().clone();
+            counts_sorted.sort_by(|a, b| a.phase_set.cmp(&b.phase_set).then(a.gene_id.cmp(&b.gene_id)).then(a.isoform_id.cmp(&b.isoform_id)));
+            for cnt in counts_sorted.iter() {
+                // fraction of the reads of the gene on each haplotype that come from the isoform
+                let hap1_usage = if cnt.gene_hap1_reads > 0 { cnt.hap1_reads as f64 / cnt.gene_hap1_reads as f64 } else { 0.0 };
+                let hap2_usage = if cnt.gene_hap2_reads > 0 { cnt.hap2_reads as f64 / cnt.gene_hap2_reads as f64 } else { 0.0 };
+                isoform_writer.write(
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4e}\t{:.4e}\t{}\n",
+                        cnt.gene_id,
+                        cnt.isoform_id,
+                        cnt.chromosome,
+                        cnt.phase_set,
+                        cnt.hap1_reads,
+                        cnt.hap2_reads,
+                        hap1_usage,
+                        hap2_usage,
+                        cnt.pvalue,
+                        cnt.qvalue,
+                        if cnt.qvalue <= max_isoform_qvalue { "yes" } else { "no" }
+                    ).as_bytes(),
+                ).unwrap();
+            }
+        }
+        drop(isoform_writer);
+    }
+
+    if heteroplasmy {
+        let mut heteroplasmy_writer = File::create(phased_bam_file.replace(".phased.bam", ".heteroplasmy.tsv")).unwrap();
+        heteroplasmy_writer.write(
+            "#Chromosome\tPosition\tReference\tAllele\tAllele count\tDepth\tAllele fraction\tCI lower\tCI upper\tQuality\n".as_bytes(),
+        ).unwrap();
+        for site in heteroplasmies.iter() {
+            heteroplasmy_writer.write(
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.2}\n",
+                    site.chromosome,
+                    site.pos + 1,
+                    site.reference,
+                    site.allele,
+                    site.allele_cnt,
+                    site.depth,
+                    site.allele_frac,
+                    site.ci[0],
+                    site.ci[1],
+                    site.qual
+                ).as_bytes(),
+            ).unwrap(); // 1-based
+        }
+        drop(heteroplasmy_writer);
+    }
+
+    if !genotype_only && phase_stats {
+        let mut split_hashmap: HashMap<String, Vec<PhaseSplit>> = HashMap::new();
+        for sp in phase_split_queue.lock().unwrap().iter() {
+            split_hashmap.entry(sp.chromosome.clone()).or_insert(Vec::new()).push(sp.clone());
+        }
+        let mut split_writer = File::create(phased_bam_file.replace(".phased.bam", ".phase_splits.tsv")).unwrap();
+        split_writer.write(
+            "#Chromosome\tSNP1 position\tSNP2 position\tPhase set1\tPhase set2\tAgree reads\tDisagree reads\tReason\n".as_bytes(),
+        ).unwrap();
+        for chr in contig_order.iter() {
+            if !split_hashmap.contains_key(chr) {
+                continue;
+            }
+            let mut splits_sorted = split_hashmap.get(chr).unwrap().clone();
+            splits_sorted.sort_by(|a, b| a.snp_poses.cmp(&b.snp_poses));
+            for sp in splits_sorted.iter() {
+                split_writer.write(
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                        sp.chromosome,
+                        sp.snp_poses[0] + 1,
+                        sp.snp_poses[1] + 1,
+                        sp.phase_sets[0],
+                        sp.phase_sets[1],
+                        sp.agree_reads,
+                        sp.disagree_reads,
+                        sp.reason
+                    ).as_bytes(),
+                ).unwrap(); // 1-based
+            }
+        }
+        drop(split_writer);
+    }
+
+    if !genotype_only && phase_stats {
+        let mut block_hashmap: HashMap<String, Vec<PhaseBlock>> = HashMap::new();
+        for block in phase_block_queue.lock().unwrap().iter() {
+            block_hashmap.entry(block.chromosome.clone()).or_insert(Vec::new()).push(block.clone());
+        }
+        let mut block_writer = File::create(phased_bam_file.replace(".phased.bam", ".phase_blocks.tsv")).unwrap();
+        block_writer.write(
+            "#Chromosome\tPhase set\tStart\tEnd\tSNPs\tReads\tMean PQ\n".as_bytes(),
+        ).unwrap();
+        let mut block_lengths: Vec<i64> = Vec::new();
+        let mut largest_block_snps = 0;
+        for chr in contig_order.iter() {
+            if !block_hashmap.contains_key(chr) {
+                continue;
+            }
+            let mut blocks_sorted = block_hashmap.get(chr).unwrap().clone();
+            blocks_sorted.sort_by(|a, b| a.start.cmp(&b.start));
+            for block in blocks_sorted.iter() {
+                block_writer.write(
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{:.2}\n",
+                        block.chromosome,
+                        block.phase_set,
+                        block.start + 1,
+                        block.end + 1,
+                        block.num_snps,
+                        block.num_reads,
+                        block.mean_phase_score
+                    ).as_bytes(),
+                ).unwrap(); // 1-based, start inclusive, end inclusive
+                block_lengths.push(block.end - block.start + 1);
+                largest_block_snps = largest_block_snps.max(block.num_snps);
+            }
+        }
+        drop(block_writer);
+
+        let (het_cnt, phased_cnt) = *het_snp_count.lock().unwrap();
+        let mut stats_writer = File::create(phased_bam_file.replace(".phased.bam", ".phase_stats.tsv")).unwrap();
+        stats_writer.write(format!("phase_blocks\t{}\n", block_lengths.len()).as_bytes()).unwrap();
+        stats_writer.write(format!("het_snps\t{}\n", het_cnt).as_bytes()).unwrap();
+        stats_writer.write(format!("phased_het_snps\t{}\n", phased_cnt).as_bytes()).unwrap();
+        stats_writer.write(format!("phased_het_snp_fraction\t{:.4}\n", if het_cnt > 0 { phased_cnt as f64 / het_cnt as f64 } else { 0.0 }).as_bytes()).unwrap();
+        stats_writer.write(format!("block_n50\t{}\n", calculate_n50(&block_lengths)).as_bytes()).unwrap();
+        stats_writer.write(format!("largest_block_length\t{}\n", block_lengths.iter().max().unwrap_or(&0)).as_bytes()).unwrap();
+        stats_writer.write(format!("largest_block_snps\t{}\n", largest_block_snps).as_bytes()).unwrap();
+        if (ase || monoallelic_report) && matches!(ase_model, AseModel::betabinomial) {
+            stats_writer.write(format!("ase_dispersion\t{:.6}\n", ase_dispersion).as_bytes()).unwrap();
+        }
+        drop(stats_writer);
+    }
+
     if haplotype_specific_exon {
-        let mut exon_hashmap: HashMap<String, Vec<(Exon, i32, i32)>> = HashMap::new();
+        // exons are ranked by the phred scaled q-value of the usage difference between haplotypes, capped at 1000
+        let mut haplotype_exons: Vec<HaplotypeExon> = haplotype_exon_queue.lock().unwrap().drain(..).collect();
+        let qvalues = benjamini_hochberg(&haplotype_exons.iter().map(|e| e.pvalue).collect());
+        for (e, q) in haplotype_exons.iter_mut().zip(qvalues.into_iter()) {
+            e.qvalue = q;
+        }
+        let mut exon_hashmap: HashMap<String, Vec<HaplotypeExon>> = HashMap::new();
+        for e in haplotype_exons.into_iter() {
+            exon_hashmap.entry(e.exon.chr.clone()).or_insert(Vec::new()).push(e);
+        }
+        // the table keeps the columns of earlier versions, the usage normalised by the haplotype read counts is appended
         let mut exon_writer = File::create(phased_bam_file.replace(".phased.bam", ".haplotype_exon.tsv")).unwrap();
         exon_writer.write(
-            "#Chromosome\tExon start\tExon end\tExon state\tHap1 expression\tHap2 expression\n".as_bytes(),
+            "#Chromosome\tExon start\tExon end\tExon state\tHap1 expression\tHap2 expression\tPhase set\tHap1 usage\tHap2 usage\tP-value\tQ-value\n".as_bytes(),
         ).unwrap();
-        for rd in haplotype_exon_queue.lock().unwrap().iter() {
-            let e = &rd.0;
-            if exon_hashmap.contains_key(&e.chr) {
-                let exon_vec = exon_hashmap.get_mut(&e.chr).unwrap();
-                exon_vec.push(rd.clone());
-            } else {
-                exon_hashmap.insert(e.chr.clone(), vec![rd.clone()]);
+        let mut track_writer = match haplotype_exon_format {
+            ExonFormat::bed => {
+                let mut w = File::create(phased_bam_file.replace(".phased.bam", ".haplotype_exon.bed")).unwrap();
+                w.write(
+                    "#Chromosome\tExon start\tExon end\tHaplotype\tScore\tStrand\tPhase set\tExon state\tHap1 reads\tHap2 reads\tHap1 usage\tHap2 usage\tP-value\tQ-value\n".as_bytes(),
+                ).unwrap();
+                w
             }
-        }
+            ExonFormat::gtf => File::create(phased_bam_file.replace(".phased.bam", ".haplotype_exon.gtf")).unwrap(),
+        };
         for chr in contig_order.iter() {
             if !exon_hashmap.contains_key(chr) {
                 continue;
             }
             let mut exons_sorted = exon_hashmap.get(chr).unwrap().clone();
-            exons_sorted.sort_by(|a, b| a.0.start.cmp(&b.0.start));
-            for rd in exons_sorted.iter() {
-                let e = &rd.0;
+            exons_sorted.sort_by(|a, b| a.exon.start.cmp(&b.exon.start).then(a.exon.end.cmp(&b.exon.end)).then(a.phase_set.cmp(&b.phase_set)));
+            for he in exons_sorted.iter() {
+                let e = &he.exon;
+                let (hap1_usage, hap2_usage) = he.usage();
                 exon_writer.write(
                     format!(
-                        "{}\t{}\t{}\t{}\t{}\t{}\n",
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4e}\t{:.4e}\n",
                         e.chr,
                         e.start + 1,
                         e.end,
                         e.state,
-                        rd.1,
-                        rd.2
+                        he.hap1_reads,
+                        he.hap2_reads,
+                        he.phase_set,
+                        hap1_usage,
+                        hap2_usage,
+                        he.pvalue,
+                        he.qvalue
                     ).as_bytes(),
                 ).unwrap(); // 1-based, start inclusive, end inclusive
+                let haplotype = if hap1_usage >= hap2_usage { "hap1" } else { "hap2" }; // haplotype with the higher usage
+                let score = (-10.0 * he.qvalue.max(1e-100).log10()).round().min(1000.0) as u32;
+                let line = match haplotype_exon_format {
+                    ExonFormat::bed => format!(
+                        "{}\t{}\t{}\t{}\t{}\t.\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4e}\t{:.4e}\n",
+                        e.chr,
+                        e.start,
+                        e.end,
+                        haplotype,
+                        score,
+                        he.phase_set,
+                        e.state,
+                        he.hap1_reads,
+                        he.hap2_reads,
+                        hap1_usage,
+                        hap2_usage,
+                        he.pvalue,
+                        he.qvalue
+                    ), // 0-based, start inclusive, end exclusive
+                    ExonFormat::gtf => format!(
+                        "{}\tlongcallR\texon\t{}\t{}\t{}\t.\t.\thaplotype \"{}\"; phase_set \"{}\"; exon_state \"{}\"; hap1_reads \"{}\"; hap2_reads \"{}\"; hap1_usage \"{:.4}\"; hap2_usage \"{:.4}\"; pvalue \"{:.4e}\"; qvalue \"{:.4e}\";\n",
+                        e.chr,
+                        e.start + 1,
+                        e.end,
+                        score,
+                        haplotype,
+                        he.phase_set,
+                        e.state,
+                        he.hap1_reads,
+                        he.hap2_reads,
+                        hap1_usage,
+                        hap2_usage,
+                        he.pvalue,
+                        he.qvalue
+                    ), // 1-based, start inclusive, end inclusive
+                };
+                track_writer.write(line.as_bytes()).unwrap();
             }
         }
         drop(exon_writer);
+        drop(track_writer);
+    }
+
+    if haplotype_splicing {
+        // percent spliced in of alternative junctions on each haplotype, a junction is differentially spliced when significant
+        // and the PSI difference exceeds the cutoff
+        let junctions = test_splice_junctions(&splice_junction_queue.lock().unwrap());
+        let mut junction_hashmap: HashMap<String, Vec<SpliceJunction>> = HashMap::new();
+        for junc in junctions.into_iter() {
+            junction_hashmap.entry(junc.chromosome.clone()).or_insert(Vec::new()).push(junc);
+        }
+        let mut splicing_writer = File::create(phased_bam_file.replace(".phased.bam", ".haplotype_splicing.tsv")).unwrap();
+        splicing_writer.write(
+            "#Chromosome\tIntron start\tIntron end\tPhase set\tHap1 reads\tHap2 reads\tHap1 PSI\tHap2 PSI\tDelta PSI\tP-value\tQ-value\tDifferential\n".as_bytes(),
+        ).unwrap();
+        for chr in contig_order.iter() {
+            if !junction_hashmap.contains_key(chr) {
+                continue;
+            }
+            let mut junctions_sorted = junction_hashmap.get(chr).unwrap().clone();
+            junctions_sorted.sort_by(|a, b| a.start.cmp(&b.start).then(a.end.cmp(&b.end)).then(a.phase_set.cmp(&b.phase_set)));
+            for junc in junctions_sorted.iter() {
+                let (hap1_psi, hap2_psi) = junc.psi();
+                let differential = junc.qvalue <= max_splicing_qvalue && (hap1_psi - hap2_psi).abs() >= min_delta_psi;
+                splicing_writer.write(
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4e}\t{:.4e}\t{}\n",
+                        junc.chromosome,
+                        junc.start + 1,
+                        junc.end,
+                        junc.phase_set,
+                        junc.hap1_reads,
+                        junc.hap2_reads,
+                        hap1_psi,
+                        hap2_psi,
+                        hap1_psi - hap2_psi,
+                        junc.pvalue,
+                        junc.qvalue,
+                        if differential { "yes" } else { "no" }
+                    ).as_bytes(),
+                ).unwrap(); // 1-based, start inclusive, end inclusive
+            }
+        }
+        drop(splicing_writer);
+    }
+
+    if haplotype_end_site {
+        // alternative TSS and polyA sites, a site is differentially used when its usage differs significantly between haplotypes
+        let clusters = test_end_sites(&end_site_queue.lock().unwrap(), end_site_distance as i64, min_end_site_reads);
+        let mut cluster_hashmap: HashMap<String, Vec<EndSiteCluster>> = HashMap::new();
+        for c in clusters.into_iter() {
+            cluster_hashmap.entry(c.chromosome.clone()).or_insert(Vec::new()).push(c);
+        }
+        let mut site_writer = File::create(phased_bam_file.replace(".phased.bam", ".haplotype_end_site.tsv")).unwrap();
+        site_writer.write(
+            "#Gene\tChromosome\tStrand\tSite type\tPhase set\tStart\tEnd\tPeak\tHap1 reads\tHap2 reads\tHap1 usage\tHap2 usage\tP-value\tQ-value\tDifferential\n".as_bytes(),
+        ).unwrap();
+        for chr in contig_order.iter() {
+            if !cluster_hashmap.contains_key(chr) {
+                continue;
+            }
+            let mut clusters_sorted = cluster_hashmap.get(chr).unwrap().clone();
+            clusters_sorted.sort_by(|a, b| a.start.cmp(&b.start).then(a.site_type.cmp(&b.site_type)).then(a.phase_set.cmp(&b.phase_set)));
+            for c in clusters_sorted.iter() {
+                let (hap1_usage, hap2_usage) = c.usage();
+                site_writer.write(
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4e}\t{:.4e}\t{}\n",
+                        c.gene_id,
+                        c.chromosome,
+                        c.strand,
+                        c.site_type,
+                        c.phase_set,
+                        c.start + 1,
+                        c.end + 1,
+                        c.peak + 1,
+                        c.hap1_reads,
+                        c.hap2_reads,
+                        hap1_usage,
+                        hap2_usage,
+                        c.pvalue,
+                        c.qvalue,
+                        if c.qvalue <= max_end_site_qvalue { "yes" } else { "no" }
+                    ).as_bytes(),
+                ).unwrap(); // 1-based, start inclusive, end inclusive
+            }
+        }
+        drop(site_writer);
     }
 
     if !no_bam_output {
-        if !haplotype_bam_output {
-            let mut read_assignments: HashMap<String, i32> = HashMap::new();
-            for rd in read_haplotag_queue.lock().unwrap().iter() {
-                if read_assignments.contains_key(&rd.0) {
-                    read_assignments.remove(&rd.0);   // one read belongs to at least two regions
-                } else {
-                    read_assignments.insert(rd.0.clone(), rd.1);
+        // reads outside all regions are copied in parallel
+        pool.install(|| {
+            bam_chunks.par_iter().enumerate().for_each(|(chunk_idx, chunk)| {
+                if chunk.region_idx.is_none() {
+                    write_gap_bam(&bam_file, &chunk_bam_path(&tmp_dir, chunk_idx), &bam_header, chunk);
                 }
+            });
+        });
+        // regions without owned chunk or without reads may not write their chunk
+        for chunk_idx in 0..bam_chunks.len() {
+            if fs::metadata(chunk_bam_path(&tmp_dir, chunk_idx)).is_err() {
+                bam::Writer::from_path(chunk_bam_path(&tmp_dir, chunk_idx), &bam_header, Format::Bam).unwrap();
             }
-            let mut read_phasesets: HashMap<String, u32> = HashMap::new();
-            for rd in read_phaseset_queue.lock().unwrap().iter() {
-                if read_phasesets.contains_key(&rd.0) {
-                    read_phasesets.remove(&rd.0);   // one read belongs to at least two regions or two phase sets
-                } else {
-                    read_phasesets.insert(rd.0.clone(), rd.1);
-                }
+        }
+
+        // a read assigned by several regions is tagged only when all regions agree
+        let mut read_tags: HashMap<String, ReadAssignment> = HashMap::new();
+        let mut conflict_reads: HashSet<String> = HashSet::new();
+        for rd in read_haplotag_queue.lock().unwrap().iter() {
+            if rd.haplotype == 0 {
+                continue;
             }
-            let mut bam_reader = bam::IndexedReader::from_path(&bam_file).unwrap();
-            let header = bam::Header::from_template(&bam_reader.header());
-            let mut bam_writer = bam::Writer::from_path(phased_bam_file, &header, Format::Bam).unwrap();
-            bam_writer.set_threads(thread_size).unwrap();
-            for region in isolated_regions.iter() {
-                // TODO: duplicate reads in different regions
-                bam_reader.fetch((region.chr.as_str(), region.start, region.end)).unwrap(); // set region
-                for r in bam_reader.records() {
-                    let mut record = r.unwrap();
-                    if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
-                        continue;
-                    }
-                    if record.reference_start() + 1 < region.start as i64 || record.reference_end() + 1 > region.end as i64 {
-                        // reads beyond the region boundary will be ignored to provent duplicated reads
-                        continue;
-                    }
-                    let qname = std::str::from_utf8(record.qname()).unwrap().to_string();
-                    if read_assignments.contains_key(&qname) {
-                        let asg = read_assignments.get(&qname).unwrap();
-                        if *asg != 0 {
-                            let _ = record.push_aux(b"HP:i", Aux::I32(*asg));
-                        }
+            match read_tags.get(&rd.read_id) {
+                Some(tag) => {
+                    if tag.haplotype != rd.haplotype || tag.phase_set != rd.phase_set {
+                        conflict_reads.insert(rd.read_id.clone());
                     }
-                    if read_phasesets.contains_key(&qname) {
-                        let ps = read_phasesets.get(&qname).unwrap();
-                        let _ = record.push_aux(b"PS:i", Aux::U32(*ps));
-                    }
-                    let _ = bam_writer.write(&record).unwrap();
+                }
+                None => {
+                    read_tags.insert(rd.read_id.clone(), rd.clone());
                 }
             }
-            drop(bam_writer);
+        }
+        if !haplotype_bam_output {
+            concat_chunk_bams(
+                &bam_file,
+                &tmp_dir,
+                &phased_bam_file,
+                bam_chunks.len(),
+                &read_tags,
+                &conflict_reads,
+                assignment_tags,
+                thread_size,
+            );
         } else {
             let mut hap1_read_assignments: HashSet<String> = HashSet::new();
             let mut hap2_read_assignments: HashSet<String> = HashSet::new();
@@ -554,41 +1142,68 @@ pub fn multithread_phase_haplotag(
             for rname in read_haplotag2_queue.lock().unwrap().iter() {
                 hap2_read_assignments.insert(rname.clone());
             }
-            let mut bam_reader = bam::IndexedReader::from_path(&bam_file).unwrap();
-            let header = bam::Header::from_template(&bam_reader.header());
-            let mut hap1_bam_writer = bam::Writer::from_path(
-                phased_bam_file.replace("phased", "hap1"),
-                &header,
-                Format::Bam,
-            ).unwrap();
-            let mut hap2_bam_writer = bam::Writer::from_path(
-                phased_bam_file.replace("phased", "hap2"),
-                &header,
-                Format::Bam,
-            ).unwrap();
-            hap1_bam_writer.set_threads(thread_size).unwrap();
-            hap2_bam_writer.set_threads(thread_size).unwrap();
-            for region in isolated_regions.iter() {
-                bam_reader.fetch((region.chr.as_str(), region.start, region.end)).unwrap(); // set region
-                for r in bam_reader.records() {
-                    let mut record = r.unwrap();
-                    if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
-                        continue;
-                    }
-                    if record.reference_start() + 1 < region.start as i64 || record.reference_end() + 1 > region.end as i64 {
-                        // reads beyond the region boundary will be ignored to provent duplicated reads
-                        continue;
-                    }
-                    let qname = std::str::from_utf8(record.qname()).unwrap().to_string();
-                    if hap1_read_assignments.contains(&qname) {
-                        let _ = hap1_bam_writer.write(&record).unwrap();
-                    } else if hap2_read_assignments.contains(&qname) {
-                        let _ = hap2_bam_writer.write(&record).unwrap();
-                    }
-                }
+            split_chunk_bams(
+                &bam_file,
+                &tmp_dir,
+                &phased_bam_file,
+                bam_chunks.len(),
+                &hap1_read_assignments,
+                &hap2_read_assignments,
+                &read_tags,
+                &conflict_reads,
+                assignment_tags,
+                haplotype_fastq_output,
+                thread_size,
+            );
+        }
+    }
+}
+
+fn apply_phase_set_links(
+    phase_set_links: &HashMap<(String, u32), PhaseSetLink>,
+    vcf_records: &mut VecDeque<VCFRecord>,
+    read_assignments: &mut VecDeque<ReadAssignment>,
+    queue1: &mut VecDeque<String>,
+    queue2: &mut VecDeque<String>,
+    phase_splits: &mut VecDeque<PhaseSplit>,
+    phase_blocks: &mut VecDeque<PhaseBlock>,
+    ase_units: &mut VecDeque<AseUnit>,
+    isoform_counts: &mut VecDeque<IsoformCount>,
+    splice_junctions: &mut VecDeque<SpliceJunction>,
+    end_sites: &mut VecDeque<EndSiteCount>,
+    haplotype_exons: &mut VecDeque<HaplotypeExon>,
+) {
+    // relabel the phase set and orientation of all outputs collected from the regions
+    for rd in vcf_records.iter_mut() {
+        relabel_vcf_record(rd, phase_set_links);
+    }
+    let mut flipped_reads: HashSet<String> = HashSet::new();
+    for asg in read_assignments.iter_mut() {
+        let haplotype = asg.haplotype;
+        relabel_read_assignment(asg, phase_set_links);
+        if asg.haplotype != haplotype {
+            flipped_reads.insert(asg.read_id.clone());
+        }
+    }
+    // reads split by haplotype follow the orientation of the merged phase set
+    let (hap1_reads, hap2_reads): (Vec<String>, Vec<String>) = (queue1.drain(..).collect(), queue2.drain(..).collect());
+    for rname in hap1_reads.into_iter() {
+        if flipped_reads.contains(&rname) { queue2.push_back(rname); } else { queue1.push_back(rname); }
+    }
+    for rname in hap2_reads.into_iter() {
+        if flipped_reads.contains(&rname) { queue1.push_back(rname); } else { queue2.push_back(rname); }
+    }
+    for sp in phase_splits.iter_mut() {
+        for ps in sp.phase_sets.iter_mut() {
+            if let Some(link) = phase_set_links.get(&(sp.chromosome.clone(), *ps)) {
+                *ps = link.phase_set;
             }
-            drop(hap1_bam_writer);
-            drop(hap2_bam_writer);
         }
     }
+    merge_phase_blocks(phase_blocks, phase_set_links);
+    relabel_ase_units(ase_units, phase_set_links);
+    relabel_isoform_counts(isoform_counts, phase_set_links);
+    relabel_splice_junctions(splice_junctions, phase_set_links);
+    relabel_end_sites(end_sites, phase_set_links);
+    relabel_haplotype_exons(haplotype_exons, phase_set_links);
 }
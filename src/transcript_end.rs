@@ -0,0 +1,266 @@
+use std::collections::{HashMap, VecDeque};
+
+use fishers_exact::fishers_exact;
+use rust_htslib::bam;
+use rust_htslib::bam::record::Aux;
+use rust_lapper::Interval;
+
+use crate::ase::{benjamini_hochberg, gene_of_read};
+use crate::phase_link::PhaseSetLink;
+use crate::snpfrags::SNPFrag;
+
+#[derive(Debug, Clone, Default)]
+pub struct EndSiteCount {
+    pub gene_id: String,
+    pub chromosome: String,
+    pub phase_set: u32,
+    pub strand: char,
+    // transcript strand, '+' or '-'
+    pub site_type: String,
+    // "TSS" for 5' ends, "polyA" for 3' ends
+    pub pos: i64,
+    // 0-based position of the read end on the reference
+    pub hap1_reads: u32,
+    pub hap2_reads: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EndSiteCluster {
+    pub gene_id: String,
+    pub chromosome: String,
+    pub phase_set: u32,
+    pub strand: char,
+    pub site_type: String,
+    pub start: i64,
+    pub end: i64,
+    // span of the clustered read ends, 0-based, [start, end]
+    pub peak: i64,
+    // position with the most read ends
+    pub hap1_reads: u32,
+    pub hap2_reads: u32,
+    pub hap1_total: u32,
+    pub hap2_total: u32,
+    // read ends of each haplotype in all clusters of the gene, phase set, strand and site type
+    pub pvalue: f64,
+    pub qvalue: f64,
+}
+
+impl EndSiteCluster {
+    pub fn usage(&self) -> (f64, f64) {
+        // fraction of the read ends of each haplotype in the cluster
+        let hap1_usage = if self.hap1_total > 0 { self.hap1_reads as f64 / self.hap1_total as f64 } else { 0.0 };
+        let hap2_usage = if self.hap2_total > 0 { self.hap2_reads as f64 / self.hap2_total as f64 } else { 0.0 };
+        return (hap1_usage, hap2_usage);
+    }
+}
+
+pub fn transcript_strands(records: &Vec<bam::Record>) -> HashMap<String, char> {
+    // transcript strand of the reads with a ts tag: read +, ts + or read -, ts - is transcript +, otherwise transcript -
+    let mut strands: HashMap<String, char> = HashMap::new();
+    for record in records.iter() {
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        let ts = match record.aux(b"ts") {
+            Ok(Aux::Char(c)) => c,
+            _ => continue,
+        };
+        let strand = match (record.is_reverse(), ts) {
+            (false, b'+') | (true, b'-') => '+',
+            (false, b'-') | (true, b'+') => '-',
+            _ => continue,
+        };
+        strands.insert(std::str::from_utf8(record.qname()).unwrap().to_string(), strand);
+    }
+    return strands;
+}
+
+impl SNPFrag {
+    pub fn collect_end_sites(
+        &self,
+        read_strands: &HashMap<String, char>,
+        gene_exons: &HashMap<String, Vec<Interval<usize, u8>>>,
+        read_phase_sets: &HashMap<String, u32>,
+    ) -> Vec<EndSiteCount> {
+        // 5' (TSS) and 3' (polyA) ends of the haplotagged reads with a known transcript strand
+        let mut sites: HashMap<(String, u32, char, String, i64), EndSiteCount> = HashMap::new();
+        for frag in self.fragments.iter() {
+            if (frag.assignment != 1 && frag.assignment != 2) || frag.exons.len() == 0 {
+                continue;
+            }
+            let ps = match read_phase_sets.get(&frag.read_id) {
+                Some(v) => *v,
+                None => continue,
+            };
+            let strand = match read_strands.get(&frag.read_id) {
+                Some(v) => *v,
+                None => continue,
+            };
+            let gene_id = gene_of_read(gene_exons, &frag.exons);
+            let (read_start, read_end) = (frag.exons.first().unwrap().start, frag.exons.last().unwrap().end - 1);
+            let ends = if strand == '+' { [("TSS", read_start), ("polyA", read_end)] } else { [("TSS", read_end), ("polyA", read_start)] };
+            for (site_type, pos) in ends.iter() {
+                let site = sites.entry((gene_id.clone(), ps, strand, site_type.to_string(), *pos)).or_insert(EndSiteCount {
+                    gene_id: gene_id.clone(),
+                    chromosome: self.region.chr.clone(),
+                    phase_set: ps,
+                    strand: strand,
+                    site_type: site_type.to_string(),
+                    pos: *pos,
+                    ..Default::default()
+                });
+                if frag.assignment == 1 {
+                    site.hap1_reads += 1;
+                } else {
+                    site.hap2_reads += 1;
+                }
+            }
+        }
+        return sites.into_values().collect();
+    }
+}
+
+pub fn relabel_end_sites(sites: &mut VecDeque<EndSiteCount>, phase_set_links: &HashMap<(String, u32), PhaseSetLink>) {
+    for site in sites.iter_mut() {
+        if let Some(link) = phase_set_links.get(&(site.chromosome.clone(), site.phase_set)) {
+            site.phase_set = link.phase_set;
+            if link.flip {
+                std::mem::swap(&mut site.hap1_reads, &mut site.hap2_reads);
+            }
+        }
+    }
+}
+
+pub fn test_end_sites(sites: &VecDeque<EndSiteCount>, cluster_distance: i64, min_cluster_reads: u32) -> Vec<EndSiteCluster> {
+    // Read ends of the same gene, phase set, strand and site type are clustered when closer than cluster_distance. Groups with
+    // more than one cluster of at least min_cluster_reads reads have alternative sites, the usage of each site against the other
+    // sites is compared between haplotypes (Fisher's exact test) and corrected for multiple testing.
+    let mut groups: HashMap<(String, String, u32, char, String), Vec<&EndSiteCount>> = HashMap::new();
+    for site in sites.iter() {
+        groups.entry((site.gene_id.clone(), site.chromosome.clone(), site.phase_set, site.strand, site.site_type.clone())).or_insert(Vec::new()).push(site);
+    }
+    let mut alternative: Vec<EndSiteCluster> = Vec::new();
+    for (_, group) in groups.iter_mut() {
+        group.sort_by(|a, b| a.pos.cmp(&b.pos));
+        let mut clusters: Vec<EndSiteCluster> = Vec::new();
+        let mut peak_reads = 0;
+        for site in group.iter() {
+            let extend = match clusters.last() {
+                Some(c) => site.pos - c.end <= cluster_distance,
+                None => false,
+            };
+            if !extend {
+                clusters.push(EndSiteCluster {
+                    gene_id: site.gene_id.clone(),
+                    chromosome: site.chromosome.clone(),
+                    phase_set: site.phase_set,
+                    strand: site.strand,
+                    site_type: site.site_type.clone(),
+                    start: site.pos,
+                    end: site.pos,
+                    peak: site.pos,
+                    ..Default::default()
+                });
+                peak_reads = 0;
+            }
+            let c = clusters.last_mut().unwrap();
+            c.end = site.pos;
+            c.hap1_reads += site.hap1_reads;
+            c.hap2_reads += site.hap2_reads;
+            if site.hap1_reads + site.hap2_reads > peak_reads {
+                peak_reads = site.hap1_reads + site.hap2_reads;
+                c.peak = site.pos;
+            }
+        }
+        clusters.retain(|c| c.hap1_reads + c.hap2_reads >= min_cluster_reads);
+        if clusters.len() < 2 {
+            continue;
+        }
+        let hap1_total: u32 = clusters.iter().map(|c| c.hap1_reads).sum();
+        let hap2_total: u32 = clusters.iter().map(|c| c.hap2_reads).sum();
+        for mut c in clusters.into_iter() {
+            c.hap1_total = hap1_total;
+            c.hap2_total = hap2_total;
+            let table = [c.hap1_reads, c.hap2_reads, hap1_total - c.hap1_reads, hap2_total - c.hap2_reads];
+            c.pvalue = fishers_exact(&table).unwrap().two_tail_pvalue.min(1.0);
+            alternative.push(c);
+        }
+    }
+    let qvalues = benjamini_hochberg(&alternative.iter().map(|c| c.pvalue).collect());
+    for (c, q) in alternative.iter_mut().zip(qvalues.into_iter()) {
+        c.qvalue = q;
+    }
+    return alternative;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_with_ts(qname: &str, reverse: bool, ts: Option<u8>) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(qname.as_bytes(), None, b"ACGT", &[30, 30, 30, 30]);
+        record.unset_unmapped();
+        if reverse {
+            record.set_reverse();
+        }
+        if let Some(c) = ts {
+            record.push_aux(b"ts", Aux::Char(c)).unwrap();
+        }
+        return record;
+    }
+
+    #[test]
+    fn transcript_strand_from_read_strand_and_ts_tag() {
+        let mut secondary = read_with_ts("secondary", false, Some(b'+'));
+        secondary.set_secondary();
+        let records = vec![
+            read_with_ts("fwd_plus", false, Some(b'+')),
+            read_with_ts("fwd_minus", false, Some(b'-')),
+            read_with_ts("rev_plus", true, Some(b'+')),
+            read_with_ts("rev_minus", true, Some(b'-')),
+            read_with_ts("no_tag", false, None),
+            read_with_ts("unknown", false, Some(b'?')),
+            secondary,
+        ];
+        let strands = transcript_strands(&records);
+        assert_eq!(strands.len(), 4);
+        assert_eq!(strands["fwd_plus"], '+');
+        assert_eq!(strands["fwd_minus"], '-');
+        assert_eq!(strands["rev_plus"], '-');
+        assert_eq!(strands["rev_minus"], '+');
+    }
+
+    fn end_site(gene_id: &str, pos: i64, hap1_reads: u32, hap2_reads: u32) -> EndSiteCount {
+        return EndSiteCount {
+            gene_id: gene_id.to_string(),
+            chromosome: "chr1".to_string(),
+            phase_set: 101,
+            strand: '+',
+            site_type: "polyA".to_string(),
+            pos,
+            hap1_reads,
+            hap2_reads,
+        };
+    }
+
+    #[test]
+    fn end_sites_cluster_within_cluster_distance() {
+        let mut sites: VecDeque<EndSiteCount> = VecDeque::new();
+        sites.push_back(end_site("gene1", 110, 2, 2));
+        sites.push_back(end_site("gene1", 100, 3, 0));
+        sites.push_back(end_site("gene1", 120, 0, 0)); // exactly cluster_distance from the previous end
+        sites.push_back(end_site("gene1", 131, 0, 5)); // one base further starts a new cluster
+        sites.push_back(end_site("gene1", 500, 1, 0)); // below min_cluster_reads
+        sites.push_back(end_site("gene2", 100, 4, 4)); // a single site has no alternative
+        let clusters = test_end_sites(&sites, 10, 3);
+        assert_eq!(clusters.len(), 2);
+        let first = clusters.iter().find(|c| c.start == 100).unwrap();
+        assert_eq!((first.end, first.peak), (120, 110));
+        assert_eq!((first.hap1_reads, first.hap2_reads, first.hap1_total, first.hap2_total), (5, 2, 5, 7));
+        let second = clusters.iter().find(|c| c.start == 131).unwrap();
+        assert_eq!((second.end, second.hap1_reads, second.hap2_reads), (131, 0, 5));
+        assert_eq!(second.usage(), (0.0, 5.0 / 7.0));
+        assert!(first.pvalue < 0.05 && first.pvalue == second.pvalue);
+    }
+}
@@ -14,6 +14,7 @@ use rust_htslib::bam::record::Aux;
 use rust_lapper::{Interval, Lapper};
 use seq_io::fasta::{Reader, Record};
 
+use crate::indel::{left_align_deletion, left_align_insertion};
 use crate::Platform;
 
 #[derive(Default, Clone, Debug)]
@@ -61,12 +62,34 @@ impl Region {
     }
 }
 
+#[derive(Default, Debug, Clone, Copy)]
+pub struct QualSummary {
+    pub pass_cnt: u32,
+    // number of bases with base quality >= min_baseq
+    pub log_error: f64,
+    // sum of log10(error rate) of all bases, base quality is capped at 40
+    pub log_correct: f64,
+    // sum of log10(1 - error rate) of all bases, base quality is capped at 40
+}
+
+impl QualSummary {
+    pub fn add(&mut self, baseq: u8, min_baseq: u8) {
+        if baseq >= min_baseq {
+            self.pass_cnt += 1;
+        }
+        let bq = if baseq < 40 { baseq } else { 40 };
+        let error_rate = 0.1_f64.powf((bq as f64) / 10.0);
+        self.log_error += error_rate.log10();
+        self.log_correct += (1.0 - error_rate).log10();
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct BaseQual {
-    pub a: Vec<u8>,
-    pub c: Vec<u8>,
-    pub g: Vec<u8>,
-    pub t: Vec<u8>,
+    pub a: QualSummary,
+    pub c: QualSummary,
+    pub g: QualSummary,
+    pub t: QualSummary,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -80,14 +103,14 @@ pub struct BaseStrands {
 
 #[derive(Default, Debug, Clone)]
 pub struct DistanceToEnd {
-    pub a: Vec<i64>,
-    // allele A to the end of read for every read
-    pub c: Vec<i64>,
-    // allele C to the end of read for every read
-    pub g: Vec<i64>,
-    // allele G to the end of read for every read
-    pub t: Vec<i64>,
-    // allele T to the end of read for every read
+    pub a: i64,
+    // sum of distances of allele A to the end of read
+    pub c: i64,
+    // sum of distances of allele C to the end of read
+    pub g: i64,
+    // sum of distances of allele G to the end of read
+    pub t: i64,
+    // sum of distances of allele T to the end of read
 }
 
 
@@ -463,23 +486,44 @@ pub fn read_references(ref_path: &str) -> HashMap<String, Vec<u8>> {
     return references;
 }
 
+pub fn fetch_region_records(bam_path: &str, region: &Region) -> Vec<bam::Record> {
+    // decode the reads of a region once, the records are shared by pileup, fragments, somatic detection and the bam chunk of the region.
+    // The region is 1-based [start, end), fetched as 0-based [start - 1, end - 1) like the bam chunks.
+    let mut bam: bam::IndexedReader = bam::IndexedReader::from_path(bam_path).unwrap();
+    bam.fetch((region.chr.as_str(), region.start as i64 - 1, region.end as i64 - 1)).unwrap();
+    let mut records: Vec<bam::Record> = Vec::new();
+    for r in bam.records() {
+        records.push(r.unwrap());
+    }
+    return records;
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct IndelFreq {
+    pub ins: HashMap<Vec<u8>, u32>,
+    // inserted sequence -> number of reads, inserted after the anchor base
+    pub del: HashMap<u32, u32>,
+    // deletion length -> number of reads, deleted after the anchor base
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct Profile {
     pub freq_vec: Vec<BaseFreq>,
     pub region: Region,
+    pub indel_freq: HashMap<usize, IndelFreq>,
+    // index in freq_vec of the left-aligned anchor base -> indel alleles. Only filled when indel calling is enabled.
 }
 
 impl Profile {
-    pub fn init_with_pileup(&mut self, bam_path: &str, region: &Region, ref_seq: &Vec<u8>, platform: &Platform, min_mapq: u8, min_baseq: u8, min_read_length: usize, min_depth: u32, max_depth: u32, distance_to_read_end: u32, polya_tail_length: u32) {
+    pub fn init_with_pileup(&mut self, records: &Vec<bam::Record>, region: &Region, ref_seq: &Vec<u8>, platform: &Platform, min_mapq: u8, min_baseq: u8, min_read_length: usize, min_depth: u32, max_depth: u32, distance_to_read_end: u32, polya_tail_length: u32, call_indels: bool) {
         // When region is large and the number of reads is large, the runtime of init_profile_with_pileup is time-consuming.
         // This function is used to fill the profile by parsing each read in the bam file instead of using pileup.
 
         let start_time = Instant::now();
-        let mut bam: bam::IndexedReader = bam::IndexedReader::from_path(bam_path).unwrap();
-        bam.fetch((region.chr.as_str(), region.start, region.end)).unwrap();
         let vec_size = (region.end - region.start) as usize;    // end is exclusive
         self.freq_vec = vec![BaseFreq::default(); vec_size];
         self.region = region.clone();
+        self.indel_freq = HashMap::new();
         let freq_vec_pos = region.start as usize - 1;    // the first position on reference, 0-based, inclusive
         let polyA_win = polya_tail_length as i64;
 
@@ -488,8 +532,7 @@ impl Profile {
             self.freq_vec[i].ref_base = ref_seq[freq_vec_pos + i] as char;
         }
 
-        for r in bam.records() {
-            let record = r.unwrap();
+        for record in records.iter() {
             if record.mapq() < min_mapq || record.seq_len() < min_read_length || record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
                 continue;
             }
@@ -601,43 +644,43 @@ impl Profile {
                                 match base {
                                     'A' | 'a' => {
                                         self.freq_vec[pos_in_freq_vec as usize].a += 1;
-                                        self.freq_vec[pos_in_freq_vec as usize].baseq.a.push(baseq);
+                                        self.freq_vec[pos_in_freq_vec as usize].baseq.a.add(baseq, min_baseq);
                                         if strand == 0 {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.a[0] += 1;
                                         } else {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.a[1] += 1;
                                         }
-                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.a.push(dist);
+                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.a += dist;
                                     }
                                     'C' | 'c' => {
                                         self.freq_vec[pos_in_freq_vec as usize].c += 1;
-                                        self.freq_vec[pos_in_freq_vec as usize].baseq.c.push(baseq);
+                                        self.freq_vec[pos_in_freq_vec as usize].baseq.c.add(baseq, min_baseq);
                                         if strand == 0 {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.c[0] += 1;
                                         } else {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.c[1] += 1;
                                         }
-                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.c.push(dist);
+                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.c += dist;
                                     }
                                     'G' | 'g' => {
                                         self.freq_vec[pos_in_freq_vec as usize].g += 1;
-                                        self.freq_vec[pos_in_freq_vec as usize].baseq.g.push(baseq);
+                                        self.freq_vec[pos_in_freq_vec as usize].baseq.g.add(baseq, min_baseq);
                                         if strand == 0 {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.g[0] += 1;
                                         } else {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.g[1] += 1;
                                         }
-                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.g.push(dist);
+                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.g += dist;
                                     }
                                     'T' | 't' => {
                                         self.freq_vec[pos_in_freq_vec as usize].t += 1;
-                                        self.freq_vec[pos_in_freq_vec as usize].baseq.t.push(baseq);
+                                        self.freq_vec[pos_in_freq_vec as usize].baseq.t.add(baseq, min_baseq);
                                         if strand == 0 {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.t[0] += 1;
                                         } else {
                                             self.freq_vec[pos_in_freq_vec as usize].base_strands.t[1] += 1;
                                         }
-                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.t.push(dist);
+                                        self.freq_vec[pos_in_freq_vec as usize].distance_to_end.t += dist;
                                     }
                                     _ => {
                                         println!("Invalid nucleotide base: {}", base);
@@ -655,6 +698,16 @@ impl Profile {
                         }
                     }
                     b'D' => {
+                        if call_indels && pos_in_freq_vec >= 1 && pos_in_freq_vec < vec_size as i32 {
+                            // indels close to read ends are not reliable
+                            if (pos_in_read as i64 - leading_softclips).abs() >= distance_to_read_end as i64 && (pos_in_read as i64 - (seq.len() as i64 - trailing_softclips)).abs() >= distance_to_read_end as i64 {
+                                let anchor = left_align_deletion(ref_seq, freq_vec_pos + pos_in_freq_vec as usize - 1, cg.len() as usize);
+                                if anchor >= freq_vec_pos {
+                                    let ifreq = self.indel_freq.entry(anchor - freq_vec_pos).or_insert(IndelFreq::default());
+                                    *ifreq.del.entry(cg.len()).or_insert(0) += 1;
+                                }
+                            }
+                        }
                         for _ in 0..cg.len() {
                             if pos_in_freq_vec < 0 {
                                 pos_in_freq_vec += 1;
@@ -677,6 +730,17 @@ impl Profile {
                             break;
                         }
                         self.freq_vec[(pos_in_freq_vec - 1) as usize].ni += 1; // insertion is counted as the previous position
+                        if call_indels {
+                            // indels close to read ends are not reliable
+                            if (pos_in_read as i64 - leading_softclips).abs() >= distance_to_read_end as i64 && (pos_in_read as i64 + cg.len() as i64 - (seq.len() as i64 - trailing_softclips)).abs() >= distance_to_read_end as i64 {
+                                let ins_seq: Vec<u8> = (0..cg.len() as usize).map(|i| seq[pos_in_read + i].to_ascii_uppercase()).collect();
+                                let (anchor, ins_seq) = left_align_insertion(ref_seq, freq_vec_pos + pos_in_freq_vec as usize - 1, &ins_seq);
+                                if anchor >= freq_vec_pos {
+                                    let ifreq = self.indel_freq.entry(anchor - freq_vec_pos).or_insert(IndelFreq::default());
+                                    *ifreq.ins.entry(ins_seq).or_insert(0) += 1;
+                                }
+                            }
+                        }
                         pos_in_read += cg.len() as usize;
                     }
                     b'N' => {
@@ -755,4 +819,33 @@ pub fn independent_test(data: [u32; 4]) -> f64 {
     }
 
     return phred_pvalue;
-}
\ No newline at end of file
+}
+pub fn calculate_n50(lengths: &Vec<i64>) -> i64 {
+    // N50 of the lengths, 0 for empty input
+    let mut sorted = lengths.clone();
+    sorted.sort_by(|a, b| b.cmp(a));
+    let total: i64 = sorted.iter().sum();
+    let mut acc = 0;
+    for l in sorted.iter() {
+        acc += l;
+        if acc * 2 >= total {
+            return *l;
+        }
+    }
+    return 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn n50_of_known_lengths() {
+        assert_eq!(calculate_n50(&vec![]), 0);
+        assert_eq!(calculate_n50(&vec![100]), 100);
+        // total 100, the two longest blocks reach half of it
+        assert_eq!(calculate_n50(&vec![10, 40, 20, 30]), 30);
+        assert_eq!(calculate_n50(&vec![2, 3, 4, 5, 6, 7, 8, 9, 10]), 8);
+        assert_eq!(calculate_n50(&vec![50, 50]), 50);
+    }
+}
@@ -16,10 +16,16 @@ pub struct VCFRecord {
 
 
 impl SNPFrag {
-    pub fn output_phased_vcf(&mut self, min_phase_score: f32, min_qual_for_candidate: u32) -> Vec<VCFRecord> {
+    pub fn output_phased_vcf(&mut self, ref_seq: &Vec<u8>, min_phase_score: f32, min_qual_for_candidate: u32) -> Vec<VCFRecord> {
         let mut records: Vec<VCFRecord> = Vec::new();
+        let mnv_groups = self.find_mnv_groups(true);
+        let mnv_members = SNPFrag::mnv_members(&mnv_groups);
         for i in 0..self.candidate_snps.len() {
             let snp = &self.candidate_snps[i];
+            if mnv_members.contains(&i) {
+                // reported in the merged MNV record
+                continue;
+            }
 
             if snp.rna_editing {
                 let mut rd: VCFRecord = VCFRecord::default();
@@ -187,6 +193,10 @@ impl SNPFrag {
                     );
                     rd.format = "GT:GQ:DP:AF:PQ:AE".to_string().into_bytes();
                 }
+                if let Some(pc) = snp.phase_confidence {
+                    rd.genotype = format!("{}:{:.3}", rd.genotype, pc);
+                    rd.format.extend(":PC".as_bytes());
+                }
                 records.push(rd);
                 continue;
             }
@@ -245,17 +255,26 @@ impl SNPFrag {
                 continue;
             }
         }
+        records.extend(self.output_mnv_vcf(&mnv_groups, ref_seq, min_phase_score, min_qual_for_candidate, true));
+        records.extend(self.output_indel_vcf(min_phase_score, min_qual_for_candidate, true));
+        records.sort_by(|a, b| a.position.cmp(&b.position));
         return records;
     }
 
 
-    pub fn output_vcf(&mut self, min_qual: u32) -> Vec<VCFRecord> {
+    pub fn output_vcf(&mut self, ref_seq: &Vec<u8>, min_qual: u32) -> Vec<VCFRecord> {
         let mut records: Vec<VCFRecord> = Vec::new();
+        let mnv_groups = self.find_mnv_groups(false);
+        let mnv_members = SNPFrag::mnv_members(&mnv_groups);
 
         // output heterozygous SNPs
         // assert_eq!(self.haplotype.len(), self.snps.len());
         for i in 0..self.candidate_snps.len() {
             let snp = &self.candidate_snps[i];
+            if mnv_members.contains(&i) {
+                // reported in the merged MNV record
+                continue;
+            }
             // if snp.somatic || snp.rna_editing {
             //     continue;
             // }
@@ -399,6 +418,9 @@ impl SNPFrag {
                 continue;
             }
         }
+        records.extend(self.output_mnv_vcf(&mnv_groups, ref_seq, 0.0, min_qual, false));
+        records.extend(self.output_indel_vcf(0.0, min_qual, false));
+        records.sort_by(|a, b| a.position.cmp(&b.position));
         return records;
     }
 }